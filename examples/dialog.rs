@@ -50,11 +50,35 @@ impl App for DialogApp {
 
 #[tokio::main]
 async fn main() {
+    let mut server = AppServer::new_with_port(2222);
+    let app = DialogApp {};
+    // `cargo run --example dialog -- --local` runs the app in this terminal instead.
+    if std::env::args().any(|arg| arg == "--local") {
+        server.run_local(Arc::new(app)).await.unwrap();
+        return;
+    }
     let key_pairs = [
         KeyPair::generate_rsa(4096, SignatureHash::SHA2_256).unwrap(),
         KeyPair::generate_ed25519().unwrap(),
     ];
-    let mut server = AppServer::new_with_port(2222);
-    let app = DialogApp {};
     server.run(&key_pairs, Arc::new(app)).await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use ssh_ui::cursive::event::Key;
+    use ssh_ui::cursive::Vec2;
+    use ssh_ui::testing::TestSession;
+
+    use super::*;
+
+    #[test]
+    fn says_hello_and_quits() {
+        let mut session = TestSession::new(&DialogApp {}, Vec2::new(80, 24)).unwrap();
+        assert!(session.screen().contains("Hello over ssh!"));
+        session.assert_snapshot("tests/snapshots/dialog.txt");
+        session.assert_annotated_snapshot("tests/snapshots/dialog_styled.txt");
+        session.press(Key::Enter);
+        assert!(!session.is_running());
+    }
+}
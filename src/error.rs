@@ -0,0 +1,64 @@
+use std::fmt::Display;
+use std::io;
+
+/// Errors returned by ssh_ui.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A listener couldn't be bound to its address.
+    Bind { addr: String, source: io::Error },
+    /// A client was refused while authenticating, for example because its address is banned.
+    Auth(String),
+    /// A session's pseudo-terminal couldn't be created or written to.
+    Pty(io::Error),
+    /// The terminal backend or the runtime driving it couldn't be set up.
+    Backend(io::Error),
+    /// The app returned an error from one of its callbacks.
+    App(String),
+    /// The app panicked, with the panic's message.
+    Panic(String),
+    /// The ssh connection failed.
+    Ssh(russh::Error),
+    /// The other end of an internal channel went away, usually because the client disconnected.
+    Disconnected,
+}
+
+impl Error {
+    /// Wraps an error returned by one of the app's callbacks. Those aren't `Send`, so only the
+    /// message is kept.
+    pub(crate) fn app(err: Box<dyn std::error::Error>) -> Self {
+        Error::App(err.to_string())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Bind { addr, source } => write!(f, "failed to listen on {}: {}", addr, source),
+            Error::Auth(reason) => write!(f, "authentication refused: {}", reason),
+            Error::Pty(err) => write!(f, "pseudo-terminal failed: {}", err),
+            Error::Backend(err) => write!(f, "terminal backend failed: {}", err),
+            Error::App(message) => write!(f, "app failed: {}", message),
+            Error::Panic(message) => write!(f, "app panicked: {}", message),
+            Error::Ssh(err) => write!(f, "ssh connection failed: {}", err),
+            Error::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind { source, .. } => Some(source),
+            Error::Pty(err) | Error::Backend(err) => Some(err),
+            Error::Ssh(err) => Some(err),
+            Error::Auth(_) | Error::App(_) | Error::Panic(_) | Error::Disconnected => None,
+        }
+    }
+}
+
+impl From<russh::Error> for Error {
+    fn from(err: russh::Error) -> Self {
+        Error::Ssh(err)
+    }
+}
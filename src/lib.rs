@@ -1,21 +1,51 @@
+mod error;
+mod local;
 pub(crate) mod ssh;
+pub mod testing;
 
 #[macro_use]
 extern crate lazy_static;
 
-use std::{error::Error, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
+use cursive::event::{Event, Key};
 use cursive::View;
+use log::info;
 
 pub use cursive;
 pub use russh_keys;
 
 use russh_keys::key::{KeyPair, PublicKey};
-use ssh::{plugin::set_plugin, server::Server, session_manager::SessionManager};
-use tokio::sync::{
-    mpsc::{self, Sender},
-    watch,
+use ssh::{
+    audit,
+    listener::Listener,
+    metrics,
+    plugin::set_plugin,
+    server::Server,
+    session_manager::{SessionManager, SessionSettings},
+    waiting_room::WaitingRoom,
 };
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task::JoinSet;
+
+pub use error::Error;
+pub use ssh::audit::{AuditEvent, AuditSink, JsonLinesAuditSink};
+pub use ssh::banner::Banner;
+pub use ssh::console::AdminConsole;
+pub use ssh::incident::{ErrorScreen, Incident};
+pub use ssh::limits::{BanEvent, ConnectionLimits};
+pub use ssh::listener::{ListenAddr, ListenFd};
+pub use ssh::metrics::{MetricsConfig, MetricsRecorder, PrometheusRecorder};
+pub use ssh::recording::RecordingPolicy;
+pub use ssh::telnet::TelnetConfig;
+pub use ssh::timeouts::{Expiry, SessionTimeouts};
+pub use ssh::waiting_room::{SessionLimits, SessionOverflow};
+#[cfg(feature = "websocket")]
+pub use ssh::websocket::{WebSocketConfig, WebSocketRequest};
+
+/// Number of characters of a paste that [`AppSession::on_paste`] replays as key presses by default.
+pub const MAX_REPLAYED_PASTE_CHARS: usize = 4096;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SessionHandle(u64);
@@ -28,46 +58,326 @@ pub trait AppSession {
         session_handle: SessionHandle,
         pub_key: Option<PublicKey>,
         force_refresh_sender: Sender<()>,
-    ) -> Result<Box<dyn View>, Box<dyn Error>>;
+    ) -> Result<Box<dyn View>, Box<dyn std::error::Error>>;
 
     /// Called when the session ticks.
-    fn on_tick(&mut self, _siv: &mut cursive::Cursive) -> Result<(), Box<dyn Error>> {
+    fn on_tick(&mut self, _siv: &mut cursive::Cursive) -> Result<(), Box<dyn std::error::Error>> {
         Ok(())
     }
+
+    /// Called after `on_start` to decide the timeouts for this session. Defaults to the server's.
+    fn timeouts(&self, server_timeouts: &SessionTimeouts) -> SessionTimeouts {
+        server_timeouts.clone()
+    }
+
+    /// Called after `on_start` to decide the message of the day shown on top of the session's view.
+    /// Defaults to the server's.
+    fn motd(&self, server_motd: Option<&str>) -> Option<String> {
+        server_motd.map(str::to_string)
+    }
+
+    /// Called after `on_start` to decide whether this session is recorded. Defaults to the server's
+    /// [`RecordingPolicy`], and has no effect if the server has no recording policy.
+    fn record(&self, server_default: bool) -> bool {
+        server_default
+    }
+
+    /// Called with everything the user pasted at once, for terminals that support bracketed paste,
+    /// instead of an event per character. Line breaks arrive as `\n`. Defaults to replaying the
+    /// first [`MAX_REPLAYED_PASTE_CHARS`] characters as if they had been typed, since each one is
+    /// a trip through the whole view tree. Override it to take longer pastes in one go, for
+    /// example with `EditView::insert` on the view that should receive them.
+    fn on_paste(&mut self, siv: &mut cursive::Cursive, text: &str) {
+        if text.chars().nth(MAX_REPLAYED_PASTE_CHARS).is_some() {
+            info!(
+                "Replaying only the first {} characters of a paste",
+                MAX_REPLAYED_PASTE_CHARS
+            );
+        }
+        for c in text.chars().take(MAX_REPLAYED_PASTE_CHARS) {
+            siv.on_event(match c {
+                '\n' => Event::Key(Key::Enter),
+                '\t' => Event::Key(Key::Tab),
+                c => Event::Char(c),
+            });
+        }
+    }
+
+    /// Called when the session is about to be ended by a timeout, before the cursive runner quits.
+    fn on_expire(&mut self, _siv: &mut cursive::Cursive, _expiry: Expiry) {}
 }
 
 /// A plugin that lets you integrate with the ssh_ui system.
 pub trait App: Send + Sync {
     /// Called when the plugin is loaded.
-    fn on_load(&mut self) -> Result<(), Box<dyn Error>>;
+    fn on_load(&mut self) -> Result<(), Box<dyn std::error::Error>>;
     /// Called to request a new session.
     fn new_session(&self) -> Box<dyn AppSession>;
+    /// Called when a channel opens to decide whether it should spectate an existing session
+    /// instead of starting its own. Spectators see the session's output but can't send it input.
+    fn spectate(&self, _username: &str, _pub_key: Option<&PublicKey>) -> Option<SessionHandle> {
+        None
+    }
+    /// Called when a peer is temporarily banned for failing to authenticate too many times.
+    fn on_ban(&self, _ban: &BanEvent) {}
+    /// Called when a session fails because one of its callbacks returned an error or panicked,
+    /// before the user is shown the [`ErrorScreen`].
+    fn on_error(&self, _incident: &Incident) {}
 }
 
 /// Server that handles incoming ssh connections.
 pub struct AppServer {
     port: u16,
+    listen: Vec<ListenAddr>,
+    connection_limits: ConnectionLimits,
+    session_limits: SessionLimits,
+    session_timeouts: SessionTimeouts,
+    banner: Option<Banner>,
+    motd: Option<String>,
+    recording: Option<RecordingPolicy>,
+    error_screen: ErrorScreen,
+    detach_grace_period: Option<Duration>,
+    telnet: Option<TelnetConfig>,
+    raw_terminal: Option<ListenAddr>,
+    #[cfg(feature = "websocket")]
+    websocket: Option<WebSocketConfig>,
+    metrics: Option<MetricsConfig>,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    admin_console: Option<AdminConsole>,
 }
 
 impl AppServer {
     /// Creates a new server with the specified port.
     pub fn new_with_port(port: u16) -> Self {
-        Self { port }
+        Self {
+            port,
+            listen: Vec::new(),
+            connection_limits: ConnectionLimits::default(),
+            session_limits: SessionLimits::default(),
+            session_timeouts: SessionTimeouts::default(),
+            banner: None,
+            motd: None,
+            recording: None,
+            error_screen: ErrorScreen::default(),
+            detach_grace_period: None,
+            telnet: None,
+            raw_terminal: None,
+            #[cfg(feature = "websocket")]
+            websocket: None,
+            metrics: None,
+            audit_sink: None,
+            admin_console: None,
+        }
+    }
+
+    /// Accepts ssh connections on `listen`. Call this once for each address to listen on; the
+    /// first call replaces the default of every IPv6 and IPv4 address at the port given to
+    /// [`AppServer::new_with_port`]. Per-IP limits don't apply to Unix sockets.
+    ///
+    /// Without a listen address, a process started by systemd socket activation uses the sockets
+    /// named `ssh` with `FileDescriptorName=`, or else every socket systemd passed it.
+    pub fn with_listen_addr(mut self, listen: impl Into<ListenAddr>) -> Self {
+        self.listen.push(listen.into());
+        self
+    }
+
+    /// Sets the per-IP connection and authentication limits enforced by the server. There are none
+    /// by default.
+    pub fn with_connection_limits(mut self, connection_limits: ConnectionLimits) -> Self {
+        self.connection_limits = connection_limits;
+        self
+    }
+
+    /// Sets the cap on concurrently running sessions and what to do with connections over it.
+    pub fn with_session_limits(mut self, session_limits: SessionLimits) -> Self {
+        self.session_limits = session_limits;
+        self
+    }
+
+    /// Sets the idle and maximum-duration timeouts applied to each session.
+    pub fn with_session_timeouts(mut self, session_timeouts: SessionTimeouts) -> Self {
+        self.session_timeouts = session_timeouts;
+        self
+    }
+
+    /// Sets the banner shown to clients before they authenticate.
+    pub fn with_banner(mut self, banner: Banner) -> Self {
+        self.banner = Some(banner);
+        self
+    }
+
+    /// Sets a message of the day shown on top of every session's view until a key is pressed.
+    pub fn with_motd(mut self, motd: impl Into<String>) -> Self {
+        self.motd = Some(motd.into());
+        self
+    }
+
+    /// Records sessions to asciicast v2 files according to the given policy.
+    pub fn with_recording(mut self, recording: RecordingPolicy) -> Self {
+        self.recording = Some(recording);
+        self
     }
 
-    /// Listens on the specified port for new ssh connections indefinitely.
-    pub async fn run(
+    /// Sets the screen shown to users whose session failed.
+    pub fn with_error_screen(mut self, error_screen: ErrorScreen) -> Self {
+        self.error_screen = error_screen;
+        self
+    }
+
+    /// Keeps a session running for `grace_period` after its client disconnects. A new connection
+    /// with the same public key picks the session back up where it left off. Sessions of clients
+    /// that didn't authenticate with a key end as soon as they disconnect.
+    pub fn with_persistent_sessions(mut self, grace_period: Duration) -> Self {
+        self.detach_grace_period = Some(grace_period);
+        self
+    }
+
+    /// Also serves the app over telnet, for clients that don't speak ssh.
+    pub fn with_telnet(mut self, telnet: TelnetConfig) -> Self {
+        self.telnet = Some(telnet);
+        self
+    }
+
+    /// Also streams the app's terminal over `listen` with no protocol on top, for local tools such
+    /// as `socat -,raw,echo=0 UNIX-CONNECT:<path>`. Nothing is authenticated, so this is meant for
+    /// a Unix socket whose filesystem permissions decide who may connect. The terminal is asked for
+    /// its size every couple of seconds, so a resize shows up after a short delay.
+    pub fn with_raw_terminal(mut self, listen: impl Into<ListenAddr>) -> Self {
+        self.raw_terminal = Some(listen.into());
+        self
+    }
+
+    /// Also serves the app over WebSocket, for browser terminals such as xterm.js.
+    #[cfg(feature = "websocket")]
+    pub fn with_websocket(mut self, websocket: WebSocketConfig) -> Self {
+        self.websocket = Some(websocket);
+        self
+    }
+
+    /// Reports connection, session and bandwidth metrics, either over HTTP for Prometheus to
+    /// scrape or to a recorder of your own.
+    pub fn with_metrics(mut self, metrics: MetricsConfig) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Records connections, authentication attempts, commands and sessions to `sink`, for example
+    /// a [`JsonLinesAuditSink`].
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    /// Serves the built-in [`AdminConsole`] next to the app, for operators to watch and manage the
+    /// live sessions.
+    pub fn with_admin_console(mut self, admin_console: AdminConsole) -> Self {
+        self.admin_console = Some(admin_console);
+        self
+    }
+
+    /// Listens on the specified port for new ssh connections indefinitely. Returns an error if a
+    /// listener can't be bound; failures within a session only end that session. Dropping the
+    /// future, for example by aborting its task, stops the server and closes its listeners.
+    pub async fn run(&mut self, key_pairs: &[KeyPair], plugin: Arc<dyn App>) -> Result<(), Error> {
+        let (sh, repo) = self.build(key_pairs, plugin).await;
+        sh.listen(repo).await
+    }
+
+    /// Runs a single session of the app on the current terminal instead of serving it over ssh,
+    /// which is handy while developing. The session gets no public key, and the server's message
+    /// of the day is shown, but ssh-specific settings such as limits and timeouts don't apply.
+    /// Returns once the session quits.
+    pub async fn run_local(&self, plugin: Arc<dyn App>) -> Result<(), Error> {
+        let motd = self.motd.clone();
+        // Cursive blocks while it waits for input, so it gets a thread of its own, like the
+        // sessions of an ssh server do.
+        tokio::task::spawn_blocking(move || local::run(plugin, motd))
+            .await
+            .map_err(|err| Error::App(err.to_string()))?
+    }
+
+    /// Serves ssh connections accepted from `listener` indefinitely, ignoring the configured
+    /// listen address and the other frontends' listeners.
+    pub(crate) async fn run_on(
         &mut self,
+        listener: TcpListener,
         key_pairs: &[KeyPair],
         plugin: Arc<dyn App>,
-    ) -> Result<(), Box<dyn Error>> {
+    ) {
+        let (sh, repo) = self.build(key_pairs, plugin).await;
+        sh.serve(
+            vec![Listener::Tcp(listener)],
+            None,
+            None,
+            repo,
+            JoinSet::new(),
+        )
+        .await;
+    }
+
+    fn listen_addrs(&self) -> Vec<ListenAddr> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+        let mut activated = ListenAddr::systemd_named("ssh");
+        if activated.is_empty() {
+            activated = ListenAddr::systemd();
+        }
+        if activated.is_empty() {
+            return vec![ListenAddr::DualStack(self.port)];
+        }
+        for listen in &activated {
+            info!("Using socket {} from systemd socket activation", listen);
+        }
+        activated
+    }
+
+    async fn build(&self, key_pairs: &[KeyPair], plugin: Arc<dyn App>) -> (Server, SessionManager) {
         set_plugin(plugin);
+        let (recorder, metrics_endpoint): (Option<Arc<dyn MetricsRecorder>>, _) =
+            match &self.metrics {
+                Some(MetricsConfig::Endpoint(addr)) => {
+                    let recorder = Arc::new(PrometheusRecorder::new());
+                    (Some(recorder.clone()), Some((*addr, recorder)))
+                }
+                Some(MetricsConfig::Recorder(recorder)) => (Some(recorder.clone()), None),
+                None => (None, None),
+            };
+        metrics::set_recorder(recorder);
+        audit::set_sink(self.audit_sink.clone());
         let (sender, receiver) = mpsc::channel(100);
-        let (_tx, rx) = watch::channel(false);
-        let repo = SessionManager::new(sender.clone(), receiver);
-        let sh = Server::new(key_pairs, rx, sender, self.port).await;
-        sh.listen(repo).await.unwrap();
-
-        Ok(())
+        let repo = SessionManager::new(
+            receiver,
+            WaitingRoom::new(self.session_limits.clone()),
+            SessionSettings {
+                timeouts: self.session_timeouts.clone(),
+                motd: self.motd.clone(),
+                recording: self.recording.clone(),
+                error_screen: self.error_screen.clone(),
+                detach_grace_period: self.detach_grace_period,
+            },
+            self.admin_console.clone(),
+        );
+        let sh = Server::new(
+            key_pairs,
+            sender,
+            self.listen_addrs(),
+            self.connection_limits.clone(),
+            self.banner.clone(),
+            self.telnet.clone(),
+            self.raw_terminal.clone(),
+        )
+        .await;
+        #[cfg(feature = "websocket")]
+        let sh = sh.with_websocket(self.websocket.clone());
+        let console_listen = self
+            .admin_console
+            .as_ref()
+            .and_then(AdminConsole::listen_addr)
+            .cloned();
+        let sh = sh
+            .with_metrics_endpoint(metrics_endpoint)
+            .with_console_listener(console_listen);
+        (sh, repo)
     }
 }
@@ -0,0 +1,55 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::cursive::backends::termion::Backend;
+use crate::cursive::event::Event;
+use crate::cursive::Cursive;
+use crate::ssh::plugin::motd_view;
+use crate::{App, Error, SessionHandle};
+
+use log::{trace, warn};
+use tokio::runtime::Builder;
+use tokio::sync::mpsc::channel;
+
+/// Runs a single session of `plugin` on the process's own terminal until it quits.
+pub(crate) fn run(plugin: Arc<dyn App>, motd: Option<String>) -> Result<(), Error> {
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .map_err(Error::Backend)?;
+    let _enter = runtime.handle().enter();
+
+    let handle_id = SessionHandle(0);
+    trace!("Starting local session");
+    let mut siv = Cursive::new();
+    let (refresh_sender, mut refresh_receiver) = channel(10);
+    let mut session = plugin.new_session();
+    let view = session
+        .on_start(&mut siv, handle_id, None, refresh_sender)
+        .map_err(Error::app)?;
+    siv.add_layer(view);
+    if let Some(motd) = session.motd(motd.as_deref()) {
+        siv.add_layer(motd_view(motd));
+    }
+    let session = Rc::new(RefCell::new(session));
+
+    let mut runner = siv.into_runner(Backend::init().map_err(Error::Backend)?);
+    runner.add_global_callback(Event::Refresh, move |siv| {
+        if let Err(err) = session.borrow_mut().on_tick(siv) {
+            warn!("Tick failed for local session: {}", err);
+        }
+    });
+    runner.refresh();
+    runner.on_event(Event::Refresh);
+    while runner.is_running() {
+        if refresh_receiver.try_recv().is_ok() {
+            runner.on_event(Event::Refresh);
+            runner.refresh();
+        }
+        runner.step();
+    }
+    trace!("Local session ended");
+    Ok(())
+}
@@ -0,0 +1,280 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::SessionHandle;
+
+/// Something that happened on the server that an [`AuditSink`] keeps a record of.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuditEvent {
+    /// A client connected and was let past the connection limits. `frontend` is `ssh`, `telnet`,
+    /// `raw` or `websocket`, and Unix socket peers have no address.
+    ConnectionOpened {
+        frontend: &'static str,
+        peer: Option<SocketAddr>,
+    },
+    /// A client was turned away before getting a session. `reason` is `banned`,
+    /// `too_many_connections` or `rate_limited` when the connection limits refused it, and
+    /// `forbidden_origin` for a WebSocket opened by a page on an origin that isn't allowed or
+    /// `handshake_timeout` for one whose upgrade request didn't arrive in time.
+    ConnectionRefused {
+        frontend: &'static str,
+        peer: Option<SocketAddr>,
+        reason: &'static str,
+    },
+    /// A client tried to authenticate. `method` is `publickey` or `none` over ssh, and
+    /// `websocket` for the WebSocket authenticator, whose refusals have an empty username.
+    Auth {
+        peer: Option<SocketAddr>,
+        method: &'static str,
+        username: String,
+        /// The SHA-256 fingerprint of the key offered, if any.
+        fingerprint: Option<String>,
+        accepted: bool,
+    },
+    /// A client asked whether a public key would be accepted, which it can do without holding
+    /// the key. Whether it then proved it does is recorded as an `Auth` event.
+    KeyOffered {
+        peer: Option<SocketAddr>,
+        username: String,
+        /// The SHA-256 fingerprint of the key offered.
+        fingerprint: String,
+    },
+    /// A client asked to run a command instead of an interactive shell.
+    Exec {
+        peer: Option<SocketAddr>,
+        username: String,
+        command: String,
+    },
+    /// A session started.
+    SessionStarted {
+        session: SessionHandle,
+        username: String,
+        fingerprint: Option<String>,
+    },
+    /// A session ended after running for `duration`.
+    SessionEnded {
+        session: SessionHandle,
+        duration: Duration,
+    },
+}
+
+impl AuditEvent {
+    fn to_json(&self) -> Value {
+        let peer = |peer: &Option<SocketAddr>| peer.map(|peer| peer.to_string());
+        match self {
+            AuditEvent::ConnectionOpened {
+                frontend,
+                peer: addr,
+            } => json!({
+                "event": "connection_opened",
+                "frontend": frontend,
+                "peer": peer(addr),
+            }),
+            AuditEvent::ConnectionRefused {
+                frontend,
+                peer: addr,
+                reason,
+            } => json!({
+                "event": "connection_refused",
+                "frontend": frontend,
+                "peer": peer(addr),
+                "reason": reason,
+            }),
+            AuditEvent::Auth {
+                peer: addr,
+                method,
+                username,
+                fingerprint,
+                accepted,
+            } => json!({
+                "event": "auth",
+                "peer": peer(addr),
+                "method": method,
+                "username": username,
+                "fingerprint": fingerprint,
+                "accepted": accepted,
+            }),
+            AuditEvent::KeyOffered {
+                peer: addr,
+                username,
+                fingerprint,
+            } => json!({
+                "event": "key_offered",
+                "peer": peer(addr),
+                "username": username,
+                "fingerprint": fingerprint,
+            }),
+            AuditEvent::Exec {
+                peer: addr,
+                username,
+                command,
+            } => json!({
+                "event": "exec",
+                "peer": peer(addr),
+                "username": username,
+                "command": command,
+            }),
+            AuditEvent::SessionStarted {
+                session,
+                username,
+                fingerprint,
+            } => json!({
+                "event": "session_started",
+                "session": session.0,
+                "username": username,
+                "fingerprint": fingerprint,
+            }),
+            AuditEvent::SessionEnded { session, duration } => json!({
+                "event": "session_ended",
+                "session": session.0,
+                "duration": duration.as_secs_f64(),
+            }),
+        }
+    }
+}
+
+/// Keeps an audit trail of the server's [`AuditEvent`]s.
+pub trait AuditSink: Send + Sync {
+    /// Called as each event happens. This runs on the server's tasks, so it shouldn't block for
+    /// long.
+    fn record(&self, event: &AuditEvent);
+}
+
+/// Number of lines that may wait for the writer thread before recording an event blocks until
+/// there's room.
+const MAX_PENDING_LINES: usize = 4096;
+
+/// Appends every event to a file as a line of JSON, with the Unix time it happened at under
+/// `time`.
+///
+/// The file is written on a thread of its own, so a slow disk doesn't hold up the server's tasks
+/// unless thousands of lines back up. Every line recorded is written by the time the sink is
+/// dropped.
+pub struct JsonLinesAuditSink {
+    lines: Option<SyncSender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl JsonLinesAuditSink {
+    /// Opens `path` for appending, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = sync_channel(MAX_PENDING_LINES);
+        let writer = std::thread::Builder::new()
+            .name("ssh_ui-audit".to_string())
+            .spawn(move || write_lines(file, receiver))?;
+        Ok(Self {
+            lines: Some(sender),
+            writer: Some(writer),
+        })
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn record(&self, event: &AuditEvent) {
+        let mut line = event.to_json();
+        line["time"] = json!(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |t| t.as_secs_f64()));
+        let line = format!("{}\n", line);
+        let sent = self.lines.as_ref().map(|lines| lines.send(line));
+        if !matches!(sent, Some(Ok(()))) {
+            warn!("Audit log writer has stopped, dropping {:?}", event);
+        }
+    }
+}
+
+impl Drop for JsonLinesAuditSink {
+    fn drop(&mut self) {
+        // Closing the queue lets the writer finish the lines still in it and exit.
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Writes each line with a single write, so lines never interleave with another process appending
+/// to the same file.
+fn write_lines(mut file: File, lines: Receiver<String>) {
+    for line in lines {
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write audit event {}: {}", line.trim_end(), err);
+        }
+    }
+}
+
+lazy_static! {
+    static ref SINK: RwLock<Option<Arc<dyn AuditSink>>> = RwLock::new(None);
+}
+
+pub(crate) fn set_sink(sink: Option<Arc<dyn AuditSink>>) {
+    *SINK.write().unwrap() = sink;
+}
+
+pub(crate) fn record(event: AuditEvent) {
+    let sink = SINK.read().unwrap().clone();
+    if let Some(sink) = sink {
+        sink.record(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refusals_are_written_as_json() {
+        let event = AuditEvent::ConnectionRefused {
+            frontend: "telnet",
+            peer: Some("192.0.2.1:4000".parse().unwrap()),
+            reason: "banned",
+        };
+        assert_eq!(
+            event.to_json(),
+            json!({
+                "event": "connection_refused",
+                "frontend": "telnet",
+                "peer": "192.0.2.1:4000",
+                "reason": "banned",
+            })
+        );
+    }
+
+    #[test]
+    fn json_lines_are_all_written_by_the_time_the_sink_is_dropped() {
+        let path = std::env::temp_dir().join(format!("ssh_ui-audit-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sink = JsonLinesAuditSink::open(&path).unwrap();
+        for session in 0..100 {
+            sink.record(&AuditEvent::SessionEnded {
+                session: SessionHandle(session),
+                duration: Duration::from_secs(1),
+            });
+        }
+        drop(sink);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let sessions: Vec<u64> = text
+            .lines()
+            .map(|line| {
+                let line: Value = serde_json::from_str(line).unwrap();
+                assert_eq!(line["event"], "session_ended");
+                assert!(line["time"].as_f64().unwrap() > 0.0);
+                line["session"].as_u64().unwrap()
+            })
+            .collect();
+        assert_eq!(sessions, (0..100).collect::<Vec<_>>());
+    }
+}
@@ -4,7 +4,7 @@ use crate::cursive::backends::termion::termion::event::Event as TEvent;
 use crate::cursive::backends::termion::termion::event::Key as TKey;
 use crate::cursive::backends::termion::termion::event::MouseButton as TMouseButton;
 use crate::cursive::backends::termion::termion::event::MouseEvent as TMouseEvent;
-use crate::cursive::backends::termion::termion::input::{Events, TermRead};
+use crate::cursive::backends::termion::termion::input::{EventsAndRaw, TermReadEventsAndRaw};
 use crate::cursive::backends::termion::termion::style as tstyle;
 
 use crate::cursive::backend;
@@ -14,11 +14,26 @@ use crate::cursive::Vec2;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
 
+use log::debug;
+
+use super::keys;
+use super::metrics;
+
 use std::cell::Cell;
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::fs::File;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+/// Asks the terminal to wrap pasted text in [`PASTE_START`] and [`PASTE_END`].
+pub(crate) const ENABLE_BRACKETED_PASTE: &str = "\x1b[?2004h";
+const DISABLE_BRACKETED_PASTE: &str = "\x1b[?2004l";
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+/// Anything pasted past this is dropped, which also bounds a paste that never ends.
+const MAX_PASTE_BYTES: usize = 1 << 20;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CursiveOutput {
@@ -26,6 +41,31 @@ pub enum CursiveOutput {
     Close,
 }
 
+/// Text the client pasted, waiting for the session to handle it.
+#[derive(Clone, Default)]
+pub(crate) struct Pastes(Arc<Mutex<VecDeque<String>>>);
+
+impl Pastes {
+    fn push(&self, text: String) {
+        self.0.lock().unwrap().push_back(text);
+    }
+
+    pub fn take(&self) -> Vec<String> {
+        self.0.lock().unwrap().drain(..).collect()
+    }
+}
+
+/// Where a piece of input left a bracketed paste.
+#[derive(Debug, PartialEq, Eq)]
+enum Paste {
+    /// The input isn't part of a paste.
+    None,
+    /// A paste is still being read.
+    Pending,
+    /// The input ended a paste, which is now waiting in [`Pastes`].
+    Done,
+}
+
 /// Backend using termion
 pub struct Backend {
     current_style: Cell<theme::ColorPair>,
@@ -33,7 +73,11 @@ pub struct Backend {
     // Inner state required to parse input
     last_button: Option<MouseButton>,
 
-    events: Events<File>,
+    events: EventsAndRaw<File>,
+
+    // The raw bytes of a bracketed paste still being read.
+    paste: Option<Vec<u8>>,
+    pastes: Pastes,
 
     // Raw input file descriptor, to fix the file on exit, since we can't
     // (currently) get it from events.
@@ -104,6 +148,7 @@ impl Backend {
         output_sender: Sender<CursiveOutput>,
         resize_receiver: Receiver<Vec2>,
         relayout_sender: Sender<()>,
+        pastes: Pastes,
     ) -> std::io::Result<Box<dyn backend::Backend>> {
         #[cfg(unix)]
         use std::os::unix::io::AsRawFd;
@@ -120,7 +165,9 @@ impl Backend {
             current_style: Cell::new(theme::ColorPair::from_256colors(0, 0)),
 
             last_button: None,
-            events: input_file.events(),
+            events: input_file.events_and_raw(),
+            paste: None,
+            pastes,
             #[cfg(unix)]
             input_fd,
             running,
@@ -131,7 +178,11 @@ impl Backend {
             data: RefCell::new(Vec::new()),
         };
 
-        c.write(format!("{}", termion::cursor::Hide));
+        c.write(format!(
+            "{}{}",
+            termion::cursor::Hide,
+            ENABLE_BRACKETED_PASTE
+        ));
 
         Ok(Box::new(c))
     }
@@ -141,9 +192,11 @@ impl Backend {
         with_color(colors.back, |c| self.write(tcolor::Bg(c)));
     }
 
-    fn map_key(&mut self, event: TEvent) -> Event {
+    /// Maps termion's event to cursive's. Whatever can't be mapped is passed on as
+    /// `Event::Unknown` with the bytes that made it up.
+    fn map_key(&mut self, event: TEvent, raw: Vec<u8>) -> Event {
         match event {
-            TEvent::Unsupported(bytes) => Event::Unknown(bytes),
+            TEvent::Unsupported(bytes) => keys::decode_or_unknown(bytes),
             TEvent::Key(TKey::Esc) => Event::Key(Key::Esc),
             TEvent::Key(TKey::Backspace) => Event::Key(Key::Backspace),
             TEvent::Key(TKey::Left) => Event::Key(Key::Left),
@@ -156,15 +209,17 @@ impl Backend {
             TEvent::Key(TKey::PageDown) => Event::Key(Key::PageDown),
             TEvent::Key(TKey::Delete) => Event::Key(Key::Del),
             TEvent::Key(TKey::Insert) => Event::Key(Key::Ins),
-            TEvent::Key(TKey::F(i)) if i < 12 => Event::Key(Key::from_f(i)),
-            TEvent::Key(TKey::F(j)) => Event::Unknown(vec![j]),
+            TEvent::Key(TKey::F(i)) if i <= 12 => Event::Key(Key::from_f(i)),
+            TEvent::Key(TKey::BackTab) => Event::Shift(Key::Tab),
             TEvent::Key(TKey::Char('\n')) => Event::Key(Key::Enter),
             TEvent::Key(TKey::Char('\t')) => Event::Key(Key::Tab),
             TEvent::Key(TKey::Char(c)) => Event::Char(c),
             TEvent::Key(TKey::Ctrl(c)) => Event::CtrlChar(c),
+            TEvent::Key(TKey::Alt('\r' | '\n')) => Event::Alt(Key::Enter),
+            TEvent::Key(TKey::Alt('\x7f')) => Event::Alt(Key::Backspace),
             TEvent::Key(TKey::Alt(c)) => Event::AltChar(c),
             TEvent::Mouse(TMouseEvent::Press(btn, x, y)) => {
-                let position = (x - 1, y - 1).into();
+                let position = mouse_position(x, y);
 
                 let event = match btn {
                     TMouseButton::Left => MouseEvent::Press(MouseButton::Left),
@@ -184,25 +239,23 @@ impl Backend {
                     offset: Vec2::zero(),
                 }
             }
-            TEvent::Mouse(TMouseEvent::Release(x, y)) if self.last_button.is_some() => {
-                let event = MouseEvent::Release(self.last_button.unwrap());
-                let position = (x - 1, y - 1).into();
-                Event::Mouse {
-                    event,
-                    position,
+            TEvent::Mouse(TMouseEvent::Release(x, y)) => match self.last_button {
+                Some(btn) => Event::Mouse {
+                    event: MouseEvent::Release(btn),
+                    position: mouse_position(x, y),
                     offset: Vec2::zero(),
-                }
-            }
-            TEvent::Mouse(TMouseEvent::Hold(x, y)) if self.last_button.is_some() => {
-                let event = MouseEvent::Hold(self.last_button.unwrap());
-                let position = (x - 1, y - 1).into();
-                Event::Mouse {
-                    event,
-                    position,
+                },
+                None => Event::Unknown(raw),
+            },
+            TEvent::Mouse(TMouseEvent::Hold(x, y)) => match self.last_button {
+                Some(btn) => Event::Mouse {
+                    event: MouseEvent::Hold(btn),
+                    position: mouse_position(x, y),
                     offset: Vec2::zero(),
-                }
-            }
-            _ => Event::Unknown(vec![]),
+                },
+                None => Event::Unknown(raw),
+            },
+            _ => Event::Unknown(raw),
         }
     }
 
@@ -215,21 +268,20 @@ impl Backend {
             .extend(format!("{}", content).as_bytes().to_vec());
     }
 
-    fn close(&self) {
-        // Flush the output queue.
-        {
-            let mut data = self.data.borrow_mut();
-            if !data.is_empty() {
-                self.output_sender
-                    .blocking_send(CursiveOutput::Data(data.clone()))
-                    .unwrap();
-                data.clear();
-            }
+    /// Sends everything written since the last flush to the client. Once the session is being torn
+    /// down nobody is listening, and the output is dropped.
+    fn flush(&self) {
+        let mut data = self.data.borrow_mut();
+        if !data.is_empty() {
+            let _ = self
+                .output_sender
+                .blocking_send(CursiveOutput::Data(std::mem::take(&mut *data)));
         }
+    }
 
-        self.output_sender
-            .blocking_send(CursiveOutput::Close)
-            .unwrap();
+    fn close(&self) {
+        self.flush();
+        let _ = self.output_sender.blocking_send(CursiveOutput::Close);
     }
 }
 
@@ -238,10 +290,11 @@ impl Drop for Backend {
         self.running.store(false, Ordering::Relaxed);
 
         #[cfg(unix)]
-        set_blocking(self.input_fd, true).unwrap();
+        let _ = set_blocking(self.input_fd, true);
 
         self.write(format!(
-            "{}{}",
+            "{}{}{}",
+            DISABLE_BRACKETED_PASTE,
             termion::cursor::Show,
             termion::cursor::Goto(1, 1)
         ));
@@ -307,7 +360,7 @@ impl backend::Backend for Backend {
     }
 
     fn screen_size(&self) -> Vec2 {
-        self.size.clone()
+        self.size
     }
 
     fn clear(&self, color: theme::Color) {
@@ -321,6 +374,8 @@ impl backend::Backend for Backend {
 
     fn refresh(&mut self) {
         // TODO: Is this important for ssh connections?
+        let backlog = self.output_sender.max_capacity() - self.output_sender.capacity();
+        metrics::frame_rendered(backlog);
     }
 
     fn print_at(&self, pos: Vec2, text: &str) {
@@ -341,32 +396,76 @@ impl backend::Backend for Backend {
 
             let mut dupes_left = repetitions - 1;
             while dupes_left > 0 {
-                self.write(format!("{}", text));
+                self.write(text);
                 dupes_left -= 1;
             }
         }
     }
 
     fn poll_event(&mut self) -> Option<Event> {
-        {
-            let mut data = self.data.borrow_mut();
-            if !data.is_empty() {
-                self.output_sender
-                    .blocking_send(CursiveOutput::Data(data.clone()))
-                    .unwrap();
-                data.clear();
-            }
-        }
+        self.flush();
         if let Ok(size) = self.resize_receiver.try_recv() {
             self.size = size;
-            self.relayout_sender.blocking_send(()).unwrap();
+            let _ = self.relayout_sender.blocking_send(());
         }
-        if let Some(Ok(event)) = self.events.next() {
-            Some(self.map_key(event))
-        } else {
-            None
+        // termion's parser unwraps on malformed or truncated escape sequences, which a client can
+        // send on purpose. Losing the sequence is better than losing the session.
+        loop {
+            match catch_unwind(AssertUnwindSafe(|| self.events.next())) {
+                Ok(Some(Ok((event, raw)))) => {
+                    match collect_paste(&mut self.paste, &self.pastes, &raw) {
+                        Paste::None => return Some(self.map_key(event, raw)),
+                        Paste::Pending => {}
+                        // Input after a paste has to wait until the session has handled it.
+                        Paste::Done => return None,
+                    }
+                }
+                Ok(_) => return None,
+                Err(_) => {
+                    debug!("Dropped input that termion failed to parse");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Collects the contents of a bracketed paste into `paste`, keeping what the client sent rather
+/// than the keys termion reads into it, and hands it to `pastes` once it ends. `raw` is the input
+/// making up a single event.
+fn collect_paste(paste: &mut Option<Vec<u8>>, pastes: &Pastes, raw: &[u8]) -> Paste {
+    let buffer = match paste {
+        Some(buffer) => buffer,
+        None if raw == PASTE_START => {
+            *paste = Some(Vec::new());
+            return Paste::Pending;
+        }
+        None => return Paste::None,
+    };
+    if raw != PASTE_END {
+        let room = MAX_PASTE_BYTES - buffer.len();
+        buffer.extend_from_slice(&raw[..raw.len().min(room)]);
+        return Paste::Pending;
+    }
+    let mut bytes = &buffer[..];
+    if bytes.len() == MAX_PASTE_BYTES {
+        // The cut may have split the last character, which would otherwise come out as U+FFFD.
+        if let Some(last) = bytes.utf8_chunks().last() {
+            bytes = &bytes[..bytes.len() - last.invalid().len()];
         }
     }
+    // Terminals send line breaks in a paste as carriage returns, like the Enter key.
+    let text = String::from_utf8_lossy(bytes)
+        .replace("\r\n", "\n")
+        .replace('\r', "\n");
+    *paste = None;
+    pastes.push(text);
+    Paste::Done
+}
+
+/// Converts termion's one-based mouse coordinates, which a client could send as zero.
+fn mouse_position(x: u16, y: u16) -> Vec2 {
+    (x.saturating_sub(1), y.saturating_sub(1)).into()
 }
 
 fn with_color<F, R>(clr: theme::Color, f: F) -> R
@@ -397,3 +496,109 @@ where
         theme::Color::RgbLowRes(r, g, b) => f(&tcolor::AnsiValue::rgb(r, g, b)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `events` one at a time, returning where each left the paste.
+    fn collect(paste: &mut Option<Vec<u8>>, pastes: &Pastes, events: &[&[u8]]) -> Vec<Paste> {
+        events
+            .iter()
+            .map(|raw| collect_paste(paste, pastes, raw))
+            .collect()
+    }
+
+    #[test]
+    fn input_outside_a_paste_passes_through() {
+        let pastes = Pastes::default();
+        let mut paste = None;
+        assert_eq!(
+            collect(&mut paste, &pastes, &[b"a", PASTE_END]),
+            [Paste::None, Paste::None]
+        );
+        assert!(pastes.take().is_empty());
+    }
+
+    #[test]
+    fn normalizes_line_breaks() {
+        let pastes = Pastes::default();
+        let mut paste = None;
+        let events: &[&[u8]] = &[PASTE_START, b"a", b"\r\n", b"b", b"\r", b"c\n", PASTE_END];
+        let states = collect(&mut paste, &pastes, events);
+        assert_eq!(states.last(), Some(&Paste::Done));
+        assert_eq!(pastes.take(), ["a\nb\nc\n"]);
+        assert_eq!(paste, None);
+    }
+
+    #[test]
+    fn pastes_can_span_several_reads() {
+        let pastes = Pastes::default();
+        let mut paste = None;
+        assert_eq!(
+            collect(&mut paste, &pastes, &[PASTE_START, "h\u{e9}".as_bytes()]),
+            [Paste::Pending, Paste::Pending]
+        );
+        assert!(pastes.take().is_empty());
+        assert_eq!(
+            collect(&mut paste, &pastes, &[b"llo", PASTE_END, b"x"]),
+            [Paste::Pending, Paste::Done, Paste::None]
+        );
+        assert_eq!(pastes.take(), ["h\u{e9}llo"]);
+    }
+
+    #[test]
+    fn long_pastes_are_truncated() {
+        let pastes = Pastes::default();
+        let mut paste = None;
+        let chunk = [b'a'; 1000];
+        collect_paste(&mut paste, &pastes, PASTE_START);
+        for _ in 0..MAX_PASTE_BYTES / chunk.len() + 2 {
+            assert_eq!(collect_paste(&mut paste, &pastes, &chunk), Paste::Pending);
+        }
+        // Nothing shorter sneaks in after the cut.
+        collect_paste(&mut paste, &pastes, b"b");
+        assert_eq!(collect_paste(&mut paste, &pastes, PASTE_END), Paste::Done);
+        let text = pastes.take().remove(0);
+        assert_eq!(text.len(), MAX_PASTE_BYTES);
+        assert!(text.bytes().all(|byte| byte == b'a'));
+    }
+
+    #[test]
+    fn long_pastes_are_truncated_between_characters() {
+        for straddling in ["\u{e9}", "\u{20ac}", "\u{1f600}"] {
+            for inside in 1..straddling.len() {
+                let pastes = Pastes::default();
+                let mut paste = None;
+                let before = vec![b'a'; MAX_PASTE_BYTES - inside];
+                collect_paste(&mut paste, &pastes, PASTE_START);
+                collect_paste(&mut paste, &pastes, &before);
+                collect_paste(&mut paste, &pastes, straddling.as_bytes());
+                collect_paste(&mut paste, &pastes, PASTE_END);
+                let text = pastes.take().remove(0);
+                assert_eq!(
+                    text.as_bytes(),
+                    before,
+                    "{:?} cut after {}",
+                    straddling,
+                    inside
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pastes_that_never_end_stay_bounded() {
+        let pastes = Pastes::default();
+        let mut paste = None;
+        collect_paste(&mut paste, &pastes, PASTE_START);
+        for _ in 0..MAX_PASTE_BYTES / 512 + 10 {
+            assert_eq!(
+                collect_paste(&mut paste, &pastes, &[b'z'; 1024]),
+                Paste::Pending
+            );
+        }
+        assert_eq!(paste.map(|buffer| buffer.len()), Some(MAX_PASTE_BYTES));
+        assert!(pastes.take().is_empty());
+    }
+}
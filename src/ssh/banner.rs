@@ -0,0 +1,36 @@
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::listener::Peer;
+
+/// Text shown to clients before they authenticate, typically a legal notice.
+#[derive(Clone)]
+pub enum Banner {
+    /// The same banner for every connection.
+    Static(String),
+    /// A banner computed from the peer's address when it connects. Connections over Unix sockets
+    /// have no address and get no banner.
+    PerConnection(Arc<dyn Fn(SocketAddr) -> Option<String> + Send + Sync>),
+}
+
+impl Banner {
+    pub(crate) fn for_peer(&self, peer: Peer) -> Option<Arc<str>> {
+        match (self, peer) {
+            (Banner::Static(text), _) => Some(Arc::from(text.as_str())),
+            (Banner::PerConnection(compute), Peer::Tcp(peer_addr)) => {
+                compute(peer_addr).map(Arc::from)
+            }
+            (Banner::PerConnection(_), Peer::Unix) => None,
+        }
+    }
+}
+
+impl Debug for Banner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Static(text) => f.debug_tuple("Static").field(text).finish(),
+            Self::PerConnection(_) => f.debug_tuple("PerConnection").finish(),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt::Debug;
+
+use russh::{server::Handle, ChannelId, CryptoVec};
+use tokio::sync::mpsc::Sender;
+
+/// Output for a frontend that encodes and writes it from its own task, such as telnet.
+#[derive(Debug)]
+pub(crate) enum FrontendOutput {
+    Data(Vec<u8>),
+    Close,
+}
+
+/// Where the output of a session, waiting room or spectator goes.
+#[derive(Clone)]
+pub(crate) enum ClientOutput {
+    Ssh(Handle, ChannelId),
+    Frontend(Sender<FrontendOutput>),
+}
+
+impl ClientOutput {
+    /// Sends data to the client. Fails once the client has gone away.
+    pub async fn data(&self, data: &[u8]) -> Result<(), ()> {
+        match self {
+            ClientOutput::Ssh(handle, channel_id) => handle
+                .data(*channel_id, CryptoVec::from_slice(data))
+                .await
+                .map_err(|_| ()),
+            ClientOutput::Frontend(sender) => sender
+                .send(FrontendOutput::Data(data.to_vec()))
+                .await
+                .map_err(|_| ()),
+        }
+    }
+
+    /// Closes the client's channel or connection.
+    pub async fn close(&self) {
+        match self {
+            ClientOutput::Ssh(handle, channel_id) => {
+                let _ = handle.close(*channel_id).await;
+            }
+            ClientOutput::Frontend(sender) => {
+                let _ = sender.send(FrontendOutput::Close).await;
+            }
+        }
+    }
+}
+
+impl Debug for ClientOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientOutput::Ssh(_, channel_id) => f.debug_tuple("Ssh").field(channel_id).finish(),
+            ClientOutput::Frontend(_) => f.debug_tuple("Frontend").finish(),
+        }
+    }
+}
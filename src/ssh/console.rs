@@ -0,0 +1,427 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use russh_keys::key::PublicKey;
+use tokio::sync::mpsc::Sender;
+
+use crate::cursive::traits::{Nameable, Resizable, Scrollable};
+use crate::cursive::views::{Dialog, DummyView, EditView, LinearLayout, SelectView, TextView};
+use crate::cursive::{Cursive, View};
+use crate::{App, AppSession, ListenAddr, SessionHandle, SessionTimeouts};
+
+use super::metrics;
+use super::session_manager::{SessionChannel, SessionRegistry};
+
+const SESSION_LIST: &str = "ssh_ui_console_sessions";
+const SUMMARY: &str = "ssh_ui_console_summary";
+const MESSAGE_INPUT: &str = "ssh_ui_console_message";
+
+/// A built-in app for operators, listing the live sessions with their username, address, duration
+/// and terminal size. From there a session can be kicked, sent a message or have its recording
+/// toggled, next to a summary of the server's metrics.
+///
+/// Only clients authenticating with one of the authorized keys get the console, everyone else
+/// reaching it is turned away.
+#[derive(Clone, Debug)]
+pub struct AdminConsole {
+    mount: Mount,
+    authorized_keys: Vec<PublicKey>,
+}
+
+#[derive(Clone, Debug)]
+enum Mount {
+    Username(String),
+    ListenAddr(ListenAddr),
+}
+
+impl AdminConsole {
+    /// Serves the console to ssh clients logging in as `username` on the app's own listeners.
+    pub fn on_username(username: impl Into<String>, authorized_keys: Vec<PublicKey>) -> Self {
+        Self {
+            mount: Mount::Username(username.into()),
+            authorized_keys,
+        }
+    }
+
+    /// Serves the console to every ssh client connecting to `listen`, whatever their username.
+    pub fn on_listen_addr(listen: impl Into<ListenAddr>, authorized_keys: Vec<PublicKey>) -> Self {
+        Self {
+            mount: Mount::ListenAddr(listen.into()),
+            authorized_keys,
+        }
+    }
+
+    pub(crate) fn listen_addr(&self) -> Option<&ListenAddr> {
+        match &self.mount {
+            Mount::ListenAddr(listen) => Some(listen),
+            Mount::Username(_) => None,
+        }
+    }
+
+    /// Whether the channel asked for the console, and if so whether it's allowed in.
+    pub(crate) fn admits(&self, channel: &SessionChannel) -> Option<bool> {
+        let wants_console = match &self.mount {
+            Mount::Username(username) => *username == channel.username,
+            Mount::ListenAddr(_) => channel.console,
+        };
+        wants_console.then(|| {
+            channel.key.as_ref().is_some_and(|key| {
+                let fingerprint = key.fingerprint();
+                self.authorized_keys
+                    .iter()
+                    .any(|authorized| authorized.fingerprint() == fingerprint)
+            })
+        })
+    }
+}
+
+/// Operator requests for a session, picked up by its event loop.
+#[derive(Clone, Default)]
+pub(crate) struct SessionControl(Arc<Mutex<ControlState>>);
+
+#[derive(Default)]
+struct ControlState {
+    kicked: bool,
+    messages: Vec<String>,
+}
+
+impl SessionControl {
+    pub fn kick(&self) {
+        self.0.lock().unwrap().kicked = true;
+    }
+
+    pub fn message(&self, message: String) {
+        self.0.lock().unwrap().messages.push(message);
+    }
+
+    /// Takes the pending requests: whether the session was kicked, and the messages to show.
+    pub fn take(&self) -> (bool, Vec<String>) {
+        let mut state = self.0.lock().unwrap();
+        (state.kicked, std::mem::take(&mut state.messages))
+    }
+}
+
+/// The console itself, reading the session manager's bookkeeping.
+pub(crate) struct ConsoleApp {
+    sessions: SessionRegistry,
+}
+
+impl ConsoleApp {
+    pub fn new(sessions: SessionRegistry) -> Self {
+        Self { sessions }
+    }
+}
+
+impl App for ConsoleApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        Box::new(ConsoleSession {
+            sessions: self.sessions.clone(),
+        })
+    }
+}
+
+struct ConsoleSession {
+    sessions: SessionRegistry,
+}
+
+impl AppSession for ConsoleSession {
+    fn on_start(
+        &mut self,
+        siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn std::error::Error>> {
+        // Redraws the list once a second, through `on_tick`.
+        siv.set_fps(1);
+        let kick_sessions = self.sessions.clone();
+        let message_sessions = self.sessions.clone();
+        let recording_sessions = self.sessions.clone();
+        let layout = LinearLayout::vertical()
+            .child(TextView::new(summary()).with_name(SUMMARY))
+            .child(DummyView)
+            .child(TextView::new(format!(
+                "{:>6}  {:<16}  {:<24}  {:>9}  {:>9}  {}",
+                "ID", "USER", "ADDRESS", "DURATION", "SIZE", "FLAGS"
+            )))
+            .child(
+                SelectView::<SessionHandle>::new()
+                    .with_all(rows(&self.sessions))
+                    .with_name(SESSION_LIST)
+                    .scrollable()
+                    .full_height(),
+            );
+        let console = Dialog::around(layout)
+            .title("Admin console")
+            .button("Kick", move |siv| {
+                if let Some(handle) = selected(siv) {
+                    if let Some(session) = kick_sessions.lock().unwrap().get(&handle) {
+                        session.control.kick();
+                    }
+                }
+            })
+            .button("Message", move |siv| {
+                if let Some(handle) = selected(siv) {
+                    let submit_sessions = message_sessions.clone();
+                    let send_sessions = message_sessions.clone();
+                    let input = EditView::new()
+                        .on_submit(move |siv, message| {
+                            send_message(siv, &submit_sessions, handle, message)
+                        })
+                        .with_name(MESSAGE_INPUT)
+                        .fixed_width(50);
+                    siv.add_layer(
+                        Dialog::around(input)
+                            .title(format!("Message session {}", handle.0))
+                            .button("Send", move |siv| {
+                                let message = siv
+                                    .call_on_name(MESSAGE_INPUT, |input: &mut EditView| {
+                                        input.get_content()
+                                    })
+                                    .unwrap_or_default();
+                                send_message(siv, &send_sessions, handle, &message)
+                            })
+                            .dismiss_button("Cancel"),
+                    );
+                }
+            })
+            .button("Toggle recording", move |siv| {
+                let handle = match selected(siv) {
+                    Some(handle) => handle,
+                    None => return,
+                };
+                let recorder = match recording_sessions.lock().unwrap().get(&handle) {
+                    Some(session) => session.recorder.clone(),
+                    None => return,
+                };
+                match recorder.enabled() {
+                    Some(enabled) => recorder.set_enabled(!enabled),
+                    None => siv.add_layer(
+                        Dialog::info("The server has no recording policy.").title("Recording"),
+                    ),
+                }
+            })
+            .button("Quit", |siv| siv.quit())
+            .full_screen();
+        Ok(Box::new(console))
+    }
+
+    fn on_tick(&mut self, siv: &mut Cursive) -> Result<(), Box<dyn std::error::Error>> {
+        let rows = rows(&self.sessions);
+        siv.call_on_name(SESSION_LIST, |list: &mut SelectView<SessionHandle>| {
+            let selection = list.selection().map(|handle| *handle);
+            list.clear();
+            list.add_all(rows);
+            let position = selection
+                .and_then(|selection| list.iter().position(|(_, handle)| *handle == selection));
+            if let Some(position) = position {
+                // The list has no select callback, so there's nothing to run.
+                let _ = list.set_selection(position);
+            }
+        });
+        siv.call_on_name(SUMMARY, |text: &mut TextView| text.set_content(summary()));
+        Ok(())
+    }
+
+    /// The console is for operators, who shouldn't be timed out or recorded.
+    fn timeouts(&self, _server_timeouts: &SessionTimeouts) -> SessionTimeouts {
+        SessionTimeouts::default()
+    }
+
+    fn motd(&self, _server_motd: Option<&str>) -> Option<String> {
+        None
+    }
+
+    fn record(&self, _server_default: bool) -> bool {
+        false
+    }
+}
+
+fn selected(siv: &mut Cursive) -> Option<SessionHandle> {
+    siv.call_on_name(SESSION_LIST, |list: &mut SelectView<SessionHandle>| {
+        list.selection().map(|handle| *handle)
+    })
+    .flatten()
+}
+
+fn send_message(
+    siv: &mut Cursive,
+    sessions: &SessionRegistry,
+    handle: SessionHandle,
+    message: &str,
+) {
+    if let Some(session) = sessions.lock().unwrap().get(&handle) {
+        session.control.message(message.to_string());
+    }
+    siv.pop_layer();
+}
+
+fn rows(sessions: &SessionRegistry) -> Vec<(String, SessionHandle)> {
+    let mut rows: Vec<_> = sessions
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(handle, session)| {
+            let size = session.spectators.player_size();
+            let mut flags = Vec::new();
+            if session.console {
+                flags.push("console");
+            }
+            if session.recorder.enabled() == Some(true) {
+                flags.push("recording");
+            }
+            let label = format!(
+                "{:>6}  {:<16}  {:<24}  {:>9}  {:>9}  {}",
+                handle.0,
+                session.username,
+                session
+                    .peer
+                    .map_or_else(|| "local".to_string(), |peer| peer.to_string()),
+                duration(session.started.elapsed()),
+                format!("{}x{}", size.x, size.y),
+                flags.join(",")
+            );
+            (label, *handle)
+        })
+        .collect();
+    rows.sort_by_key(|(_, handle)| *handle);
+    rows
+}
+
+fn summary() -> String {
+    let totals = metrics::totals();
+    format!(
+        "Sessions: {} active, {} started    Connections: {}    Failed logins: {}\nTraffic: {} in, {} out",
+        totals.active_sessions,
+        totals.sessions_started,
+        totals.connections_accepted,
+        totals.auth_failures,
+        bytes(totals.bytes_in),
+        bytes(totals.bytes_out)
+    )
+}
+
+fn duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use russh_keys::key::KeyPair;
+
+    use super::*;
+    use crate::cursive::Vec2;
+    use crate::ssh::client::ClientOutput;
+    use crate::ssh::spans;
+    use crate::testing::TestSession;
+
+    fn key() -> PublicKey {
+        KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap()
+    }
+
+    fn channel(username: &str, key: Option<PublicKey>, console: bool) -> SessionChannel {
+        let (output, _) = tokio::sync::mpsc::channel(1);
+        let (_, update_rx) = tokio::sync::mpsc::channel(1);
+        SessionChannel {
+            output: ClientOutput::Frontend(output),
+            update_rx,
+            username: username.to_string(),
+            key,
+            peer: None,
+            console,
+            span: spans::current(),
+        }
+    }
+
+    #[test]
+    fn username_mount_only_admits_authorized_keys() {
+        let admin = key();
+        let console = AdminConsole::on_username("admin", vec![admin.clone()]);
+        assert_eq!(
+            console.admits(&channel("alice", Some(admin.clone()), false)),
+            None
+        );
+        assert_eq!(
+            console.admits(&channel("admin", Some(admin), false)),
+            Some(true)
+        );
+        assert_eq!(
+            console.admits(&channel("admin", Some(key()), false)),
+            Some(false)
+        );
+        assert_eq!(console.admits(&channel("admin", None, false)), Some(false));
+    }
+
+    #[test]
+    fn listen_addr_mount_ignores_the_username() {
+        let admin = key();
+        let listen = SocketAddr::from((Ipv4Addr::LOCALHOST, 2222));
+        let console = AdminConsole::on_listen_addr(listen, vec![admin.clone()]);
+        assert_eq!(console.listen_addr(), Some(&ListenAddr::Tcp(listen)));
+        assert_eq!(
+            console.admits(&channel("admin", Some(admin.clone()), false)),
+            None
+        );
+        assert_eq!(
+            console.admits(&channel("alice", Some(admin), true)),
+            Some(true)
+        );
+        assert_eq!(
+            console.admits(&channel("alice", Some(key()), true)),
+            Some(false)
+        );
+        assert_eq!(
+            console.admits(&channel("anonymous", None, true)),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn console_lists_no_sessions_on_an_idle_server() {
+        let app = ConsoleApp::new(Arc::new(Mutex::new(HashMap::new())));
+        let session = TestSession::new(&app, Vec2::new(100, 20)).unwrap();
+        let screen = session.screen();
+        assert!(screen.contains("Admin console"));
+        assert!(screen.contains("Sessions: 0 active"));
+        assert!(screen.contains("USER"));
+    }
+
+    #[test]
+    fn units_scale_with_the_size() {
+        assert_eq!(bytes(512), "512 B");
+        assert_eq!(bytes(1536), "1.5 KiB");
+        assert_eq!(bytes(3 * 1024 * 1024 * 1024 * 1024), "3072.0 GiB");
+        assert_eq!(duration(Duration::from_secs(3725)), "1:02:05");
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::Ok;
+use log::debug;
 use log::info;
 use log::trace;
 use russh::server::Auth;
@@ -9,24 +9,132 @@ use russh::Channel;
 use russh::ChannelId;
 use russh::MethodSet;
 use russh_keys::key::PublicKey;
-use tokio::spawn;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 
+use crate::Error;
+
+use super::audit::{self, AuditEvent};
+use super::client::ClientOutput;
+use super::limits::ConnectionLimiter;
+use super::limits::ConnectionPermit;
+use super::metrics;
+use super::session_manager::SessionChannel;
 use super::session_manager::SessionRepoUpdate;
 use super::session_manager::SshSessionUpdate;
+use super::spans::{self, Span};
 
 pub struct ThinHandler {
     session_repo_update_sender: Sender<SessionRepoUpdate>,
-    session_update_sender: Option<Sender<SshSessionUpdate>>,
+    channels: HashMap<ChannelId, Sender<SshSessionUpdate>>,
+    /// The last key accepted by `auth_publickey`. A client can query keys without proving it holds
+    /// them, so this only becomes `pubkey` once authentication has succeeded, by which point it's
+    /// the key whose signature was verified.
+    offered: Option<PublicKey>,
+    /// The key the client authenticated with, if any.
     pubkey: Option<PublicKey>,
+    username: String,
+    limiter: ConnectionLimiter,
+    /// Unix socket connections aren't counted against any IP, so they have no permit.
+    permit: Option<ConnectionPermit>,
+    /// Unix socket peers have no address.
+    peer: Option<SocketAddr>,
+    /// Whether the connection came in on the admin console's listener.
+    console: bool,
+    /// Shown to the client before it authenticates.
+    banner: Option<Arc<str>>,
+    /// russh runs the handler on a task of its own, so every callback enters this explicitly.
+    span: Span,
 }
 
 impl ThinHandler {
-    pub(crate) fn new(session_repo_update_sender: Sender<SessionRepoUpdate>) -> ThinHandler {
+    pub(crate) fn new(
+        session_repo_update_sender: Sender<SessionRepoUpdate>,
+        limiter: ConnectionLimiter,
+        permit: Option<ConnectionPermit>,
+        peer: Option<SocketAddr>,
+        console: bool,
+        banner: Option<Arc<str>>,
+        span: Span,
+    ) -> ThinHandler {
         ThinHandler {
             session_repo_update_sender,
-            session_update_sender: None,
+            channels: HashMap::new(),
+            offered: None,
             pubkey: None,
+            username: String::new(),
+            limiter,
+            permit,
+            peer,
+            console,
+            banner,
+            span,
+        }
+    }
+
+    /// Rejects an authentication attempt and counts it against the peer. Once the peer is banned
+    /// the connection is dropped instead of letting it keep guessing.
+    fn reject(mut self) -> Result<(Self, Auth), Error> {
+        self.offered = None;
+        if let Some(ip) = self.permit.as_ref().map(ConnectionPermit::ip) {
+            if self.limiter.record_auth_failure(ip) || self.limiter.is_banned(ip) {
+                return Err(Error::Auth(format!("{} is banned", ip)));
+            }
+        }
+        Ok((
+            self,
+            Auth::Reject {
+                proceed_with_methods: None,
+            },
+        ))
+    }
+
+    fn audit_auth(
+        &self,
+        method: &'static str,
+        user: &str,
+        public_key: Option<&PublicKey>,
+        accepted: bool,
+    ) {
+        audit::record(AuditEvent::Auth {
+            peer: self.peer,
+            method,
+            username: user.to_string(),
+            fingerprint: public_key.map(PublicKey::fingerprint),
+            accepted,
+        });
+    }
+
+    /// Asks the session manager for a session drawing to `output`, returning the sender for the
+    /// session's updates. Fails once the session manager has gone away.
+    async fn start_session(&self, output: ClientOutput) -> Result<Sender<SshSessionUpdate>, Error> {
+        let (session_update_sender, session_update_receiver) = tokio::sync::mpsc::channel(100);
+        self.session_repo_update_sender
+            .send(SessionRepoUpdate::NewSession(SessionChannel {
+                output,
+                update_rx: session_update_receiver,
+                username: self.username.clone(),
+                key: self.pubkey.clone(),
+                peer: self.peer,
+                console: self.console,
+                span: self.span.clone(),
+            }))
+            .await
+            .map_err(|_| Error::Disconnected)?;
+        Ok(session_update_sender)
+    }
+
+    /// Hands an update to the session attached to `channel`, if there is one.
+    async fn forward(&self, channel: ChannelId, update: SshSessionUpdate) {
+        match self.channels.get(&channel) {
+            Some(sender) => {
+                if sender.send(update).await.is_err() {
+                    debug!("Session for channel {:?} has already ended", channel);
+                }
+            }
+            None => debug!("Dropping update for unknown channel {:?}", channel),
         }
     }
 }
@@ -38,25 +146,18 @@ impl Handler for ThinHandler {
         channel: Channel<Msg>,
         session: Session,
     ) -> Result<(Self, bool, Session), Self::Error> {
-        info!("Channel opened");
-        let (session_update_sender, session_update_receiver) = tokio::sync::mpsc::channel(100);
-        self.session_update_sender = Some(session_update_sender);
-        let sender = self.session_repo_update_sender.clone();
-        let handle = session.handle();
-        let pubkey = self.pubkey.clone();
-        spawn(async move {
-            sender
-                .send(SessionRepoUpdate::NewSession(
-                    handle,
-                    channel.id(),
-                    session_update_receiver,
-                    pubkey,
-                ))
-                .await
-                .unwrap();
-        });
-
-        Ok((self, true, session))
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                info!("Channel {:?} opened", channel.id());
+                let output = ClientOutput::Ssh(session.handle(), channel.id());
+                let session_update_sender = self.start_session(output).await?;
+                self.channels.insert(channel.id(), session_update_sender);
+                Ok((self, true, session))
+            },
+            span,
+        )
+        .await
     }
 
     async fn auth_publickey(
@@ -64,86 +165,162 @@ impl Handler for ThinHandler {
         user: &str,
         public_key: &PublicKey,
     ) -> Result<(Self, Auth), Self::Error> {
-        info!(
-            "Public key auth request for user {} using key {:?}",
-            user, public_key
-        );
-        if user == "root" {
-            Ok((
-                self,
-                Auth::Reject {
-                    proceed_with_methods: None,
-                },
-            ))
-        } else {
-            self.pubkey = Some(public_key.clone());
-            Ok((self, Auth::Accept))
-        }
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                info!(
+                    "Public key auth request for user {} using key {:?}",
+                    user, public_key
+                );
+                if user == "root" {
+                    metrics::auth_attempt("publickey", false);
+                    self.audit_auth("publickey", user, Some(public_key), false);
+                    self.reject()
+                } else {
+                    // Only a probe until the client signs with the key, see `auth_succeeded`.
+                    metrics::key_offered();
+                    audit::record(AuditEvent::KeyOffered {
+                        peer: self.peer,
+                        username: user.to_string(),
+                        fingerprint: public_key.fingerprint(),
+                    });
+                    spans::record_username(&self.span, user);
+                    self.offered = Some(public_key.clone());
+                    self.username = user.to_string();
+                    Ok((self, Auth::Accept))
+                }
+            },
+            span,
+        )
+        .await
     }
 
-    async fn auth_none(self, user: &str) -> Result<(Self, Auth), Self::Error> {
-        info!("`None` auth request for user {} using", user);
-        match user {
-            // This might be fun for a honeypot but anyone looking for `none` auth with a root user deserves to be shut down.
-            "root" => Ok((
-                self,
-                Auth::Reject {
-                    proceed_with_methods: None,
-                },
-            )),
-            "anon" | "anonymous" => Ok((self, Auth::Accept)),
-            _ => Ok((
-                self,
-                Auth::Reject {
-                    proceed_with_methods: Some(MethodSet::PUBLICKEY),
-                },
-            )),
-        }
+    async fn auth_none(mut self, user: &str) -> Result<(Self, Auth), Self::Error> {
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                info!("`None` auth request for user {} using", user);
+                self.offered = None;
+                let accepted = matches!(user, "anon" | "anonymous");
+                metrics::auth_attempt("none", accepted);
+                self.audit_auth("none", user, None, accepted);
+                match user {
+                    // This might be fun for a honeypot but anyone looking for `none` auth with a root user deserves to be shut down.
+                    "root" => self.reject(),
+                    "anon" | "anonymous" => {
+                        spans::record_username(&self.span, user);
+                        self.username = user.to_string();
+                        Ok((self, Auth::Accept))
+                    }
+                    _ => Ok((
+                        self,
+                        Auth::Reject {
+                            proceed_with_methods: Some(MethodSet::PUBLICKEY),
+                        },
+                    )),
+                }
+            },
+            span,
+        )
+        .await
+    }
+
+    async fn auth_succeeded(mut self, session: Session) -> Result<(Self, Session), Self::Error> {
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                // Whichever method succeeded was the last one asked about, so a key is only still
+                // on offer if its signature was just verified.
+                self.pubkey = self.offered.take();
+                if let Some(key) = &self.pubkey {
+                    metrics::auth_attempt("publickey", true);
+                    self.audit_auth("publickey", &self.username, Some(key), true);
+                }
+                Ok((self, session))
+            },
+            span,
+        )
+        .await
     }
 
     async fn channel_close(
-        self,
-        _channel: ChannelId,
+        mut self,
+        channel: ChannelId,
         session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        let sender = self.session_update_sender.clone().unwrap();
-        spawn(async move {
-            sender.send(SshSessionUpdate::Close).await.unwrap();
-        });
-        Result::Ok((self, session))
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                self.forward(channel, SshSessionUpdate::Close).await;
+                self.channels.remove(&channel);
+                Result::Ok((self, session))
+            },
+            span,
+        )
+        .await
     }
 
     async fn data(
         self,
-        _channel: ChannelId,
+        channel: ChannelId,
         data: &[u8],
         session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        let data = data.to_vec();
-        let sender = self.session_update_sender.clone();
-        spawn(async move {
-            sender
-                .clone()
-                .unwrap()
-                .send(SshSessionUpdate::Data(data.to_vec()))
-                .await
-                .unwrap();
-        });
-        Result::Ok((self, session))
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                self.forward(channel, SshSessionUpdate::Data(data.to_vec()))
+                    .await;
+                Result::Ok((self, session))
+            },
+            span,
+        )
+        .await
     }
 
     async fn shell_request(
         self,
-        _channel: ChannelId,
+        channel: ChannelId,
         session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        info!("shell request");
-        Result::Ok((self, session))
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                info!("shell request on channel {:?}", channel);
+                Result::Ok((self, session))
+            },
+            span,
+        )
+        .await
+    }
+
+    /// Commands aren't run, the session starts as usual, but they're kept in the audit trail.
+    async fn exec_request(
+        self,
+        channel: ChannelId,
+        data: &[u8],
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                let command = String::from_utf8_lossy(data).into_owned();
+                info!("exec request on channel {:?}: {}", channel, command);
+                audit::record(AuditEvent::Exec {
+                    peer: self.peer,
+                    username: self.username.clone(),
+                    command,
+                });
+                Result::Ok((self, session))
+            },
+            span,
+        )
+        .await
     }
 
     async fn pty_request(
         self,
-        _channel: ChannelId,
+        channel: ChannelId,
         _term: &str,
         col_width: u32,
         row_height: u32,
@@ -152,50 +329,121 @@ impl Handler for ThinHandler {
         _modes: &[(russh::Pty, u32)],
         session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        info!("pty request");
-        let sender = self.session_update_sender.clone();
-        spawn(async move {
-            sender
-                .clone()
-                .unwrap()
-                .send(SshSessionUpdate::WindowResize(
-                    col_width as usize,
-                    row_height as usize,
-                ))
-                .await
-                .unwrap();
-        });
-        Result::Ok((self, session))
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                info!("pty request on channel {:?}", channel);
+                self.forward(
+                    channel,
+                    SshSessionUpdate::WindowResize(col_width as usize, row_height as usize),
+                )
+                .await;
+                Result::Ok((self, session))
+            },
+            span,
+        )
+        .await
     }
 
     async fn window_change_request(
         self,
-        _channel: ChannelId,
+        channel: ChannelId,
         col_width: u32,
         row_height: u32,
         _pix_width: u32,
         _pix_height: u32,
         session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        trace!("window change request");
-        let sender = self.session_update_sender.clone();
-        spawn(async move {
-            sender
-                .clone()
-                .unwrap()
-                .send(SshSessionUpdate::WindowResize(
-                    col_width as usize,
-                    row_height as usize,
-                ))
-                .await
-                .unwrap();
-        });
-        Result::Ok((self, session))
+        let span = self.span.clone();
+        spans::instrument(
+            async move {
+                trace!("window change request on channel {:?}", channel);
+                self.forward(
+                    channel,
+                    SshSessionUpdate::WindowResize(col_width as usize, row_height as usize),
+                )
+                .await;
+                Result::Ok((self, session))
+            },
+            span,
+        )
+        .await
     }
 
-    type Error = anyhow::Error;
+    type Error = Error;
+
+    fn auth_banner(&self) -> Option<&str> {
+        self.banner.as_deref()
+    }
 }
 
 impl Drop for ThinHandler {
     fn drop(&mut self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::ssh::limits::ConnectionLimits;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn handler(sender: Sender<SessionRepoUpdate>, limiter: ConnectionLimiter) -> ThinHandler {
+        let permit = limiter.try_admit(PEER).unwrap();
+        ThinHandler::new(
+            sender,
+            limiter,
+            Some(permit),
+            Some(SocketAddr::new(PEER, 2222)),
+            false,
+            None,
+            spans::current(),
+        )
+    }
+
+    fn output() -> ClientOutput {
+        let (output, _) = tokio::sync::mpsc::channel(1);
+        ClientOutput::Frontend(output)
+    }
+
+    #[tokio::test]
+    async fn sessions_are_handed_to_the_session_manager() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let handler = handler(sender, ConnectionLimiter::new(ConnectionLimits::default()));
+        assert!(handler.start_session(output()).await.is_ok());
+        assert!(matches!(
+            receiver.recv().await,
+            Some(SessionRepoUpdate::NewSession(SessionChannel {
+                peer: Some(_),
+                ..
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_fail_once_the_session_manager_is_gone() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        drop(receiver);
+        let handler = handler(sender, ConnectionLimiter::new(ConnectionLimits::default()));
+        assert!(matches!(
+            handler.start_session(output()).await,
+            Err(Error::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn banned_peers_are_refused_rather_than_rejected() {
+        let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+        let limiter = ConnectionLimiter::new(ConnectionLimits {
+            max_auth_failures: Some(2),
+            ..Default::default()
+        });
+        let handler = handler(sender, limiter);
+        let Ok((handler, Auth::Reject { .. })) = handler.reject() else {
+            panic!("the first failure should only be rejected");
+        };
+        assert!(matches!(handler.reject(), Err(Error::Auth(_))));
+    }
+}
@@ -0,0 +1,78 @@
+use std::any::Any;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::SystemTime;
+
+use crate::cursive::views::{Dialog, TextView};
+use crate::cursive::View;
+use crate::{Error, SessionHandle};
+
+/// A session that ended because the app failed, as reported to [`App::on_error`](crate::App::on_error).
+#[derive(Debug)]
+pub struct Incident {
+    /// Short identifier shown to the user on the error screen, so their report can be matched
+    /// with the server's logs.
+    pub id: String,
+    /// The session that failed.
+    pub session: SessionHandle,
+    /// What went wrong.
+    pub error: Error,
+}
+
+impl Incident {
+    pub(crate) fn new(session: SessionHandle, error: Error) -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        SystemTime::now().hash(&mut hasher);
+        session.hash(&mut hasher);
+        Self {
+            id: format!("{:08x}", hasher.finish() as u32),
+            session,
+            error,
+        }
+    }
+}
+
+/// What the user sees when their session fails. Every `{incident}` in the message is replaced by
+/// the incident's ID.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorScreen {
+    pub title: String,
+    pub message: String,
+}
+
+impl ErrorScreen {
+    pub fn new(title: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn view(&self, incident: &Incident) -> impl View {
+        Dialog::around(TextView::new(
+            self.message.replace("{incident}", &incident.id),
+        ))
+        .title(self.title.clone())
+        .button("Disconnect", |siv| siv.quit())
+    }
+}
+
+impl Default for ErrorScreen {
+    fn default() -> Self {
+        Self::new(
+            "Something went wrong",
+            "Sorry, this session ran into a problem and can't continue.\n\nIf you report it, please mention incident {incident}.",
+        )
+    }
+}
+
+/// Extracts the message a panic was started with.
+pub(crate) fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
@@ -0,0 +1,255 @@
+//! Decodes the escape sequences xterm and compatible terminals send for special keys, including
+//! the modifier-encoded ones termion doesn't understand, such as `ESC [ 1 ; 5 A` for Ctrl+Up or
+//! `ESC [ 3 ; 2 ~` for Shift+Delete. rxvt's encodings of modified arrows, and of Ctrl on the keys
+//! numbered with `~`, are understood too.
+
+use crate::cursive::event::{Event, Key};
+
+const SHIFT: u16 = 1;
+const ALT: u16 = 2;
+const CTRL: u16 = 4;
+const META: u16 = 8;
+
+/// Decodes a complete CSI (`ESC [`) or SS3 (`ESC O`) sequence, as termion delimited it. Returns
+/// `None` for anything that isn't a key cursive has an event for.
+pub(crate) fn decode(raw: &[u8]) -> Option<Event> {
+    let (introducer, body) = match raw {
+        [0x1b, introducer @ (b'[' | b'O'), body @ ..] => (*introducer, body),
+        _ => return None,
+    };
+    let (&final_byte, params) = body.split_last()?;
+    let params = std::str::from_utf8(params).ok()?;
+    let params: Vec<u16> = if params.is_empty() {
+        Vec::new()
+    } else {
+        params
+            .split(';')
+            .map(|param| param.parse().ok())
+            .collect::<Option<_>>()?
+    };
+    let (key, modifiers) = match (introducer, final_byte, params.as_slice()) {
+        // rxvt sends Shift+arrows as CSI and Ctrl+arrows as SS3 with a lowercase final byte.
+        (b'[', b'a'..=b'd', []) => (letter_key(final_byte.to_ascii_uppercase())?, SHIFT),
+        (b'O', b'a'..=b'd', []) => (letter_key(final_byte.to_ascii_uppercase())?, CTRL),
+        (b'[', b'~', [code, modifiers @ ..]) => (tilde_key(*code)?, modifier(modifiers)?),
+        // rxvt marks Ctrl, and Ctrl with Shift, with the final byte instead of a parameter. Its `$`
+        // for Shift isn't a final byte, so termion never delimits those sequences properly.
+        (b'[', b'^', [code]) => (tilde_key(*code)?, CTRL),
+        (b'[', b'@', [code]) => (tilde_key(*code)?, CTRL | SHIFT),
+        (b'[', b'Z', []) => (Key::Tab, SHIFT),
+        (_, _, []) => (letter_key(final_byte)?, 0),
+        (b'[', _, [1, modifiers]) => (letter_key(final_byte)?, modifier(&[*modifiers])?),
+        _ => return None,
+    };
+    with_modifiers(key, modifiers)
+}
+
+/// Decodes `raw`, which termion couldn't, passing it on unchanged as `Event::Unknown` if it isn't
+/// a key either.
+pub(crate) fn decode_or_unknown(raw: Vec<u8>) -> Event {
+    decode(&raw).unwrap_or(Event::Unknown(raw))
+}
+
+/// Keys whose sequence ends with a letter, such as `ESC [ A` or `ESC O P`.
+fn letter_key(final_byte: u8) -> Option<Key> {
+    Some(match final_byte {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'E' => Key::NumpadCenter,
+        b'F' => Key::End,
+        b'H' => Key::Home,
+        b'P' => Key::F1,
+        b'Q' => Key::F2,
+        b'R' => Key::F3,
+        b'S' => Key::F4,
+        _ => return None,
+    })
+}
+
+/// Keys numbered in a sequence ending with `~`, such as `ESC [ 3 ~`. F13 and up have no cursive
+/// key.
+fn tilde_key(code: u16) -> Option<Key> {
+    Some(match code {
+        1 | 7 => Key::Home,
+        2 => Key::Ins,
+        3 => Key::Del,
+        4 | 8 => Key::End,
+        5 => Key::PageUp,
+        6 => Key::PageDown,
+        11..=15 => Key::from_f(code as u8 - 10),
+        17..=21 => Key::from_f(code as u8 - 11),
+        23 | 24 => Key::from_f(code as u8 - 12),
+        _ => return None,
+    })
+}
+
+/// Decodes xterm's modifier parameter, which is one more than a bitmask of the modifiers held.
+fn modifier(params: &[u16]) -> Option<u16> {
+    match params {
+        [] => Some(0),
+        [modifiers @ 1..=16] => Some(modifiers - 1),
+        _ => None,
+    }
+}
+
+fn with_modifiers(key: Key, modifiers: u16) -> Option<Event> {
+    let shift = modifiers & SHIFT != 0;
+    // Terminals that tell Meta apart from Alt are rare, and cursive doesn't.
+    let alt = modifiers & (ALT | META) != 0;
+    let ctrl = modifiers & CTRL != 0;
+    Some(match (ctrl, alt, shift) {
+        (false, false, false) => Event::Key(key),
+        (false, false, true) => Event::Shift(key),
+        (false, true, false) => Event::Alt(key),
+        (false, true, true) => Event::AltShift(key),
+        (true, false, false) => Event::Ctrl(key),
+        (true, false, true) => Event::CtrlShift(key),
+        (true, true, false) => Event::CtrlAlt(key),
+        (true, true, true) => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_str(raw: &str) -> Option<Event> {
+        decode(raw.as_bytes())
+    }
+
+    #[test]
+    fn decodes_every_xterm_modifier_parameter() {
+        let table: [(u16, Option<Event>); 16] = [
+            (1, Some(Event::Key(Key::Up))),
+            (2, Some(Event::Shift(Key::Up))),
+            (3, Some(Event::Alt(Key::Up))),
+            (4, Some(Event::AltShift(Key::Up))),
+            (5, Some(Event::Ctrl(Key::Up))),
+            (6, Some(Event::CtrlShift(Key::Up))),
+            (7, Some(Event::CtrlAlt(Key::Up))),
+            (8, None),
+            (9, Some(Event::Alt(Key::Up))),
+            (10, Some(Event::AltShift(Key::Up))),
+            (11, Some(Event::Alt(Key::Up))),
+            (12, Some(Event::AltShift(Key::Up))),
+            (13, Some(Event::CtrlAlt(Key::Up))),
+            (14, None),
+            (15, Some(Event::CtrlAlt(Key::Up))),
+            (16, None),
+        ];
+        for (modifiers, expected) in table {
+            let letter = format!("\x1b[1;{}A", modifiers);
+            assert_eq!(decode_str(&letter), expected, "{:?}", letter);
+            let tilde = format!("\x1b[3;{}~", modifiers);
+            let expected = expected.map(|event| match event {
+                Event::Key(_) => Event::Key(Key::Del),
+                Event::Shift(_) => Event::Shift(Key::Del),
+                Event::Alt(_) => Event::Alt(Key::Del),
+                Event::AltShift(_) => Event::AltShift(Key::Del),
+                Event::Ctrl(_) => Event::Ctrl(Key::Del),
+                Event::CtrlShift(_) => Event::CtrlShift(Key::Del),
+                Event::CtrlAlt(_) => Event::CtrlAlt(Key::Del),
+                event => unreachable!("{:?}", event),
+            });
+            assert_eq!(decode_str(&tilde), expected, "{:?}", tilde);
+        }
+        assert_eq!(decode_str("\x1b[1;0A"), None);
+        assert_eq!(decode_str("\x1b[1;17A"), None);
+        assert_eq!(decode_str("\x1b[3;17~"), None);
+    }
+
+    #[test]
+    fn decodes_function_keys() {
+        let table = [
+            ("\x1bOP", Event::Key(Key::F1)),
+            ("\x1bOS", Event::Key(Key::F4)),
+            ("\x1b[1;5P", Event::Ctrl(Key::F1)),
+            ("\x1b[1;2S", Event::Shift(Key::F4)),
+            ("\x1b[11~", Event::Key(Key::F1)),
+            ("\x1b[15~", Event::Key(Key::F5)),
+            ("\x1b[17~", Event::Key(Key::F6)),
+            ("\x1b[21~", Event::Key(Key::F10)),
+            ("\x1b[23;3~", Event::Alt(Key::F11)),
+            ("\x1b[24;2~", Event::Shift(Key::F12)),
+        ];
+        for (raw, expected) in table {
+            assert_eq!(decode_str(raw), Some(expected), "{:?}", raw);
+        }
+        // F13 and up have no cursive key.
+        assert_eq!(decode_str("\x1b[25~"), None);
+        assert_eq!(decode_str("\x1b[16~"), None);
+    }
+
+    #[test]
+    fn decodes_rxvt_sequences() {
+        let table = [
+            ("\x1b[a", Event::Shift(Key::Up)),
+            ("\x1b[b", Event::Shift(Key::Down)),
+            ("\x1b[c", Event::Shift(Key::Right)),
+            ("\x1b[d", Event::Shift(Key::Left)),
+            ("\x1bOa", Event::Ctrl(Key::Up)),
+            ("\x1bOd", Event::Ctrl(Key::Left)),
+            ("\x1b[3^", Event::Ctrl(Key::Del)),
+            ("\x1b[5^", Event::Ctrl(Key::PageUp)),
+            ("\x1b[11^", Event::Ctrl(Key::F1)),
+            ("\x1b[3@", Event::CtrlShift(Key::Del)),
+            ("\x1b[24@", Event::CtrlShift(Key::F12)),
+        ];
+        for (raw, expected) in table {
+            assert_eq!(decode_str(raw), Some(expected), "{:?}", raw);
+        }
+        assert_eq!(decode_str("\x1b[e"), None);
+        assert_eq!(decode_str("\x1b[3;5^"), None);
+    }
+
+    #[test]
+    fn decodes_unmodified_keys() {
+        assert_eq!(decode_str("\x1b[Z"), Some(Event::Shift(Key::Tab)));
+        assert_eq!(decode_str("\x1b[E"), Some(Event::Key(Key::NumpadCenter)));
+        assert_eq!(decode_str("\x1bOH"), Some(Event::Key(Key::Home)));
+        assert_eq!(decode_str("\x1b[7~"), Some(Event::Key(Key::Home)));
+        assert_eq!(decode_str("\x1b[8~"), Some(Event::Key(Key::End)));
+    }
+
+    #[test]
+    fn rejects_malformed_sequences() {
+        for raw in [
+            "",
+            "\x1b",
+            "\x1b[",
+            "a",
+            "\x1bX1~",
+            "\x1b[99999~",
+            "\x1b[1;99999A",
+            "\x1b[-1~",
+            "\x1b[1;;5A",
+            "\x1b[2;5A",
+            "\x1b[1;5;2A",
+            "\x1b[1;5z",
+        ] {
+            assert_eq!(decode_str(raw), None, "{:?}", raw);
+        }
+        assert_eq!(decode(b"\x1b[\xff~"), None);
+    }
+
+    #[test]
+    fn undecodable_input_is_passed_on_unchanged() {
+        for raw in [
+            &b"\x1b[99999~"[..],
+            b"\x1b[25~",
+            b"\x1b[\xff~",
+            b"\x1b]0;title\x07",
+        ] {
+            assert_eq!(
+                decode_or_unknown(raw.to_vec()),
+                Event::Unknown(raw.to_vec())
+            );
+        }
+        assert_eq!(
+            decode_or_unknown(b"\x1b[1;5A".to_vec()),
+            Event::Ctrl(Key::Up)
+        );
+    }
+}
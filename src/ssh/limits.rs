@@ -0,0 +1,396 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use super::plugin::get_plugin;
+
+/// Limits applied to every peer IP before a connection reaches the app, on every frontend but Unix
+/// sockets. Clients behind a reverse proxy all share its address and so its limits. The default
+/// lets every connection through; [`ConnectionLimits::strict`] is a reasonable starting point for
+/// a server exposed to the internet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Maximum number of simultaneously open connections from a single IP.
+    pub max_connections_per_ip: Option<usize>,
+    /// Maximum number of new connections a single IP may open in any one-minute window.
+    pub max_new_connections_per_minute: Option<usize>,
+    /// Number of failed authentication attempts after which an IP is temporarily banned.
+    pub max_auth_failures: Option<usize>,
+    /// How long a ban lasts once `max_auth_failures` has been reached.
+    pub ban_duration: Duration,
+    /// Minimum time a rejected authentication attempt takes to be answered.
+    pub auth_rejection_time: Duration,
+}
+
+impl ConnectionLimits {
+    /// At most 8 open and 30 new connections a minute per IP, a ten minute ban after 10 failed
+    /// authentication attempts, and a second's delay on every rejection.
+    pub fn strict() -> Self {
+        Self {
+            max_connections_per_ip: Some(8),
+            max_new_connections_per_minute: Some(30),
+            max_auth_failures: Some(10),
+            ban_duration: Duration::from_secs(600),
+            auth_rejection_time: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Emitted to [`crate::App::on_ban`] whenever a peer is temporarily banned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BanEvent {
+    /// The banned address.
+    pub ip: IpAddr,
+    /// Number of failed authentication attempts that led to the ban.
+    pub auth_failures: usize,
+    /// How long the ban lasts.
+    pub duration: Duration,
+}
+
+/// Why a connection was refused before reaching the ssh handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    Banned,
+    TooManyConnections,
+    RateLimited,
+}
+
+impl Refusal {
+    /// How the refusal is named in the audit log.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Refusal::Banned => "banned",
+            Refusal::TooManyConnections => "too_many_connections",
+            Refusal::RateLimited => "rate_limited",
+        }
+    }
+}
+
+#[derive(Default)]
+struct PeerState {
+    active: usize,
+    recent: VecDeque<Instant>,
+    auth_failures: usize,
+    last_auth_failure: Option<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl PeerState {
+    fn expire(&mut self, now: Instant, ban_duration: Duration) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= Duration::from_secs(60))
+        {
+            self.recent.pop_front();
+        }
+        // Failures are forgotten once a ban's worth of time has passed without another one.
+        if self
+            .last_auth_failure
+            .is_some_and(|t| now.duration_since(t) >= ban_duration)
+            && self.banned_until.is_none_or(|until| until <= now)
+        {
+            self.auth_failures = 0;
+            self.last_auth_failure = None;
+            self.banned_until = None;
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.active == 0 && self.recent.is_empty() && self.auth_failures == 0
+    }
+}
+
+/// How often peers with nothing left to track are forgotten. Each peer is only brought up to date
+/// when it connects again, so the whole map doesn't have to be walked on every connection.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Peers {
+    by_ip: HashMap<IpAddr, PeerState>,
+    last_pruned: Option<Instant>,
+}
+
+impl Peers {
+    /// Forgets idle peers, unless that was last done less than [`PRUNE_INTERVAL`] ago.
+    fn prune(&mut self, now: Instant, ban_duration: Duration) {
+        if self
+            .last_pruned
+            .is_some_and(|t| now.duration_since(t) < PRUNE_INTERVAL)
+        {
+            return;
+        }
+        self.last_pruned = Some(now);
+        self.by_ip.retain(|_, peer| {
+            peer.expire(now, ban_duration);
+            !peer.is_idle()
+        });
+    }
+}
+
+/// Tracks per-IP connection counts, connection rates and authentication failures.
+#[derive(Clone)]
+pub(crate) struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    peers: Arc<Mutex<Peers>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            peers: Arc::new(Mutex::new(Peers::default())),
+        }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    /// Decides whether a new connection from `ip` may proceed. The returned permit keeps the
+    /// connection counted against the peer until it is dropped.
+    pub fn try_admit(&self, ip: IpAddr) -> Result<ConnectionPermit, Refusal> {
+        self.try_admit_at(ip, Instant::now())
+    }
+
+    fn try_admit_at(&self, ip: IpAddr, now: Instant) -> Result<ConnectionPermit, Refusal> {
+        let mut peers = self.peers.lock().unwrap();
+        peers.prune(now, self.limits.ban_duration);
+        let peer = peers.by_ip.entry(ip).or_default();
+        peer.expire(now, self.limits.ban_duration);
+
+        if let Some(until) = peer.banned_until {
+            if until > now {
+                return Err(Refusal::Banned);
+            }
+            peer.banned_until = None;
+            peer.auth_failures = 0;
+            peer.last_auth_failure = None;
+        }
+        if let Some(max) = self.limits.max_connections_per_ip {
+            if peer.active >= max {
+                return Err(Refusal::TooManyConnections);
+            }
+        }
+        if let Some(max) = self.limits.max_new_connections_per_minute {
+            if peer.recent.len() >= max {
+                return Err(Refusal::RateLimited);
+            }
+        }
+
+        peer.active += 1;
+        peer.recent.push_back(now);
+        Ok(ConnectionPermit {
+            ip,
+            peers: self.peers.clone(),
+        })
+    }
+
+    /// Returns true if `ip` is currently banned.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .by_ip
+            .get(&ip)
+            .and_then(|peer| peer.banned_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// Records a failed authentication attempt, banning the peer if it has run out of attempts.
+    /// Returns true if this failure caused a ban.
+    pub fn record_auth_failure(&self, ip: IpAddr) -> bool {
+        self.record_auth_failure_at(ip, Instant::now())
+    }
+
+    fn record_auth_failure_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let ban = {
+            let mut peers = self.peers.lock().unwrap();
+            let peer = peers.by_ip.entry(ip).or_default();
+            peer.auth_failures += 1;
+            peer.last_auth_failure = Some(now);
+            match self.limits.max_auth_failures {
+                Some(max) if peer.auth_failures >= max && peer.banned_until.is_none() => {
+                    peer.banned_until = Some(now + self.limits.ban_duration);
+                    Some(BanEvent {
+                        ip,
+                        auth_failures: peer.auth_failures,
+                        duration: self.limits.ban_duration,
+                    })
+                }
+                _ => None,
+            }
+        };
+        match ban {
+            Some(ban) => {
+                info!(
+                    "Banning {} for {:?} after {} failed authentication attempts",
+                    ban.ip, ban.duration, ban.auth_failures
+                );
+                if let Some(plugin) = get_plugin() {
+                    plugin.on_ban(&ban);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Keeps a connection counted against its peer's concurrent connection limit.
+pub(crate) struct ConnectionPermit {
+    ip: IpAddr,
+    peers: Arc<Mutex<Peers>>,
+}
+
+impl ConnectionPermit {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Ok(mut peers) = self.peers.lock() {
+            if let Some(peer) = peers.by_ip.get_mut(&self.ip) {
+                peer.active = peer.active.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    fn limiter(limits: ConnectionLimits) -> ConnectionLimiter {
+        ConnectionLimiter::new(limits)
+    }
+
+    #[test]
+    fn default_admits_everything() {
+        let limiter = limiter(ConnectionLimits::default());
+        let now = Instant::now();
+        let permits: Vec<_> = (0..1000)
+            .map(|_| limiter.try_admit_at(PEER, now).unwrap())
+            .collect();
+        for _ in 0..1000 {
+            assert!(!limiter.record_auth_failure_at(PEER, now));
+        }
+        assert!(limiter.try_admit_at(PEER, now).is_ok());
+        drop(permits);
+    }
+
+    #[test]
+    fn concurrent_connections_are_released_on_drop() {
+        let limiter = limiter(ConnectionLimits {
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let first = limiter.try_admit_at(PEER, now).unwrap();
+        let _second = limiter.try_admit_at(PEER, now).unwrap();
+        assert_eq!(
+            limiter.try_admit_at(PEER, now).err(),
+            Some(Refusal::TooManyConnections)
+        );
+        assert!(limiter.try_admit_at(OTHER, now).is_ok());
+        drop(first);
+        assert!(limiter.try_admit_at(PEER, now).is_ok());
+    }
+
+    #[test]
+    fn rate_window_slides_after_a_minute() {
+        let limiter = limiter(ConnectionLimits {
+            max_new_connections_per_minute: Some(3),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        for seconds in 0..3 {
+            drop(
+                limiter
+                    .try_admit_at(PEER, start + Duration::from_secs(seconds * 10))
+                    .unwrap(),
+            );
+        }
+        let almost = start + Duration::from_secs(59);
+        assert_eq!(
+            limiter.try_admit_at(PEER, almost).err(),
+            Some(Refusal::RateLimited)
+        );
+        // The first connection has left the window, the other two haven't.
+        let later = start + Duration::from_secs(60);
+        drop(limiter.try_admit_at(PEER, later).unwrap());
+        assert_eq!(
+            limiter.try_admit_at(PEER, later).err(),
+            Some(Refusal::RateLimited)
+        );
+    }
+
+    #[test]
+    fn ban_expires_after_its_duration() {
+        let limiter = limiter(ConnectionLimits {
+            max_auth_failures: Some(3),
+            ban_duration: Duration::from_secs(600),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert!(!limiter.record_auth_failure_at(PEER, start));
+        assert!(!limiter.record_auth_failure_at(PEER, start));
+        assert!(limiter.record_auth_failure_at(PEER, start));
+        // Further failures while banned don't start another ban.
+        assert!(!limiter.record_auth_failure_at(PEER, start));
+
+        let during = start + Duration::from_secs(599);
+        assert_eq!(
+            limiter.try_admit_at(PEER, during).err(),
+            Some(Refusal::Banned)
+        );
+        assert!(limiter.try_admit_at(OTHER, during).is_ok());
+
+        let after = start + Duration::from_secs(600);
+        drop(limiter.try_admit_at(PEER, after).unwrap());
+        // The failures were forgotten along with the ban.
+        assert!(!limiter.record_auth_failure_at(PEER, after));
+        assert!(!limiter.record_auth_failure_at(PEER, after));
+        assert!(limiter.record_auth_failure_at(PEER, after));
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_a_quiet_period() {
+        let limiter = limiter(ConnectionLimits {
+            max_auth_failures: Some(2),
+            ban_duration: Duration::from_secs(600),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert!(!limiter.record_auth_failure_at(PEER, start));
+        let later = start + Duration::from_secs(600);
+        drop(limiter.try_admit_at(PEER, later).unwrap());
+        assert!(!limiter.record_auth_failure_at(PEER, later));
+    }
+
+    #[test]
+    fn idle_peers_are_forgotten_at_most_once_a_minute() {
+        let limiter = limiter(ConnectionLimits::default());
+        let known = |limiter: &ConnectionLimiter| limiter.peers.lock().unwrap().by_ip.len();
+        let start = Instant::now();
+        drop(limiter.try_admit_at(PEER, start).unwrap());
+
+        // PEER has gone quiet, but it's too soon after the last sweep to look.
+        let later = start + Duration::from_secs(59);
+        drop(limiter.try_admit_at(OTHER, later).unwrap());
+        assert_eq!(known(&limiter), 2);
+
+        let much_later = start + Duration::from_secs(120);
+        drop(limiter.try_admit_at(OTHER, much_later).unwrap());
+        assert_eq!(known(&limiter), 1);
+    }
+}
@@ -0,0 +1,589 @@
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
+
+/// The first descriptor systemd passes to a socket-activated service.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// How long an accept loop pauses after running out of file descriptors, which only frees up once
+/// connections close. Retrying right away would spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Where the server accepts connections.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    /// A TCP address. IPv6 addresses only accept IPv6 connections, so the same port can be bound
+    /// separately for IPv4.
+    Tcp(SocketAddr),
+    /// Every IPv6 and IPv4 address at this port, through one IPv6 socket that also accepts IPv4
+    /// connections. Hosts without IPv6 get every IPv4 address instead.
+    DualStack(u16),
+    /// A Unix domain socket at this path, replacing a stale socket left there by a server that's no
+    /// longer running. Anyone who can connect to the socket gets in, so access is controlled by the
+    /// permissions of the socket and its directory.
+    Unix(PathBuf),
+    /// A TCP or Unix socket that is already bound and listening, such as one inherited from a
+    /// parent process.
+    Fd(ListenFd),
+}
+
+/// A descriptor for a bound, listening socket, owned by the [`ListenAddr`]s made from it. Clones
+/// share the descriptor, which is closed once the last of them is dropped. The server listens on a
+/// duplicate, so binding the same `ListenAddr` again works.
+#[derive(Clone, Debug)]
+pub struct ListenFd(Arc<OwnedFd>);
+
+impl From<OwnedFd> for ListenFd {
+    fn from(fd: OwnedFd) -> Self {
+        ListenFd(Arc::new(fd))
+    }
+}
+
+impl FromRawFd for ListenFd {
+    /// Takes ownership of `fd`, which must be open and not owned by anything else, since it's
+    /// closed when the last clone is dropped.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        OwnedFd::from_raw_fd(fd).into()
+    }
+}
+
+impl AsFd for ListenFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl AsRawFd for ListenFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl PartialEq for ListenFd {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_raw_fd() == other.as_raw_fd()
+    }
+}
+
+impl Eq for ListenFd {}
+
+impl Hash for ListenFd {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_raw_fd().hash(state);
+    }
+}
+
+lazy_static! {
+    /// Taken from the environment once, so each descriptor has a single owner however often the
+    /// sockets are looked up.
+    static ref SYSTEMD_FDS: Vec<(ListenFd, Option<String>)> = systemd_fds();
+}
+
+impl ListenAddr {
+    /// Sockets passed to this process by systemd socket activation, in the order of the socket
+    /// unit's `ListenStream=` lines. Empty if the process wasn't socket-activated.
+    pub fn systemd() -> Vec<ListenAddr> {
+        SYSTEMD_FDS
+            .iter()
+            .map(|(fd, _)| ListenAddr::Fd(fd.clone()))
+            .collect()
+    }
+
+    /// The sockets passed by systemd socket activation that the socket unit named `name` with
+    /// `FileDescriptorName=`, which applies to every `ListenStream=` line in the unit.
+    pub fn systemd_named(name: &str) -> Vec<ListenAddr> {
+        SYSTEMD_FDS
+            .iter()
+            .filter(|(_, fd_name)| fd_name.as_deref() == Some(name))
+            .map(|(fd, _)| ListenAddr::Fd(fd.clone()))
+            .collect()
+    }
+}
+
+/// The variables systemd describes the sockets it passes with.
+const SYSTEMD_VARS: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+/// Takes the descriptors systemd passed to this process. The variables describing them are
+/// removed and the descriptors closed on exec, so child processes don't take them for their own.
+fn systemd_fds() -> Vec<(ListenFd, Option<String>)> {
+    let [pid, count, names] = SYSTEMD_VARS.map(|var| std::env::var(var).ok());
+    for var in SYSTEMD_VARS {
+        std::env::remove_var(var);
+    }
+    let fds = parse_systemd_fds(
+        pid.as_deref(),
+        count.as_deref(),
+        names.as_deref(),
+        std::process::id(),
+    );
+    debug!("Found {} sockets from systemd", fds.len());
+    fds.into_iter()
+        .map(|(fd, name)| {
+            if let Err(err) = set_cloexec(fd) {
+                warn!(
+                    "Failed to mark systemd socket {} close-on-exec: {}",
+                    fd, err
+                );
+            }
+            // SAFETY: systemd hands these descriptors to this process, which takes them only once.
+            (unsafe { ListenFd::from_raw_fd(fd) }, name)
+        })
+        .collect()
+}
+
+/// The descriptors and names described by `LISTEN_FDS` and `LISTEN_FDNAMES`, provided `LISTEN_PID`
+/// says they're meant for the process `our_pid` rather than inherited from a parent.
+fn parse_systemd_fds(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&str>,
+    our_pid: u32,
+) -> Vec<(RawFd, Option<String>)> {
+    let for_us = pid
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == our_pid);
+    let count = match count.and_then(|count| count.parse::<RawFd>().ok()) {
+        Some(count) if for_us => count,
+        _ => return Vec::new(),
+    };
+    let mut names = names.into_iter().flat_map(|names| names.split(':'));
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(count))
+        .map(|fd| (fd, names.next().map(str::to_string)))
+        .collect()
+}
+
+/// Keeps `fd` from being inherited by processes this one executes.
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: F_GETFD and F_SETFD only read and write the descriptor's flags.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+/// Hands a listener bound by the caller, for example before dropping privileges, to the server.
+/// It's closed once the `ListenAddr` and the server are done with it.
+impl From<std::net::TcpListener> for ListenAddr {
+    fn from(listener: std::net::TcpListener) -> Self {
+        ListenAddr::Fd(OwnedFd::from(listener).into())
+    }
+}
+
+/// Hands a listener bound by the caller to the server. It's closed once the `ListenAddr` and the
+/// server are done with it.
+impl From<std::os::unix::net::UnixListener> for ListenAddr {
+    fn from(listener: std::os::unix::net::UnixListener) -> Self {
+        ListenAddr::Fd(OwnedFd::from(listener).into())
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::DualStack(port) => write!(f, "[::]:{} (dual-stack)", port),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Fd(fd) => write!(f, "fd:{}", fd.as_raw_fd()),
+        }
+    }
+}
+
+/// Binds a TCP listener, deciding explicitly whether an IPv6 one also accepts IPv4 connections
+/// rather than leaving it to the system's default.
+fn bind_tcp(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            let v6_only = v6_only as libc::c_int;
+            // SAFETY: the option value is a live `c_int` and the length says so.
+            let result = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_V6ONLY,
+                    &v6_only as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            socket
+        }
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Binds `::` at `port` through `bind`, also accepting IPv4 connections, or every IPv4 address if
+/// the host has no IPv6.
+fn bind_dual_stack(
+    port: u16,
+    bind: impl Fn(SocketAddr, bool) -> io::Result<TcpListener>,
+) -> io::Result<TcpListener> {
+    match bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), false) {
+        Err(err)
+            if matches!(
+                err.raw_os_error(),
+                Some(libc::EAFNOSUPPORT | libc::EADDRNOTAVAIL)
+            ) =>
+        {
+            debug!("IPv6 is unavailable, listening on IPv4 only: {}", err);
+            bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), true)
+        }
+        bound => bound,
+    }
+}
+
+/// Whether `path` is a socket nobody is listening on any more. A socket that still accepts
+/// connections belongs to a running server, so binding over it fails instead.
+fn is_stale_socket(path: &Path) -> bool {
+    let is_socket =
+        std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+    is_socket
+        && std::os::unix::net::UnixStream::connect(path)
+            .is_err_and(|err| err.kind() == io::ErrorKind::ConnectionRefused)
+}
+
+/// A bound listening socket of any supported kind.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(bind_tcp(*addr, true)?)),
+            ListenAddr::DualStack(port) => Ok(Listener::Tcp(bind_dual_stack(*port, bind_tcp)?)),
+            ListenAddr::Unix(path) => {
+                if is_stale_socket(path) {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            ListenAddr::Fd(fd) => Self::from_fd(fd.as_fd()),
+        }
+    }
+
+    fn from_fd(fd: BorrowedFd) -> io::Result<Self> {
+        let fd = fd.try_clone_to_owned()?;
+        let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        // SAFETY: `addr` is large enough for any socket address and `len` says so.
+        let result = unsafe {
+            libc::getsockname(
+                fd.as_raw_fd(),
+                &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                &mut len,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        match addr.ss_family as libc::c_int {
+            libc::AF_UNIX => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Unix(UnixListener::from_std(listener)?))
+            }
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't listen on a socket of address family {}", family),
+            )),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Peer::Tcp(addr)))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), Peer::Unix))
+            }
+        }
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "a TCP socket"),
+            },
+            Listener::Unix(listener) => match listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.to_path_buf()))
+            {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "an unnamed Unix socket"),
+            },
+        }
+    }
+}
+
+/// The other end of an accepted connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Peer {
+    Tcp(SocketAddr),
+    /// Unix socket peers are local, and their addresses are rarely more than an empty path.
+    Unix,
+}
+
+impl Peer {
+    /// The peer's IP address, which per-IP limits are keyed by.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix => None,
+        }
+    }
+
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(*addr),
+            Peer::Unix => None,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix => write!(f, "a Unix socket peer"),
+        }
+    }
+}
+
+/// An accepted connection of any supported kind.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Called by the accept loops when accepting fails, to wait a moment if the process or the system
+/// is out of file descriptors. Other errors concern a single connection and are retried at once.
+pub(crate) async fn back_off_after(err: &io::Error) {
+    if matches!(err.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+        warn!("Out of file descriptors, pausing accepts: {}", err);
+        tokio::time::sleep(ACCEPT_BACKOFF).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_socket(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ssh_ui-listener-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn replaces_only_stale_unix_sockets() {
+        let path = temp_socket("live.sock");
+        let addr = ListenAddr::Unix(path.clone());
+        let live = Listener::bind(&addr).await.unwrap();
+        let err = Listener::bind(&addr).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+
+        // Dropping the listener leaves its socket file behind.
+        drop(live);
+        assert!(path.exists());
+        let again = Listener::bind(&addr).await.unwrap();
+        UnixStream::connect(&path).await.unwrap();
+        drop(again);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaves_other_files_alone() {
+        let path = temp_socket("not-a-socket");
+        std::fs::write(&path, "data").unwrap();
+        assert!(Listener::bind(&ListenAddr::Unix(path.clone()))
+            .await
+            .is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn listens_on_a_duplicate_of_the_descriptor() {
+        let std_listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let local = std_listener.local_addr().unwrap();
+        let addr = ListenAddr::from(std_listener);
+        let first = Listener::bind(&addr).await.unwrap();
+        drop(first);
+        let second = Listener::bind(&addr).await.unwrap();
+        let connect = TcpStream::connect(local);
+        let (accepted, _) = tokio::join!(second.accept(), connect);
+        assert!(matches!(accepted.unwrap().1, Peer::Tcp(_)));
+    }
+
+    #[tokio::test]
+    async fn accepts_only_back_off_when_out_of_file_descriptors() {
+        let start = std::time::Instant::now();
+        back_off_after(&io::Error::from(io::ErrorKind::ConnectionAborted)).await;
+        assert!(start.elapsed() < ACCEPT_BACKOFF);
+        back_off_after(&io::Error::from_raw_os_error(libc::EMFILE)).await;
+        assert!(start.elapsed() >= ACCEPT_BACKOFF);
+    }
+
+    #[test]
+    fn reads_the_sockets_systemd_passed_to_this_process() {
+        assert_eq!(
+            parse_systemd_fds(Some("42"), Some("3"), Some("ssh:ssh:admin"), 42),
+            [
+                (3, Some("ssh".to_string())),
+                (4, Some("ssh".to_string())),
+                (5, Some("admin".to_string()))
+            ]
+        );
+        assert_eq!(
+            parse_systemd_fds(Some("42"), Some("2"), None, 42),
+            [(3, None), (4, None)]
+        );
+    }
+
+    #[test]
+    fn ignores_sockets_meant_for_another_process() {
+        assert_eq!(parse_systemd_fds(Some("41"), Some("1"), None, 42), []);
+        assert_eq!(parse_systemd_fds(None, Some("1"), None, 42), []);
+        assert_eq!(parse_systemd_fds(Some("42"), None, None, 42), []);
+        assert_eq!(parse_systemd_fds(Some("42"), Some("many"), None, 42), []);
+        assert_eq!(parse_systemd_fds(Some("42"), Some("-1"), None, 42), []);
+    }
+
+    #[test]
+    fn marks_descriptors_close_on_exec() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let fd = OwnedFd::from(listener);
+        // SAFETY: clears the flags of a descriptor this test owns.
+        assert_eq!(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, 0) }, 0);
+        set_cloexec(fd.as_raw_fd()).unwrap();
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+    }
+
+    #[tokio::test]
+    async fn dual_stack_accepts_ipv4_and_ipv6() {
+        let listener = Listener::bind(&ListenAddr::DualStack(0)).await.unwrap();
+        let Listener::Tcp(tcp) = &listener else {
+            panic!("dual-stack listeners are TCP");
+        };
+        let local = tcp.local_addr().unwrap();
+        assert_eq!(local.ip(), Ipv6Addr::UNSPECIFIED);
+        for ip in [
+            IpAddr::from(Ipv4Addr::LOCALHOST),
+            IpAddr::from(Ipv6Addr::LOCALHOST),
+        ] {
+            let connect = TcpStream::connect((ip, local.port()));
+            let (accepted, connected) = tokio::join!(listener.accept(), connect);
+            connected.unwrap();
+            assert!(matches!(accepted.unwrap().1, Peer::Tcp(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn dual_stack_falls_back_to_ipv4_without_ipv6() {
+        for errno in [libc::EAFNOSUPPORT, libc::EADDRNOTAVAIL] {
+            let no_ipv6 = |addr: SocketAddr, v6_only| match addr {
+                SocketAddr::V6(_) => Err(io::Error::from_raw_os_error(errno)),
+                SocketAddr::V4(_) => bind_tcp(addr, v6_only),
+            };
+            let listener = bind_dual_stack(0, no_ipv6).unwrap();
+            assert_eq!(listener.local_addr().unwrap().ip(), Ipv4Addr::UNSPECIFIED);
+        }
+    }
+
+    #[tokio::test]
+    async fn dual_stack_only_falls_back_when_ipv6_is_missing() {
+        let tried = std::sync::Mutex::new(Vec::new());
+        let denied = |addr: SocketAddr, _| {
+            tried.lock().unwrap().push(addr);
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        };
+        let err = bind_dual_stack(22, denied).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        assert_eq!(
+            *tried.lock().unwrap(),
+            [SocketAddr::from((Ipv6Addr::UNSPECIFIED, 22))]
+        );
+    }
+}
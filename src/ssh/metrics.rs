@@ -0,0 +1,453 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+use super::listener::back_off_after;
+
+/// How long a scrape may take, from connecting to reading the last byte of the response, before the
+/// connection is dropped.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Receives the server's measurements. Names follow Prometheus conventions, and every label value
+/// comes from a small fixed set.
+pub trait MetricsRecorder: Send + Sync {
+    /// Adds `value` to a counter.
+    fn counter(&self, name: &'static str, labels: &[(&'static str, &'static str)], value: u64);
+    /// Sets a gauge to `value`.
+    fn gauge(&self, name: &'static str, labels: &[(&'static str, &'static str)], value: f64);
+    /// Records one observation, such as the duration of a session that just ended.
+    fn histogram(&self, name: &'static str, labels: &[(&'static str, &'static str)], value: f64);
+}
+
+/// Where the server reports its metrics.
+#[derive(Clone)]
+pub enum MetricsConfig {
+    /// Serves the metrics in Prometheus' text exposition format at `/metrics` on this address.
+    /// There's no authentication, so this should be a loopback or otherwise private address.
+    Endpoint(SocketAddr),
+    /// Hands every measurement to the given recorder.
+    Recorder(Arc<dyn MetricsRecorder>),
+}
+
+impl MetricsConfig {
+    /// Serves the metrics on `127.0.0.1:port`.
+    pub fn loopback(port: u16) -> Self {
+        Self::Endpoint(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+    }
+}
+
+impl Debug for MetricsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Endpoint(addr) => f.debug_tuple("Endpoint").field(addr).finish(),
+            Self::Recorder(_) => f.debug_tuple("Recorder").finish(),
+        }
+    }
+}
+
+/// Keeps the latest value of every metric and renders them in Prometheus' text exposition format.
+/// Histograms are exposed as summaries without quantiles, that is as a sum and a count.
+#[derive(Default)]
+pub struct PrometheusRecorder {
+    /// Written only the first time a series is seen. Counters and gauges are atomics after that,
+    /// so recording them on the hot paths just takes the read lock.
+    families: RwLock<BTreeMap<&'static str, Family>>,
+}
+
+type Labels = Vec<(&'static str, &'static str)>;
+
+struct Family {
+    kind: &'static str,
+    series: BTreeMap<Labels, Series>,
+}
+
+enum Series {
+    Counter(AtomicU64),
+    /// The bits of an `f64`.
+    Gauge(AtomicU64),
+    Summary(Mutex<(f64, u64)>),
+}
+
+impl PrometheusRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renders every metric recorded so far.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for (name, family) in self.families.read().unwrap().iter() {
+            let _ = writeln!(text, "# TYPE {} {}", name, family.kind);
+            for (labels, series) in &family.series {
+                let labels = render_labels(labels);
+                let _ = match series {
+                    Series::Counter(value) => {
+                        writeln!(text, "{}{} {}", name, labels, value.load(Ordering::Relaxed))
+                    }
+                    Series::Gauge(bits) => {
+                        let value = f64::from_bits(bits.load(Ordering::Relaxed));
+                        writeln!(text, "{}{} {}", name, labels, value)
+                    }
+                    Series::Summary(summary) => {
+                        let (sum, count) = *summary.lock().unwrap();
+                        writeln!(
+                            text,
+                            "{}_sum{} {}\n{}_count{} {}",
+                            name, labels, sum, name, labels, count
+                        )
+                    }
+                };
+            }
+        }
+        text
+    }
+
+    /// Hands the series to `update`, creating it with `new` the first time it's seen.
+    fn update(
+        &self,
+        name: &'static str,
+        kind: &'static str,
+        labels: &[(&'static str, &'static str)],
+        new: impl FnOnce() -> Series,
+        update: impl FnOnce(&Series),
+    ) {
+        {
+            let families = self.families.read().unwrap();
+            if let Some(series) = families
+                .get(name)
+                .and_then(|family| family.series.get(labels))
+            {
+                update(series);
+                return;
+            }
+        }
+        let mut families = self.families.write().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            kind,
+            series: BTreeMap::new(),
+        });
+        update(family.series.entry(labels.to_vec()).or_insert_with(new));
+    }
+}
+
+fn render_labels(labels: &[(&'static str, &'static str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, value))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+impl MetricsRecorder for PrometheusRecorder {
+    fn counter(&self, name: &'static str, labels: &[(&'static str, &'static str)], value: u64) {
+        let new = || Series::Counter(AtomicU64::new(0));
+        self.update(name, "counter", labels, new, |series| {
+            if let Series::Counter(current) = series {
+                current.fetch_add(value, Ordering::Relaxed);
+            }
+        });
+    }
+
+    fn gauge(&self, name: &'static str, labels: &[(&'static str, &'static str)], value: f64) {
+        let new = || Series::Gauge(AtomicU64::new(0));
+        self.update(name, "gauge", labels, new, |series| {
+            if let Series::Gauge(current) = series {
+                current.store(value.to_bits(), Ordering::Relaxed);
+            }
+        });
+    }
+
+    fn histogram(&self, name: &'static str, labels: &[(&'static str, &'static str)], value: f64) {
+        let new = || Series::Summary(Mutex::new((0.0, 0)));
+        self.update(name, "summary", labels, new, |series| {
+            if let Series::Summary(summary) = series {
+                let mut summary = summary.lock().unwrap();
+                summary.0 += value;
+                summary.1 += 1;
+            }
+        });
+    }
+}
+
+lazy_static! {
+    static ref RECORDER: RwLock<Option<Arc<dyn MetricsRecorder>>> = RwLock::new(None);
+}
+
+static ACTIVE_SESSIONS: AtomicI64 = AtomicI64::new(0);
+static SESSIONS_STARTED: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS_ACCEPTED: AtomicU64 = AtomicU64::new(0);
+static AUTH_FAILURES: AtomicU64 = AtomicU64::new(0);
+static BYTES_IN: AtomicU64 = AtomicU64::new(0);
+static BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+
+/// Running totals kept whether or not a recorder is set, for the admin console.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Totals {
+    pub active_sessions: i64,
+    pub sessions_started: u64,
+    pub connections_accepted: u64,
+    pub auth_failures: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+pub(crate) fn totals() -> Totals {
+    Totals {
+        active_sessions: ACTIVE_SESSIONS.load(Ordering::Relaxed),
+        sessions_started: SESSIONS_STARTED.load(Ordering::Relaxed),
+        connections_accepted: CONNECTIONS_ACCEPTED.load(Ordering::Relaxed),
+        auth_failures: AUTH_FAILURES.load(Ordering::Relaxed),
+        bytes_in: BYTES_IN.load(Ordering::Relaxed),
+        bytes_out: BYTES_OUT.load(Ordering::Relaxed),
+    }
+}
+
+pub(crate) fn set_recorder(recorder: Option<Arc<dyn MetricsRecorder>>) {
+    *RECORDER.write().unwrap() = recorder;
+}
+
+fn recorder() -> Option<Arc<dyn MetricsRecorder>> {
+    RECORDER.read().unwrap().clone()
+}
+
+/// Counts a connection that made it past the connection limits. `frontend` is `ssh`, `telnet`,
+/// `raw` or `websocket`.
+pub(crate) fn connection_accepted(frontend: &'static str) {
+    CONNECTIONS_ACCEPTED.fetch_add(1, Ordering::Relaxed);
+    if let Some(recorder) = recorder() {
+        recorder.counter(
+            "ssh_ui_connections_accepted_total",
+            &[("frontend", frontend)],
+            1,
+        );
+    }
+}
+
+/// Counts a public key the server would accept, before the client has proven it holds the key.
+pub(crate) fn key_offered() {
+    if let Some(recorder) = recorder() {
+        recorder.counter(
+            "ssh_ui_auth_attempts_total",
+            &[("method", "publickey"), ("result", "offered")],
+            1,
+        );
+    }
+}
+
+pub(crate) fn auth_attempt(method: &'static str, accepted: bool) {
+    if !accepted {
+        AUTH_FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(recorder) = recorder() {
+        let result = if accepted { "accepted" } else { "rejected" };
+        recorder.counter(
+            "ssh_ui_auth_attempts_total",
+            &[("method", method), ("result", result)],
+            1,
+        );
+    }
+}
+
+/// Counts a frame drawn by a session, along with how many writes were still waiting to be sent to
+/// the client at the time.
+pub(crate) fn frame_rendered(output_backlog: usize) {
+    if let Some(recorder) = recorder() {
+        recorder.counter("ssh_ui_frames_total", &[], 1);
+        recorder.histogram(
+            "ssh_ui_channel_backlog",
+            &[("channel", "output")],
+            output_backlog as f64,
+        );
+    }
+}
+
+/// Samples how many updates from the client were waiting for a session.
+pub(crate) fn input_backlog(backlog: usize) {
+    if let Some(recorder) = recorder() {
+        recorder.histogram(
+            "ssh_ui_channel_backlog",
+            &[("channel", "input")],
+            backlog as f64,
+        );
+    }
+}
+
+/// Measures one session from its creation until it's dropped.
+pub(crate) struct SessionMetrics {
+    started: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl SessionMetrics {
+    pub fn new() -> Self {
+        let active = ACTIVE_SESSIONS.fetch_add(1, Ordering::Relaxed) + 1;
+        SESSIONS_STARTED.fetch_add(1, Ordering::Relaxed);
+        if let Some(recorder) = recorder() {
+            recorder.counter("ssh_ui_sessions_started_total", &[], 1);
+            recorder.gauge("ssh_ui_active_sessions", &[], active as f64);
+        }
+        Self {
+            started: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts bytes received from the client.
+    pub fn input(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        BYTES_IN.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(recorder) = recorder() {
+            recorder.counter("ssh_ui_bytes_total", &[("direction", "in")], bytes as u64);
+        }
+    }
+
+    /// Counts bytes sent to the client.
+    pub fn output(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        BYTES_OUT.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(recorder) = recorder() {
+            recorder.counter("ssh_ui_bytes_total", &[("direction", "out")], bytes as u64);
+        }
+    }
+}
+
+impl Drop for SessionMetrics {
+    fn drop(&mut self) {
+        let active = ACTIVE_SESSIONS.fetch_sub(1, Ordering::Relaxed) - 1;
+        if let Some(recorder) = recorder() {
+            recorder.gauge("ssh_ui_active_sessions", &[], active as f64);
+            recorder.histogram(
+                "ssh_ui_session_duration_seconds",
+                &[],
+                self.started.elapsed().as_secs_f64(),
+            );
+            recorder.histogram(
+                "ssh_ui_session_bytes",
+                &[("direction", "in")],
+                self.bytes_in.load(Ordering::Relaxed) as f64,
+            );
+            recorder.histogram(
+                "ssh_ui_session_bytes",
+                &[("direction", "out")],
+                self.bytes_out.load(Ordering::Relaxed) as f64,
+            );
+        }
+    }
+}
+
+/// Answers every HTTP request for `/metrics` with the recorder's current metrics.
+pub(crate) async fn serve(listener: TcpListener, recorder: Arc<PrometheusRecorder>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving metrics on http://{}/metrics", addr);
+    }
+    // Scrapes belong to the endpoint's task, so they're dropped along with it.
+    let mut scrapes = JoinSet::new();
+    loop {
+        let (socket, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!("Failed to accept metrics connection: {}", err);
+                back_off_after(&err).await;
+                continue;
+            }
+        };
+        // Forget the scrapes that have ended.
+        while scrapes.try_join_next().is_some() {}
+        let recorder = recorder.clone();
+        scrapes.spawn(async move {
+            match tokio::time::timeout(SCRAPE_TIMEOUT, respond(socket, &recorder)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => debug!("Metrics request from {} failed: {}", peer_addr, err),
+                Err(_) => debug!("Metrics request from {} timed out", peer_addr),
+            }
+        });
+    }
+}
+
+async fn respond(mut socket: TcpStream, recorder: &PrometheusRecorder) -> std::io::Result<()> {
+    // Only the request line matters, and it has to fit in the first read.
+    let mut buf = [0u8; 1024];
+    let read = socket.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", recorder.render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::spawn;
+
+    use super::*;
+
+    #[test]
+    fn renders_the_text_exposition_format() {
+        let recorder = PrometheusRecorder::new();
+        assert_eq!(recorder.render(), "");
+        recorder.counter("requests_total", &[("direction", "in")], 2);
+        recorder.counter("requests_total", &[("direction", "in")], 3);
+        recorder.counter("requests_total", &[("direction", "out"), ("kind", "a")], 1);
+        recorder.gauge("active", &[], 4.0);
+        recorder.gauge("active", &[], 1.5);
+        recorder.histogram("duration_seconds", &[], 0.5);
+        recorder.histogram("duration_seconds", &[], 2.0);
+        assert_eq!(
+            recorder.render(),
+            "# TYPE active gauge\n\
+             active 1.5\n\
+             # TYPE duration_seconds summary\n\
+             duration_seconds_sum 2.5\n\
+             duration_seconds_count 2\n\
+             # TYPE requests_total counter\n\
+             requests_total{direction=\"in\"} 5\n\
+             requests_total{direction=\"out\",kind=\"a\"} 1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_over_http() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let recorder = Arc::new(PrometheusRecorder::new());
+        recorder.counter("scrapes_total", &[], 1);
+        let server = spawn(serve(listener, recorder));
+
+        let get = |path: &'static str| async move {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            socket.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            socket.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let metrics = get("/metrics").await;
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"), "{}", metrics);
+        assert!(metrics.ends_with("\r\n\r\n# TYPE scrapes_total counter\nscrapes_total 1\n"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        server.abort();
+    }
+}
@@ -1,5 +1,24 @@
+pub(crate) mod audit;
 pub(crate) mod backend;
+pub(crate) mod banner;
+pub(crate) mod client;
+pub(crate) mod console;
 pub(crate) mod handler;
+pub(crate) mod incident;
+pub(crate) mod keys;
+pub(crate) mod limits;
+pub(crate) mod listener;
+pub(crate) mod metrics;
+pub(crate) mod persistence;
 pub(crate) mod plugin;
+pub(crate) mod raw;
+pub(crate) mod recording;
 pub(crate) mod server;
 pub(crate) mod session_manager;
+pub(crate) mod spans;
+pub(crate) mod spectator;
+pub(crate) mod telnet;
+pub(crate) mod timeouts;
+pub(crate) mod waiting_room;
+#[cfg(feature = "websocket")]
+pub(crate) mod websocket;
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::SessionHandle;
+
+use super::client::ClientOutput;
+use super::session_manager::SessionChannel;
+
+/// Who a session belongs to, used to match a reconnecting client with its detached session: the
+/// fingerprint of the key the client authenticated with.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Identity(String);
+
+impl Identity {
+    /// The identity of the client on `channel`. Only clients that proved they hold a key have
+    /// one, by signing with it rather than just asking whether it would be accepted; a username
+    /// alone is shared by everyone logging in anonymously, and frontends other
+    /// than ssh don't authenticate their users at all.
+    pub fn of(channel: &SessionChannel) -> Option<Self> {
+        if !matches!(channel.output, ClientOutput::Ssh(..)) {
+            return None;
+        }
+        channel.key.as_ref().map(|key| Identity(key.fingerprint()))
+    }
+}
+
+type Parked = (SessionHandle, oneshot::Sender<SessionChannel>);
+
+/// Sessions whose client has gone away but which are being kept alive for a grace period.
+#[derive(Clone, Default)]
+pub(crate) struct DetachedSessions(Arc<Mutex<HashMap<Identity, Parked>>>);
+
+impl DetachedSessions {
+    /// Parks a session under `identity`. The receiver yields the channel of the next client with
+    /// the same identity. Parking replaces any other session detached under the same identity.
+    pub fn park(
+        &self,
+        identity: Identity,
+        handle_id: SessionHandle,
+    ) -> oneshot::Receiver<SessionChannel> {
+        let (sender, receiver) = oneshot::channel();
+        self.0.lock().unwrap().insert(identity, (handle_id, sender));
+        receiver
+    }
+
+    /// Forgets a parked session, unless another one has since been parked under its identity.
+    pub fn unpark(&self, identity: &Identity, handle_id: SessionHandle) {
+        let mut detached = self.0.lock().unwrap();
+        if detached.get(identity).is_some_and(|(h, _)| *h == handle_id) {
+            detached.remove(identity);
+        }
+    }
+
+    /// Hands the channel to a session detached under the same identity. The channel is given
+    /// back if no such session is waiting for it.
+    pub fn reattach(&self, channel: SessionChannel) -> Option<SessionChannel> {
+        let identity = match Identity::of(&channel) {
+            Some(identity) => identity,
+            None => return Some(channel),
+        };
+        let parked = self.0.lock().unwrap().remove(&identity);
+        match parked {
+            Some((_, sender)) => sender.send(channel).err(),
+            None => Some(channel),
+        }
+    }
+}
+
+/// The client a session is currently drawing to, if any.
+#[derive(Clone)]
+pub(crate) struct AttachedClient(Arc<Mutex<Option<ClientOutput>>>);
+
+impl AttachedClient {
+    pub fn new(client: ClientOutput) -> Self {
+        Self(Arc::new(Mutex::new(Some(client))))
+    }
+
+    pub fn get(&self) -> Option<ClientOutput> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn attach(&self, client: ClientOutput) {
+        self.0.lock().unwrap().replace(client);
+    }
+
+    pub fn detach(&self) -> Option<ClientOutput> {
+        self.0.lock().unwrap().take()
+    }
+}
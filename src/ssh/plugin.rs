@@ -1,20 +1,36 @@
+use std::cell::RefCell;
 use std::fs::File;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::cursive::view::Nameable;
+use crate::cursive::views::{Dialog, OnEventView, TextView};
 use crate::cursive::Cursive;
+use crate::cursive::CursiveRunner;
 use crate::cursive::Vec2;
-use crate::{App, SessionHandle};
+use crate::cursive::View;
+use crate::{App, Error, SessionHandle};
 
-use cursive::event::Event;
-use log::trace;
+use cursive::event::{Event, EventTrigger, MouseEvent};
+use log::{info, trace, warn};
 use russh_keys::key::PublicKey;
 use tokio::runtime::Builder;
 use tokio::sync::mpsc::channel;
 
 use super::backend::{Backend, CursiveOutput};
+use super::incident::{panic_message, Incident};
+use super::session_manager::{SessionSettings, SessionShared};
+use super::timeouts::{ClockState, SessionClock};
+
+const IDLE_WARNING_LAYER: &str = "ssh_ui_idle_warning";
+/// How long the error screen stays up before a failed session disconnects on its own.
+const ERROR_SCREEN_TIMEOUT: Duration = Duration::from_secs(60);
 
 lazy_static! {
     static ref PLUGINS: Mutex<Option<Arc<dyn App>>> = Mutex::new(None);
+    static ref CONSOLE: Mutex<Option<Arc<dyn App>>> = Mutex::new(None);
 }
 
 pub(super) fn get_plugin() -> Option<Arc<dyn App>> {
@@ -27,8 +43,41 @@ pub fn set_plugin(plugin: Arc<dyn App>) {
     plugins_tmp.replace(plugin);
 }
 
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
-pub struct PluginId(usize);
+pub(super) fn set_console(console: Option<Arc<dyn App>>) {
+    *CONSOLE.lock().unwrap() = console;
+}
+
+/// The app a session runs: the admin console or the server's own.
+fn session_app(shared: &SessionShared) -> Option<Arc<dyn App>> {
+    if shared.console {
+        CONSOLE.lock().unwrap().clone()
+    } else {
+        get_plugin()
+    }
+}
+
+/// Wraps the message of the day in a dialog that any keypress or click dismisses.
+pub(crate) fn motd_view(motd: String) -> impl View {
+    OnEventView::new(Dialog::around(TextView::new(motd)).title("Message of the day")).on_pre_event(
+        EventTrigger::from_fn(|event| match event {
+            Event::Mouse { event, .. } => matches!(event, MouseEvent::Press(_)),
+            Event::Char(_)
+            | Event::CtrlChar(_)
+            | Event::AltChar(_)
+            | Event::Key(_)
+            | Event::Shift(_)
+            | Event::Alt(_)
+            | Event::AltShift(_)
+            | Event::Ctrl(_)
+            | Event::CtrlShift(_)
+            | Event::CtrlAlt(_) => true,
+            _ => false,
+        }),
+        |siv| {
+            siv.pop_layer();
+        },
+    )
+}
 
 pub struct PluginManager {
     bbs_side_input: File,
@@ -36,6 +85,8 @@ pub struct PluginManager {
     resize_receiver: tokio::sync::mpsc::Receiver<Vec2>,
     relayout_sender: tokio::sync::mpsc::Sender<()>,
     relayout_receiver: tokio::sync::mpsc::Receiver<()>,
+    settings: SessionSettings,
+    shared: SessionShared,
 }
 
 unsafe impl Send for PluginManager {}
@@ -47,6 +98,8 @@ impl PluginManager {
         resize_receiver: tokio::sync::mpsc::Receiver<Vec2>,
         relayout_sender: tokio::sync::mpsc::Sender<()>,
         relayout_receiver: tokio::sync::mpsc::Receiver<()>,
+        settings: SessionSettings,
+        shared: SessionShared,
     ) -> Self {
         Self {
             bbs_side_input,
@@ -54,6 +107,8 @@ impl PluginManager {
             resize_receiver,
             relayout_sender,
             relayout_receiver,
+            settings,
+            shared,
         }
     }
 
@@ -62,56 +117,219 @@ impl PluginManager {
         pub_key: Option<PublicKey>,
         handle_id: SessionHandle,
         mut exit_rx: tokio::sync::watch::Receiver<bool>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Error> {
         let runtime = Builder::new_multi_thread()
             .worker_threads(3)
             .enable_all()
-            .build()?;
+            .build()
+            .map_err(Error::Backend)?;
         let _enter = runtime.handle().enter();
 
         trace!("Entering event loop for session handle {}", handle_id.0);
-        let mut siv = Cursive::new();
-
-        let (client_facing_relayout_sender, mut client_facing_relayout_receiver) = channel(10);
-
-        let plugin = get_plugin().unwrap();
-        let mut session = plugin.as_ref().new_session();
-        let view = session.on_start(&mut siv, handle_id, pub_key, client_facing_relayout_sender)?;
-        siv.add_layer(view);
-
         let backend = Backend::init_ssh(
             self.bbs_side_input,
             self.output_sender,
             self.resize_receiver,
             self.relayout_sender,
+            self.shared.pastes.clone(),
         )
-        .expect("Russh backend creation failed");
+        .map_err(Error::Backend)?;
+        let mut siv = Cursive::new();
+        // The runner outlives a panicking app, so its backend can still show the error screen and
+        // restore the client's terminal afterwards.
+        let mut runner = siv.runner(backend);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            run_session(
+                &mut runner,
+                pub_key,
+                handle_id,
+                &mut exit_rx,
+                &mut self.relayout_receiver,
+                &self.settings,
+                &self.shared,
+            )
+        }))
+        .unwrap_or_else(|panic| Err(Error::Panic(panic_message(panic))));
 
-        {
-            let mut runner = siv.runner(backend);
-            runner.add_global_callback(Event::Refresh, move |siv| {
-                let _ = session.on_tick(siv);
-            });
+        if let Err(error) = result {
+            let incident = Incident::new(handle_id, error);
+            warn!(
+                "Session {} failed, incident {}: {}",
+                handle_id.0, incident.id, incident.error
+            );
+            let screen = self.settings.error_screen.view(&incident);
+            if let Some(plugin) = session_app(&self.shared) {
+                if catch_unwind(AssertUnwindSafe(|| plugin.on_error(&incident))).is_err() {
+                    warn!("Error hook panicked on incident {}", incident.id);
+                }
+            }
+            let shown = catch_unwind(AssertUnwindSafe(|| {
+                show_error_screen(
+                    &mut runner,
+                    screen,
+                    &mut exit_rx,
+                    &mut self.relayout_receiver,
+                )
+            }));
+            if shown.is_err() {
+                warn!("Error screen panicked on incident {}", incident.id);
+            }
+        }
+        trace!("Exiting event loop for session {}", handle_id.0);
+        Ok(())
+    }
+}
+
+/// Runs the app's session until it quits, expires or the client goes away.
+fn run_session(
+    runner: &mut CursiveRunner<&mut Cursive>,
+    pub_key: Option<PublicKey>,
+    handle_id: SessionHandle,
+    exit_rx: &mut tokio::sync::watch::Receiver<bool>,
+    relayout_receiver: &mut tokio::sync::mpsc::Receiver<()>,
+    settings: &SessionSettings,
+    shared: &SessionShared,
+) -> Result<(), Error> {
+    let (client_facing_relayout_sender, mut client_facing_relayout_receiver) = channel(10);
+
+    let plugin = session_app(shared).ok_or_else(|| Error::App("no app is loaded".to_string()))?;
+    let mut session = plugin.as_ref().new_session();
+    let view = session
+        .on_start(runner, handle_id, pub_key, client_facing_relayout_sender)
+        .map_err(Error::app)?;
+    runner.add_layer(view);
+    if let Some(motd) = session.motd(settings.motd.as_deref()) {
+        runner.add_layer(motd_view(motd));
+    }
+    let recorder = &shared.recorder;
+    recorder.set_enabled(session.record(recorder.default_enabled()));
+    let clock = SessionClock::new(
+        session.timeouts(&settings.timeouts),
+        shared.activity.clone(),
+    );
+    let session = Rc::new(RefCell::new(session));
+
+    let tick_session = session.clone();
+    let tick_error = Rc::new(RefCell::new(None));
+    let tick_error_sink = tick_error.clone();
+    runner.add_global_callback(Event::Refresh, move |siv| {
+        if let Err(err) = tick_session.borrow_mut().on_tick(siv) {
+            tick_error_sink.borrow_mut().get_or_insert(Error::app(err));
+            siv.quit();
+        }
+    });
 
+    runner.refresh();
+    runner.on_event(Event::Refresh);
+    // Seconds left on the idle warning currently on screen, if any.
+    let mut idle_warning: Option<u64> = None;
+    while runner.is_running() && !exit_requested(exit_rx) {
+        let (kicked, messages) = shared.control.take();
+        if kicked {
+            info!("Session {} was kicked by an operator", handle_id.0);
+            runner.quit();
+            break;
+        }
+        for message in messages {
+            runner.add_layer(
+                Dialog::around(TextView::new(message))
+                    .title("Message from the operator")
+                    .dismiss_button("OK"),
+            );
             runner.refresh();
-            runner.on_event(Event::Refresh);
-            while runner.is_running() && !*exit_rx.borrow_and_update() {
-                if self.relayout_receiver.try_recv().is_ok()
-                    || client_facing_relayout_receiver.try_recv().is_ok()
-                {
-                    trace!("Forcefully refreshing layout for session {}", handle_id.0);
-                    // TODO: Figure out why this is necessary. It seems like we do actually need two refreshes and a step to make this work :(
+        }
+        for text in shared.pastes.take() {
+            session.borrow_mut().on_paste(runner, &text);
+            runner.refresh();
+        }
+        match clock.state() {
+            ClockState::Running => {
+                if idle_warning.take().is_some() {
+                    if let Some(layer) =
+                        runner.screen_mut().find_layer_from_name(IDLE_WARNING_LAYER)
+                    {
+                        runner.screen_mut().remove_layer(layer);
+                    }
                     runner.refresh();
-                    runner.on_event(Event::Refresh);
-                    runner.step();
+                }
+            }
+            ClockState::Warning(remaining) => {
+                let seconds = remaining.as_secs() + 1;
+                if idle_warning != Some(seconds) {
+                    let text = format!(
+                            "You will be disconnected in {} seconds for being idle.\nPress any key to stay connected.",
+                            seconds
+                        );
+                    if idle_warning.is_some() {
+                        runner.call_on_name(IDLE_WARNING_LAYER, |view: &mut TextView| {
+                            view.set_content(text)
+                        });
+                    } else {
+                        runner.add_layer(
+                            Dialog::around(TextView::new(text).with_name(IDLE_WARNING_LAYER))
+                                .title("Are you still there?"),
+                        );
+                    }
+                    idle_warning = Some(seconds);
                     runner.refresh();
-                    runner.on_event(Event::Refresh);
-                } else {
-                    runner.step();
                 }
             }
+            ClockState::Expired(expiry) => {
+                info!("Session {} expired: {:?}", handle_id.0, expiry);
+                session.borrow_mut().on_expire(runner, expiry);
+                runner.quit();
+                break;
+            }
         }
-        trace!("Exiting event loop for session {}", handle_id.0);
-        Ok(())
+        let resized = relayout_receiver.try_recv().is_ok();
+        if resized || client_facing_relayout_receiver.try_recv().is_ok() {
+            trace!("Forcefully refreshing layout for session {}", handle_id.0);
+            if resized {
+                // Cursive only clears the screen for its own resize event, which the
+                // backend never produces, so leftovers of the old layout would stay.
+                runner.clear();
+            }
+            // TODO: Figure out why this is necessary. It seems like we do actually need two refreshes and a step to make this work :(
+            runner.refresh();
+            runner.on_event(Event::Refresh);
+            runner.step();
+            runner.refresh();
+            runner.on_event(Event::Refresh);
+        } else {
+            runner.step();
+        }
+    }
+    match tick_error.take() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Whether the session's task asked the event loop to exit, or went away without asking, as it
+/// does when the server is dropped.
+fn exit_requested(exit_rx: &mut tokio::sync::watch::Receiver<bool>) -> bool {
+    *exit_rx.borrow_and_update() || exit_rx.has_changed().is_err()
+}
+
+/// Replaces whatever the failed session left on screen with the error screen, until the user
+/// dismisses it, the client goes away or [`ERROR_SCREEN_TIMEOUT`] passes.
+fn show_error_screen(
+    runner: &mut CursiveRunner<&mut Cursive>,
+    screen: impl View,
+    exit_rx: &mut tokio::sync::watch::Receiver<bool>,
+    relayout_receiver: &mut tokio::sync::mpsc::Receiver<()>,
+) {
+    // The app's views and callbacks may be in any state after it failed, so start over.
+    **runner = Cursive::new();
+    runner.add_layer(screen);
+    runner.clear();
+    runner.refresh();
+    let deadline = Instant::now() + ERROR_SCREEN_TIMEOUT;
+    while runner.is_running() && !exit_requested(exit_rx) && Instant::now() < deadline {
+        if relayout_receiver.try_recv().is_ok() {
+            runner.clear();
+            runner.refresh();
+        }
+        runner.step();
     }
 }
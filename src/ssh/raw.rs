@@ -0,0 +1,295 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::{debug, info, trace};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, WriteHalf};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinSet;
+
+use super::audit::{self, AuditEvent};
+use super::client::{ClientOutput, FrontendOutput};
+use super::limits::ConnectionLimiter;
+use super::listener::{back_off_after, Listener, Stream};
+use super::metrics;
+use super::session_manager::{SessionChannel, SessionRepoUpdate, SshSessionUpdate};
+use super::spans;
+
+/// Asks the terminal for its size in characters. Terminals answer with `ESC [ 8 ; rows ; cols t`.
+const SIZE_QUERY: &[u8] = b"\x1b[18t";
+
+/// How often the terminal is asked for its size again. Nothing tells a raw connection that the
+/// terminal was resized, so this is how long a resize takes to reach the session.
+const SIZE_QUERY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The longest partial size report held back for the next read, which is longer than any real
+/// one. Anything longer is passed through as input.
+const MAX_PARTIAL_REPORT: usize = 32;
+
+/// Accepts connections that stream a terminal's bytes both ways with no protocol on top, as
+/// `socat -,raw,echo=0 UNIX-CONNECT:<path>` does, and hands each one to the session manager.
+pub(crate) async fn serve(
+    listener: Listener,
+    username: String,
+    limiter: ConnectionLimiter,
+    session_sender: Sender<SessionRepoUpdate>,
+) {
+    info!("Listening for raw terminals on {}", listener);
+    // Connections belong to the frontend's task, so they're dropped along with it.
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!("Failed to accept raw terminal connection: {}", err);
+                back_off_after(&err).await;
+                continue;
+            }
+        };
+        // Forget the connections that have ended.
+        while connections.try_join_next().is_some() {}
+        let permit = match peer.ip().map(|ip| limiter.try_admit(ip)) {
+            Some(Ok(permit)) => Some(permit),
+            Some(Err(refusal)) => {
+                info!(
+                    "Refusing raw terminal connection from {}: {:?}",
+                    peer, refusal
+                );
+                audit::record(AuditEvent::ConnectionRefused {
+                    frontend: "raw",
+                    peer: peer.addr(),
+                    reason: refusal.reason(),
+                });
+                continue;
+            }
+            None => None,
+        };
+        metrics::connection_accepted("raw");
+        audit::record(AuditEvent::ConnectionOpened {
+            frontend: "raw",
+            peer: peer.addr(),
+        });
+        trace!("New raw terminal client for peer {:?}", peer);
+        let username = username.clone();
+        let session_sender = session_sender.clone();
+        let peer_addr = peer.addr();
+        let span = spans::connection("raw", &peer);
+        spans::record_username(&span, &username);
+        let connection = async move {
+            handle_connection(stream, username, peer_addr, session_sender).await;
+            debug!("Raw terminal connection from {} closed", peer);
+            drop(permit);
+        };
+        connections.spawn(spans::instrument(connection, span));
+    }
+}
+
+async fn handle_connection(
+    stream: Stream,
+    username: String,
+    peer_addr: Option<SocketAddr>,
+    session_sender: Sender<SessionRepoUpdate>,
+) {
+    let (mut reader, writer) = tokio::io::split(stream);
+    let (output_sender, output_receiver) = channel(100);
+    let (update_sender, update_rx) = channel(100);
+    // Owned by the connection's task, so the writer and its half of the socket go with it.
+    let mut writer_task = JoinSet::new();
+    writer_task.spawn(write_output(writer, output_receiver));
+
+    // Used until the terminal answers the size query, or for good if it never does.
+    let mut size = (80, 24);
+    let _ = update_sender
+        .send(SshSessionUpdate::WindowResize(size.0, size.1))
+        .await;
+    let channel = SessionChannel {
+        output: ClientOutput::Frontend(output_sender),
+        update_rx,
+        username,
+        key: None,
+        peer: peer_addr,
+        console: false,
+        span: spans::current(),
+    };
+    if session_sender
+        .send(SessionRepoUpdate::NewSession(channel))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let mut size_reports = SizeReports::default();
+    let mut buf = [0u8; 4096];
+    loop {
+        let read = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        for update in size_reports.feed(&buf[..read]) {
+            // Most answers to the repeated query report the size the session already has.
+            if let SshSessionUpdate::WindowResize(cols, rows) = update {
+                if (cols, rows) == size {
+                    continue;
+                }
+                size = (cols, rows);
+            }
+            if update_sender.send(update).await.is_err() {
+                debug!("Session for raw terminal connection has already ended");
+            }
+        }
+    }
+    let _ = update_sender.send(SshSessionUpdate::Close).await;
+    // Lets the writer finish sending what the session had left to say.
+    let _ = writer_task.join_next().await;
+}
+
+async fn write_output(
+    mut writer: WriteHalf<Stream>,
+    mut output_receiver: Receiver<FrontendOutput>,
+) {
+    let mut size_query = tokio::time::interval(SIZE_QUERY_INTERVAL);
+    loop {
+        let data = tokio::select! {
+            output = output_receiver.recv() => match output {
+                Some(FrontendOutput::Data(data)) => data,
+                Some(FrontendOutput::Close) | None => break,
+            },
+            _ = size_query.tick() => SIZE_QUERY.to_vec(),
+        };
+        if writer.write_all(&data).await.is_err() {
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Separates the terminal's answers to the size query from the rest of its input.
+#[derive(Default)]
+struct SizeReports {
+    /// The start of an answer cut off at the end of the last read.
+    partial: Vec<u8>,
+}
+
+impl SizeReports {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<SshSessionUpdate> {
+        let mut input = std::mem::take(&mut self.partial);
+        input.extend_from_slice(bytes);
+        let mut updates = Vec::new();
+        let mut data = Vec::new();
+        let mut rest = &input[..];
+        while !rest.is_empty() {
+            if let Some((cols, rows, len)) = size_report(rest) {
+                if !data.is_empty() {
+                    updates.push(SshSessionUpdate::Data(std::mem::take(&mut data)));
+                }
+                updates.push(SshSessionUpdate::WindowResize(cols, rows));
+                rest = &rest[len..];
+            } else if is_partial_report(rest) {
+                // The rest of the answer comes with the next read.
+                self.partial = rest.to_vec();
+                break;
+            } else {
+                data.push(rest[0]);
+                rest = &rest[1..];
+            }
+        }
+        if !data.is_empty() {
+            updates.push(SshSessionUpdate::Data(data));
+        }
+        updates
+    }
+}
+
+/// Whether `bytes` is the start of a size report and nothing else. Anything short of `ESC [ 8 ;`
+/// is passed through, since a lone escape is far more likely to be the Escape key.
+fn is_partial_report(bytes: &[u8]) -> bool {
+    bytes.len() < MAX_PARTIAL_REPORT
+        && bytes.strip_prefix(b"\x1b[8;").is_some_and(|body| {
+            body.iter()
+                .all(|&byte| byte.is_ascii_digit() || byte == b';')
+        })
+}
+
+/// Parses `ESC [ 8 ; rows ; cols t` at the start of `bytes`, returning the size and its length.
+fn size_report(bytes: &[u8]) -> Option<(usize, usize, usize)> {
+    let body = bytes.strip_prefix(b"\x1b[8;")?;
+    let end = body.iter().position(|&byte| byte == b't')?;
+    let (rows, cols) = std::str::from_utf8(&body[..end]).ok()?.split_once(';')?;
+    let rows = rows.parse().ok().filter(|&rows| rows > 0)?;
+    let cols = cols.parse().ok().filter(|&cols| cols > 0)?;
+    Some((cols, rows, 4 + end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_size_reports() {
+        assert_eq!(size_report(b"\x1b[8;24;80t"), Some((80, 24, 10)));
+        assert_eq!(size_report(b"\x1b[8;50;132tabc"), Some((132, 50, 11)));
+        assert_eq!(size_report(b"\x1b[8;0;80t"), None);
+        assert_eq!(size_report(b"\x1b[8;24;0t"), None);
+        assert_eq!(size_report(b"\x1b[8;24;80"), None);
+        assert_eq!(size_report(b"\x1b[8;24t"), None);
+        assert_eq!(size_report(b"\x1b[8;2a;80t"), None);
+        assert_eq!(size_report(b"\x1b[8;-1;80t"), None);
+        assert_eq!(size_report(b"\x1b[8;99999999999999999999999;80t"), None);
+        assert_eq!(size_report(b"\x1b[4;24;80t"), None);
+        assert_eq!(size_report(b"x\x1b[8;24;80t"), None);
+    }
+
+    #[test]
+    fn separates_size_reports_from_input() {
+        let mut size_reports = SizeReports::default();
+        assert_eq!(
+            size_reports.feed(b"ab\x1b[8;24;80tc\x1b[8;30;100t"),
+            [
+                SshSessionUpdate::Data(b"ab".to_vec()),
+                SshSessionUpdate::WindowResize(80, 24),
+                SshSessionUpdate::Data(b"c".to_vec()),
+                SshSessionUpdate::WindowResize(100, 30),
+            ]
+        );
+        assert_eq!(
+            size_reports.feed(b"\x1b[A\x1b[8;2a"),
+            [SshSessionUpdate::Data(b"\x1b[A\x1b[8;2a".to_vec())]
+        );
+        assert_eq!(size_reports.feed(b""), []);
+    }
+
+    #[test]
+    fn size_reports_can_span_reads() {
+        let mut size_reports = SizeReports::default();
+        assert_eq!(
+            size_reports.feed(b"a\x1b[8;24"),
+            [SshSessionUpdate::Data(b"a".to_vec())]
+        );
+        assert_eq!(
+            size_reports.feed(b";80tb"),
+            [
+                SshSessionUpdate::WindowResize(80, 24),
+                SshSessionUpdate::Data(b"b".to_vec())
+            ]
+        );
+        // Only the report's own prefix is held back, not a lone escape.
+        assert_eq!(
+            size_reports.feed(b"\x1b"),
+            [SshSessionUpdate::Data(b"\x1b".to_vec())]
+        );
+        // A held back start that turns out not to be a report is input after all.
+        assert_eq!(size_reports.feed(b"\x1b[8;1"), []);
+        assert_eq!(
+            size_reports.feed(b"x"),
+            [SshSessionUpdate::Data(b"\x1b[8;1x".to_vec())]
+        );
+    }
+
+    #[test]
+    fn partial_size_reports_stay_bounded() {
+        let mut size_reports = SizeReports::default();
+        let mut bytes = b"\x1b[8;".to_vec();
+        bytes.extend([b'1'; MAX_PARTIAL_REPORT]);
+        assert_eq!(size_reports.feed(&bytes), [SshSessionUpdate::Data(bytes)]);
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use serde_json::json;
+
+use crate::cursive::Vec2;
+use crate::SessionHandle;
+
+/// Server-wide settings for recording sessions to asciicast v2 files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordingPolicy {
+    /// Directory the `.cast` files are written to. Created if it doesn't exist.
+    pub directory: PathBuf,
+    /// Whether sessions are recorded unless they opt out through [`crate::AppSession::record`].
+    pub record_by_default: bool,
+    /// Whether client input is recorded alongside output and resizes.
+    pub record_input: bool,
+    /// Start a new file once the current one grows past this many bytes.
+    pub max_file_bytes: Option<u64>,
+    /// Delete the oldest recordings in `directory` once there are more than this many. Recordings
+    /// still being written are never deleted, and other files in the directory are left alone.
+    pub max_files: Option<usize>,
+}
+
+impl RecordingPolicy {
+    /// Records every session's output into `directory`, without size or count limits.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            record_by_default: true,
+            record_input: false,
+            max_file_bytes: None,
+            max_files: None,
+        }
+    }
+}
+
+/// How many events may wait for a session's writer thread before the recording is given up on.
+const MAX_PENDING_EVENTS: usize = 4096;
+
+lazy_static! {
+    /// Recordings still being written, which pruning must leave alone.
+    static ref OPEN_RECORDINGS: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+struct RecorderState {
+    policy: RecordingPolicy,
+    handle_id: SessionHandle,
+    size: Vec2,
+    enabled: bool,
+    parts: Arc<AtomicUsize>,
+    /// Feeds the writer thread of the current recording, if there is one.
+    writer: Option<SyncSender<CastEvent>>,
+    /// Bumped for every writer thread, so a failing one can tell whether it's still current.
+    writers: usize,
+}
+
+impl RecorderState {
+    fn stop(&mut self) {
+        self.enabled = false;
+        self.writer = None;
+    }
+}
+
+struct CastEvent {
+    at: Instant,
+    size: Vec2,
+    code: &'static str,
+    data: Vec<u8>,
+}
+
+/// Writes a single session's output, resizes and optionally input to asciicast files. The files
+/// are written on a thread of their own, so recording never blocks the session's tasks on disk.
+#[derive(Clone)]
+pub(crate) struct SessionRecorder(Option<Arc<Mutex<RecorderState>>>);
+
+impl SessionRecorder {
+    pub fn new(policy: Option<RecordingPolicy>, handle_id: SessionHandle) -> Self {
+        Self(policy.map(|policy| {
+            Arc::new(Mutex::new(RecorderState {
+                enabled: false,
+                policy,
+                handle_id,
+                size: Vec2::new(80, 24),
+                parts: Arc::new(AtomicUsize::new(0)),
+                writer: None,
+                writers: 0,
+            }))
+        }))
+    }
+
+    /// The server's default for this session, or `false` if recording isn't configured.
+    pub fn default_enabled(&self) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|state| state.lock().unwrap().policy.record_by_default)
+    }
+
+    /// Whether the session is being recorded, or `None` if recording isn't configured.
+    pub fn enabled(&self) -> Option<bool> {
+        self.0.as_ref().map(|state| state.lock().unwrap().enabled)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        let shared = match &self.0 {
+            Some(shared) => shared,
+            None => return,
+        };
+        let mut state = shared.lock().unwrap();
+        if !enabled {
+            // The writer thread finishes what's queued and closes the file.
+            state.stop();
+        } else if state.writer.is_none() {
+            let (sender, receiver) = sync_channel(MAX_PENDING_EVENTS);
+            state.writers += 1;
+            let writer = CastWriter {
+                shared: Arc::downgrade(shared),
+                generation: state.writers,
+                parts: state.parts.clone(),
+                policy: state.policy.clone(),
+                handle_id: state.handle_id,
+                file: None,
+                pending_output: Vec::new(),
+                pending_input: Vec::new(),
+            };
+            let spawned = std::thread::Builder::new()
+                .name(format!("ssh_ui recording {}", state.handle_id.0))
+                .spawn(move || writer.run(receiver));
+            match spawned {
+                Ok(_) => {
+                    state.enabled = true;
+                    state.writer = Some(sender);
+                }
+                Err(err) => warn!(
+                    "Not recording session {}: failed to start its writer: {}",
+                    state.handle_id.0, err
+                ),
+            }
+        }
+    }
+
+    pub fn output(&self, data: &[u8]) {
+        self.event("o", data);
+    }
+
+    pub fn input(&self, data: &[u8]) {
+        if let Some(state) = &self.0 {
+            if !state.lock().unwrap().policy.record_input {
+                return;
+            }
+        }
+        self.event("i", data);
+    }
+
+    pub fn resize(&self, size: Vec2) {
+        if let Some(state) = &self.0 {
+            state.lock().unwrap().size = size;
+        }
+        self.event("r", format!("{}x{}", size.x, size.y).as_bytes());
+    }
+
+    fn event(&self, code: &'static str, data: &[u8]) {
+        let state = match &self.0 {
+            Some(state) => state,
+            None => return,
+        };
+        let mut state = state.lock().unwrap();
+        let writer = match (&state.writer, state.enabled) {
+            (Some(writer), true) => writer,
+            _ => return,
+        };
+        let event = CastEvent {
+            at: Instant::now(),
+            size: state.size,
+            code,
+            data: data.to_vec(),
+        };
+        match writer.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "Stopping recording of session {}: the disk can't keep up",
+                    state.handle_id.0
+                );
+                state.stop();
+            }
+            // The writer already gave up and said why.
+            Err(TrySendError::Disconnected(_)) => state.stop(),
+        }
+    }
+}
+
+struct CastFile {
+    path: PathBuf,
+    writer: LineWriter<File>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Drop for CastFile {
+    fn drop(&mut self) {
+        OPEN_RECORDINGS.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// Owns the files of one recording, on the thread that writes them.
+struct CastWriter {
+    shared: Weak<Mutex<RecorderState>>,
+    generation: usize,
+    parts: Arc<AtomicUsize>,
+    policy: RecordingPolicy,
+    handle_id: SessionHandle,
+    file: Option<CastFile>,
+    /// The start of a UTF-8 character split across chunks, kept until the rest arrives.
+    pending_output: Vec<u8>,
+    pending_input: Vec<u8>,
+}
+
+impl CastWriter {
+    fn run(mut self, events: Receiver<CastEvent>) {
+        for event in events {
+            if let Err(err) = self.write_event(event) {
+                warn!(
+                    "Stopping recording of session {}: {}",
+                    self.handle_id.0, err
+                );
+                if let Some(shared) = self.shared.upgrade() {
+                    let mut state = shared.lock().unwrap();
+                    if state.writers == self.generation {
+                        state.stop();
+                    }
+                }
+                return;
+            }
+        }
+    }
+
+    fn write_event(&mut self, event: CastEvent) -> std::io::Result<()> {
+        let rotate = match (&self.file, self.policy.max_file_bytes) {
+            (None, _) => true,
+            (Some(file), Some(max)) => file.bytes >= max,
+            (Some(_), None) => false,
+        };
+        if rotate {
+            self.open(event.size)?;
+        }
+        let pending = match event.code {
+            "o" => &mut self.pending_output,
+            "i" => &mut self.pending_input,
+            _ => &mut Vec::new(),
+        };
+        let text = take_utf8(pending, &event.data);
+        if text.is_empty() && !event.data.is_empty() {
+            return Ok(());
+        }
+        let file = self.file.as_mut().expect("recording file was just opened");
+        let line = json!([
+            event
+                .at
+                .saturating_duration_since(file.started)
+                .as_secs_f64(),
+            event.code,
+            text
+        ])
+        .to_string();
+        writeln!(file.writer, "{}", line)?;
+        file.bytes += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn open(&mut self, size: Vec2) -> std::io::Result<()> {
+        fs::create_dir_all(&self.policy.directory)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        let path = self.policy.directory.join(format!(
+            "session-{}-{}-{}.cast",
+            self.handle_id.0,
+            timestamp,
+            self.parts.fetch_add(1, Ordering::Relaxed)
+        ));
+        debug!(
+            "Recording session {} to {}",
+            self.handle_id.0,
+            path.display()
+        );
+        let writer = LineWriter::new(File::create(&path)?);
+        OPEN_RECORDINGS.lock().unwrap().insert(path.clone());
+        // Taking the new file's place drops the previous one, and with it its spot on the list.
+        let file = self.file.insert(CastFile {
+            writer,
+            path,
+            started: Instant::now(),
+            bytes: 0,
+        });
+        let header = json!({
+            "version": 2,
+            "width": size.x,
+            "height": size.y,
+            "timestamp": timestamp,
+            "title": format!("ssh_ui session {}", self.handle_id.0),
+        })
+        .to_string();
+        writeln!(file.writer, "{}", header)?;
+        file.bytes = header.len() as u64 + 1;
+        self.prune();
+        Ok(())
+    }
+
+    /// Removes the oldest recordings once the directory holds more than `max_files`. Only files
+    /// named the way the recorder names them are counted, and none that are still being written.
+    fn prune(&self) {
+        let max_files = match self.policy.max_files {
+            Some(max_files) => max_files,
+            None => return,
+        };
+        let entries = match fs::read_dir(&self.policy.directory) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let open = OPEN_RECORDINGS.lock().unwrap().clone();
+        let mut casts: Vec<(SystemTime, PathBuf)> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| is_recording(path) && !open.contains(path))
+            .filter_map(|path| Some((fs::metadata(&path).ok()?.modified().ok()?, path)))
+            .collect();
+        // The files still being written count towards the limit, but can't be removed.
+        let total = casts.len()
+            + open
+                .iter()
+                .filter(|path| path.parent() == Some(&*self.policy.directory))
+                .count();
+        if total <= max_files {
+            return;
+        }
+        casts.sort();
+        for (_, path) in casts.iter().take(total - max_files) {
+            if let Err(err) = fs::remove_file(path) {
+                debug!("Failed to remove old recording {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Whether `path` is named like a recording, `session-<id>-<timestamp>-<part>.cast`.
+fn is_recording(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    let fields = match name
+        .strip_prefix("session-")
+        .and_then(|name| name.strip_suffix(".cast"))
+    {
+        Some(fields) => fields,
+        None => return false,
+    };
+    let fields: Vec<&str> = fields.split('-').collect();
+    fields.len() == 3
+        && fields
+            .iter()
+            .all(|field| !field.is_empty() && field.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+/// Decodes `pending` followed by `data` as UTF-8, leaving the start of a character that's cut off
+/// at the end in `pending` for the next chunk. Bytes that can never be valid become U+FFFD.
+fn take_utf8(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let complete = pending.len() - incomplete_tail(pending);
+    let text = String::from_utf8_lossy(&pending[..complete]).into_owned();
+    pending.drain(..complete);
+    text
+}
+
+/// The length of the unfinished multi-byte character at the end of `bytes`, if there is one.
+fn incomplete_tail(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        if byte & 0xc0 != 0x80 {
+            let len = match byte {
+                0xc2..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf4 => 4,
+                _ => 1,
+            };
+            return if len > back { back } else { 0 };
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn characters_split_across_chunks_are_kept_whole() {
+        let mut pending = Vec::new();
+        let snowman = "\u{2603}".as_bytes();
+        assert_eq!(take_utf8(&mut pending, b"a"), "a");
+        assert_eq!(take_utf8(&mut pending, &snowman[..1]), "");
+        assert_eq!(take_utf8(&mut pending, &snowman[1..2]), "");
+        assert_eq!(take_utf8(&mut pending, &snowman[2..]), "\u{2603}");
+        assert!(pending.is_empty());
+
+        let emoji = "b\u{1f600}".as_bytes();
+        assert_eq!(take_utf8(&mut pending, &emoji[..3]), "b");
+        assert_eq!(take_utf8(&mut pending, &emoji[3..]), "\u{1f600}");
+    }
+
+    #[test]
+    fn invalid_bytes_are_replaced_not_held_back() {
+        let mut pending = Vec::new();
+        assert_eq!(take_utf8(&mut pending, b"a\xffb"), "a\u{fffd}b");
+        assert_eq!(take_utf8(&mut pending, b"c\x80"), "c\u{fffd}");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn only_recordings_are_pruned() {
+        assert!(is_recording(Path::new("/tmp/session-1-1700000000-0.cast")));
+        assert!(!is_recording(Path::new("/tmp/session-1-1700000000.cast")));
+        assert!(!is_recording(Path::new("/tmp/session-1-x-0.cast")));
+        assert!(!is_recording(Path::new("/tmp/demo.cast")));
+        assert!(!is_recording(Path::new(
+            "/tmp/session-1-1700000000-0.cast.bak"
+        )));
+    }
+
+    #[test]
+    fn records_on_a_thread_of_its_own() {
+        let directory =
+            std::env::temp_dir().join(format!("ssh_ui-recording-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let recorder =
+            SessionRecorder::new(Some(RecordingPolicy::new(&directory)), SessionHandle(7));
+        recorder.set_enabled(true);
+        let snowman = "\u{2603}".as_bytes();
+        recorder.output(&snowman[..2]);
+        recorder.output(&snowman[2..]);
+        recorder.set_enabled(false);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let cast = loop {
+            let cast = fs::read_dir(&directory)
+                .ok()
+                .and_then(|mut entries| entries.next())
+                .and_then(|entry| fs::read_to_string(entry.ok()?.path()).ok())
+                .unwrap_or_default();
+            if cast.lines().count() == 2 || Instant::now() > deadline {
+                break cast;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let _ = fs::remove_dir_all(&directory);
+        let lines: Vec<&str> = cast.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", cast);
+        assert!(lines[0].contains("\"version\":2"));
+        assert!(lines[1].contains("\"o\",\"\u{2603}\""), "{}", lines[1]);
+    }
+}
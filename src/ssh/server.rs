@@ -1,91 +1,298 @@
+use log::debug;
 use log::info;
 use log::trace;
-use russh::server;
+use russh::server::run_stream;
 use russh::server::Config;
 use russh::MethodSet;
 use russh_keys::key::KeyPair;
-use std::net::IpAddr;
-use std::net::Ipv4Addr;
 use std::net::SocketAddr;
-use std::net::SocketAddrV4;
 use std::sync::Arc;
-use std::sync::Mutex;
 use std::time::Duration;
-use tokio::spawn;
-use tokio::sync::mpsc::Sender;
-use tokio::sync::watch;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::task::JoinSet;
 
+use super::audit::{self, AuditEvent};
+use super::banner::Banner;
 use super::handler::ThinHandler;
+use super::limits::ConnectionLimiter;
+use super::limits::ConnectionLimits;
+use super::listener::back_off_after;
+use super::listener::ListenAddr;
+use super::listener::Listener;
+use super::metrics::{self, PrometheusRecorder};
+use super::raw;
 use super::session_manager::SessionManager;
 use super::session_manager::SessionRepoUpdate;
+use super::spans;
+use super::telnet::{self, TelnetConfig};
+#[cfg(feature = "websocket")]
+use super::websocket::{self, WebSocketConfig};
+use crate::Error;
 
 pub struct Server {
-    pub listen: IpAddr,
-    pub port: u16,
+    pub listen: Vec<ListenAddr>,
     pub server_keys: Vec<KeyPair>,
-    pub connection_timeout: Duration,
-    pub auth_rejection_time: Duration,
-    pub exit_rx: watch::Receiver<bool>,
-    pub stdio_lock: Arc<Mutex<()>>,
+    limiter: ConnectionLimiter,
+    banner: Option<Banner>,
+    telnet: Option<TelnetConfig>,
+    raw_terminal: Option<ListenAddr>,
+    #[cfg(feature = "websocket")]
+    websocket: Option<WebSocketConfig>,
+    metrics_endpoint: Option<(SocketAddr, Arc<PrometheusRecorder>)>,
+    /// Every ssh client connecting here gets the admin console.
+    console_listen: Option<ListenAddr>,
     session_sender: Sender<SessionRepoUpdate>,
 }
 
 impl Server {
     pub async fn new(
         server_keys: &[KeyPair],
-        rx_exit: watch::Receiver<bool>,
         sender: Sender<SessionRepoUpdate>,
-        port: u16,
+        listen: Vec<ListenAddr>,
+        limits: ConnectionLimits,
+        banner: Option<Banner>,
+        telnet: Option<TelnetConfig>,
+        raw_terminal: Option<ListenAddr>,
     ) -> Self {
         Self {
             server_keys: server_keys.to_vec(),
-            connection_timeout: Duration::from_secs(600),
-            auth_rejection_time: Duration::from_secs(0),
-            exit_rx: rx_exit,
-            stdio_lock: Arc::new(Mutex::new(())),
-            listen: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-            port,
+            listen,
+            limiter: ConnectionLimiter::new(limits),
+            banner,
+            telnet,
+            raw_terminal,
+            #[cfg(feature = "websocket")]
+            websocket: None,
+            metrics_endpoint: None,
+            console_listen: None,
             session_sender: sender,
         }
     }
-    pub async fn listen(
-        self,
-        mut session_repository: SessionManager,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut config = Config::default();
-        config.connection_timeout = Some(self.connection_timeout.clone());
-        config.auth_rejection_time = self.auth_rejection_time.clone();
-        for key in &self.server_keys {
-            config.keys.push(key.clone().into());
-        }
-        config.methods = MethodSet::PUBLICKEY | MethodSet::NONE;
-        config.connection_timeout = None;
 
-        let config = Arc::new(config);
+    #[cfg(feature = "websocket")]
+    pub fn with_websocket(mut self, websocket: Option<WebSocketConfig>) -> Self {
+        self.websocket = websocket;
+        self
+    }
+
+    pub fn with_metrics_endpoint(
+        mut self,
+        metrics_endpoint: Option<(SocketAddr, Arc<PrometheusRecorder>)>,
+    ) -> Self {
+        self.metrics_endpoint = metrics_endpoint;
+        self
+    }
+
+    pub fn with_console_listener(mut self, console_listen: Option<ListenAddr>) -> Self {
+        self.console_listen = console_listen;
+        self
+    }
+
+    pub async fn listen(self, session_repository: SessionManager) -> Result<(), Error> {
+        let mut tasks = JoinSet::new();
+        let mut listeners = Vec::with_capacity(self.listen.len());
+        for listen in &self.listen {
+            listeners.push(bind(listen).await?);
+        }
+        let console_listener = match &self.console_listen {
+            Some(console_listen) => Some(bind(console_listen).await?),
+            None => None,
+        };
+        let telnet_listener = match &self.telnet {
+            Some(telnet) => Some(telnet.bind().await?),
+            None => None,
+        };
+        if let Some(raw_terminal) = &self.raw_terminal {
+            tasks.spawn(raw::serve(
+                bind(raw_terminal).await?,
+                "terminal".to_string(),
+                self.limiter.clone(),
+                self.session_sender.clone(),
+            ));
+        }
+        #[cfg(feature = "websocket")]
+        if let Some(websocket) = &self.websocket {
+            tasks.spawn(websocket::serve(
+                websocket.bind().await?,
+                websocket.clone(),
+                self.limiter.clone(),
+                self.session_sender.clone(),
+            ));
+        }
+        if let Some((addr, recorder)) = &self.metrics_endpoint {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|source| Error::Bind {
+                    addr: addr.to_string(),
+                    source,
+                })?;
+            tasks.spawn(metrics::serve(listener, recorder.clone()));
+        }
+        self.serve(
+            listeners,
+            console_listener,
+            telnet_listener,
+            session_repository,
+            tasks,
+        )
+        .await;
+        Ok(())
+    }
 
-        let addr = format!("{}:{}", self.listen, self.port);
+    /// Accepts connections from already bound listeners until the future is dropped. Every task
+    /// it starts, and the frontends' tasks in `tasks`, belong to it: dropping it stops accepting
+    /// on every listener, ends the ssh connections it accepted and stops handing out sessions.
+    pub async fn serve(
+        self,
+        listeners: Vec<Listener>,
+        console_listener: Option<Listener>,
+        telnet_listener: Option<TcpListener>,
+        mut session_repository: SessionManager,
+        mut tasks: JoinSet<()>,
+    ) {
+        let config = Arc::new(self.config());
 
-        info!("Listening on {}", addr);
+        // Every listener accepts on its own task and funnels connections into the loop below.
+        let (accepted_sender, mut accepted_receiver) = channel(100);
+        let listeners = listeners.into_iter().map(|listener| (listener, false));
+        for (listener, console) in
+            listeners.chain(console_listener.map(|listener| (listener, true)))
+        {
+            if console {
+                info!("Listening for the admin console on {}", listener);
+            } else {
+                info!("Listening on {}", listener);
+            }
+            let accepted_sender = accepted_sender.clone();
+            tasks.spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, peer)) => {
+                            if accepted_sender.send((socket, peer, console)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            debug!("Failed to accept connection: {}", err);
+                            back_off_after(&err).await;
+                        }
+                    }
+                }
+            });
+        }
+        drop(accepted_sender);
 
-        spawn(async move {
+        tasks.spawn(async move {
             session_repository.wait_for_sessions().await;
         });
 
-        russh::server::run(
-            config,
-            &SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), self.port)),
-            self,
-        )
-        .await?;
-        Ok(())
+        if let (Some(telnet_listener), Some(telnet)) = (telnet_listener, self.telnet.clone()) {
+            tasks.spawn(telnet::serve(
+                telnet_listener,
+                telnet,
+                self.limiter.clone(),
+                self.session_sender.clone(),
+            ));
+        }
+
+        while let Some((socket, peer, console)) = accepted_receiver.recv().await {
+            // Forget the connections that have ended.
+            while tasks.try_join_next().is_some() {}
+            // Filesystem permissions already decided who may connect to a Unix socket.
+            let permit = match peer.ip().map(|ip| self.limiter.try_admit(ip)) {
+                Some(Ok(permit)) => Some(permit),
+                Some(Err(refusal)) => {
+                    info!("Refusing connection from {}: {:?}", peer, refusal);
+                    audit::record(AuditEvent::ConnectionRefused {
+                        frontend: "ssh",
+                        peer: peer.addr(),
+                        reason: refusal.reason(),
+                    });
+                    continue;
+                }
+                None => None,
+            };
+            metrics::connection_accepted("ssh");
+            audit::record(AuditEvent::ConnectionOpened {
+                frontend: "ssh",
+                peer: peer.addr(),
+            });
+            trace!("New client created for peer {:?}", peer);
+            let span = spans::connection("ssh", &peer);
+            let banner = self
+                .banner
+                .as_ref()
+                .and_then(|banner| banner.for_peer(peer));
+            let handler = ThinHandler::new(
+                self.session_sender.clone(),
+                self.limiter.clone(),
+                permit,
+                peer.addr(),
+                console,
+                banner,
+                span.clone(),
+            );
+            let config = config.clone();
+            let connection = async move {
+                match run_stream(config, socket, handler).await {
+                    Ok(session) => {
+                        if let Err(err) = session.await {
+                            debug!("Connection from {} ended with error: {}", peer, err);
+                        }
+                    }
+                    Err(err) => {
+                        debug!("Connection from {} failed to start: {}", peer, err);
+                    }
+                }
+            };
+            tasks.spawn(spans::instrument(connection, span));
+        }
+    }
+
+    fn config(&self) -> Config {
+        let mut config = Config {
+            auth_rejection_time: self.limiter.limits().auth_rejection_time,
+            // OpenSSH probes with a `none` request first, which shouldn't be slowed down.
+            auth_rejection_time_initial: Some(Duration::from_secs(0)),
+            methods: MethodSet::PUBLICKEY | MethodSet::NONE,
+            connection_timeout: None,
+            ..Default::default()
+        };
+        for key in &self.server_keys {
+            config.keys.push(key.clone());
+        }
+        config
     }
 }
 
-impl server::Server for Server {
-    type Handler = ThinHandler;
+async fn bind(listen: &ListenAddr) -> Result<Listener, Error> {
+    Listener::bind(listen).await.map_err(|source| Error::Bind {
+        addr: listen.to_string(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
 
-    fn new_client(&mut self, peer_addr: Option<SocketAddr>) -> Self::Handler {
-        trace!("New client created for peer {:?}", peer_addr);
-        ThinHandler::new(self.session_sender.clone())
+    use super::*;
+
+    #[tokio::test]
+    async fn binding_an_address_in_use_fails_with_the_address() {
+        let taken = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = taken.local_addr().unwrap();
+        match bind(&ListenAddr::Tcp(addr)).await {
+            Err(Error::Bind {
+                addr: reported,
+                source,
+            }) => {
+                assert_eq!(reported, addr.to_string());
+                assert_eq!(source.kind(), std::io::ErrorKind::AddrInUse);
+            }
+            Err(err) => panic!("expected a bind error, got {}", err),
+            Ok(_) => panic!("bound an address that's in use"),
+        }
     }
 }
@@ -1,20 +1,43 @@
-use std::{fmt::Debug, fs::File, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::File,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use crate::{cursive::Vec2, SessionHandle};
+use crate::{cursive::Vec2, App, Error, SessionHandle};
 use async_std::io::WriteExt;
-use log::{debug, info};
-use russh::{server::Handle, ChannelId, CryptoVec};
+use log::{debug, info, warn};
 use russh_keys::key::PublicKey;
 use tokio::{
-    spawn,
     sync::{
         mpsc::{channel, Receiver, Sender},
-        watch,
+        oneshot, watch,
     },
-    time::sleep,
+    task::JoinSet,
+    time::{sleep, timeout},
 };
 
-use crate::ssh::plugin::PluginManager;
+use crate::cursive::backends::termion::termion;
+use crate::ssh::audit::{self, AuditEvent};
+use crate::ssh::backend::{CursiveOutput, Pastes, ENABLE_BRACKETED_PASTE};
+use crate::ssh::client::ClientOutput;
+use crate::ssh::console::{AdminConsole, ConsoleApp, SessionControl};
+use crate::ssh::incident::ErrorScreen;
+use crate::ssh::metrics::{self, SessionMetrics};
+use crate::ssh::persistence::{AttachedClient, DetachedSessions, Identity};
+use crate::ssh::plugin::{get_plugin, set_console, PluginManager};
+use crate::ssh::recording::{RecordingPolicy, SessionRecorder};
+use crate::ssh::spans::{self, Span};
+use crate::ssh::spectator::Spectators;
+use crate::ssh::timeouts::{Activity, SessionTimeouts};
+use crate::ssh::waiting_room::{SessionSlot, WaitingRoom};
+
+/// The longest a refused client is waited for before it's told so.
+const REFUSAL_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum SshSessionUpdate {
@@ -23,130 +46,369 @@ pub enum SshSessionUpdate {
     Close,
 }
 
-pub enum SessionRepoUpdate {
-    NewSession(
-        Handle,
-        ChannelId,
-        Receiver<SshSessionUpdate>,
-        Option<PublicKey>,
-    ),
+/// A newly opened ssh channel or frontend connection, before it has been turned into a session
+/// or a spectator.
+pub struct SessionChannel {
+    pub(crate) output: ClientOutput,
+    pub update_rx: Receiver<SshSessionUpdate>,
+    pub username: String,
+    pub key: Option<PublicKey>,
+    /// Unix socket peers have no address.
+    pub(crate) peer: Option<SocketAddr>,
+    /// Whether the channel came in on the admin console's listener.
+    pub(crate) console: bool,
+    /// The span of the connection the channel belongs to.
+    pub(crate) span: Span,
 }
 
-impl Debug for SessionRepoUpdate {
+impl Debug for SessionChannel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::NewSession(_arg0, arg1, arg2, arg3) => f
-                .debug_tuple("NewSession")
-                .field(arg1)
-                .field(arg2)
-                .field(arg3)
-                .finish(),
-        }
+        f.debug_struct("SessionChannel")
+            .field("output", &self.output)
+            .field("update_rx", &self.update_rx)
+            .field("username", &self.username)
+            .field("key", &self.key)
+            .field("peer", &self.peer)
+            .field("console", &self.console)
+            .field("span", &self.span)
+            .finish()
     }
 }
 
+#[derive(Debug)]
+pub enum SessionRepoUpdate {
+    NewSession(SessionChannel),
+}
+
+/// Server-wide settings handed to every session's event loop.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SessionSettings {
+    pub timeouts: SessionTimeouts,
+    pub motd: Option<String>,
+    pub recording: Option<RecordingPolicy>,
+    pub error_screen: ErrorScreen,
+    /// How long a session outlives its client, waiting for the same identity to reconnect.
+    pub detach_grace_period: Option<Duration>,
+}
+
+/// Per-session state shared between the event loop thread and the forwarding tasks.
+#[derive(Clone)]
+pub(crate) struct SessionShared {
+    pub username: String,
+    pub peer: Option<SocketAddr>,
+    pub started: Instant,
+    /// Whether the session runs the admin console rather than the app.
+    pub console: bool,
+    pub control: SessionControl,
+    pub pastes: Pastes,
+    pub activity: Activity,
+    pub recorder: SessionRecorder,
+    pub spectators: Spectators,
+    /// Forces the event loop to redraw the whole screen.
+    pub refresh_sender: Sender<()>,
+}
+
+/// Every running session, keyed by handle.
+pub(crate) type SessionRegistry = Arc<Mutex<HashMap<SessionHandle, SessionShared>>>;
+
 pub struct SessionManager {
-    pub update_sender: Sender<SessionRepoUpdate>,
     pub update_receiver: Receiver<SessionRepoUpdate>,
+    waiting_room: WaitingRoom,
+    settings: SessionSettings,
+    sessions: SessionRegistry,
+    detached: DetachedSessions,
+    console: Option<AdminConsole>,
 }
 
 impl SessionManager {
     pub fn new(
-        update_sender: Sender<SessionRepoUpdate>,
         update_receiver: Receiver<SessionRepoUpdate>,
+        waiting_room: WaitingRoom,
+        settings: SessionSettings,
+        console: Option<AdminConsole>,
     ) -> Self {
+        let sessions: SessionRegistry = Arc::new(Mutex::new(HashMap::new()));
+        set_console(
+            console
+                .as_ref()
+                .map(|_| Arc::new(ConsoleApp::new(sessions.clone())) as Arc<dyn App>),
+        );
         Self {
-            update_sender,
             update_receiver,
+            waiting_room,
+            settings,
+            sessions,
+            detached: DetachedSessions::default(),
+            console,
         }
     }
 
+    /// Starts a session for every new channel. The sessions belong to the returned future, so
+    /// dropping it ends them all.
     pub async fn wait_for_sessions(&mut self) {
         let mut handle_cursor = 0u64;
-        loop {
-            let update = self.update_receiver.recv().await;
-            if update.is_none() {
-                continue;
-            }
-            match update.unwrap() {
-                SessionRepoUpdate::NewSession(handle, channel_id, update_rx, key) => {
-                    let handle_id = handle_cursor.clone();
+        let mut tasks = JoinSet::new();
+        while let Some(update) = self.update_receiver.recv().await {
+            // Forget the sessions that have ended.
+            while tasks.try_join_next().is_some() {}
+            match update {
+                SessionRepoUpdate::NewSession(channel) => {
+                    let console = self
+                        .console
+                        .as_ref()
+                        .and_then(|console| console.admits(&channel));
+                    if console == Some(false) {
+                        info!(
+                            "Refusing the admin console to {} from {:?}",
+                            channel.username, channel.peer
+                        );
+                        let span = channel.span.clone();
+                        tasks.spawn(spans::instrument(Self::refuse(channel), span));
+                        continue;
+                    }
+                    let console = console.is_some();
+                    // Operators can't spectate through the console's login, nor pick up an
+                    // ordinary detached session.
+                    let spectate = get_plugin().filter(|_| !console).and_then(|plugin| {
+                        plugin.spectate(&channel.username, channel.key.as_ref())
+                    });
+                    if let Some(target) = spectate {
+                        let session = self.sessions.lock().unwrap().get(&target).cloned();
+                        let span = channel.span.clone();
+                        tasks.spawn(spans::instrument(
+                            Self::handle_spectator(channel, target, session),
+                            span,
+                        ));
+                        continue;
+                    }
+                    let channel = if console {
+                        channel
+                    } else {
+                        match self.detached.reattach(channel) {
+                            Some(channel) => channel,
+                            None => continue,
+                        }
+                    };
+                    let handle_id = handle_cursor;
                     handle_cursor += 1;
-                    spawn(async move {
+                    let waiting_room = self.waiting_room.clone();
+                    let settings = self.settings.clone();
+                    let sessions = self.sessions.clone();
+                    let detached = self.detached.clone();
+                    let span = spans::session(&channel.span, SessionHandle(handle_id));
+                    tasks.spawn(spans::instrument(
                         Self::handle_session(
-                            handle,
-                            channel_id,
-                            update_rx,
+                            channel,
                             SessionHandle(handle_id),
-                            key,
-                        )
-                        .await;
-                    });
+                            waiting_room,
+                            settings,
+                            sessions,
+                            detached,
+                            console,
+                        ),
+                        span,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Turns away a channel that isn't allowed into the admin console.
+    async fn refuse(mut channel: SessionChannel) {
+        // Anything sent before the client has seen the channel confirmed is lost, and its pty
+        // request is the first sign that it has.
+        let _ = timeout(REFUSAL_DELAY, channel.update_rx.recv()).await;
+        let _ = channel.output.data(b"Not authorized.\r\n").await;
+        channel.output.close().await;
+    }
+
+    /// Attaches a channel to an existing session as a read-only viewer until the channel closes.
+    async fn handle_spectator(
+        channel: SessionChannel,
+        target: SessionHandle,
+        session: Option<SessionShared>,
+    ) {
+        let SessionChannel {
+            output,
+            mut update_rx,
+            ..
+        } = channel;
+        let session = match session {
+            Some(session) => session,
+            None => {
+                info!("Can't spectate session {}, it isn't running", target.0);
+                let message = format!("Session {} isn't running.\r\n", target.0);
+                let _ = output.data(message.as_bytes()).await;
+                output.close().await;
+                return;
+            }
+        };
+        info!("Spectator attached to session {}", target.0);
+        let id = session.spectators.add(output);
+        let _ = session.refresh_sender.send(()).await;
+        while let Some(update) = update_rx.recv().await {
+            match update {
+                SshSessionUpdate::WindowResize(width, height) => {
+                    session
+                        .spectators
+                        .resize_spectator(id, Vec2::new(width, height));
+                    let _ = session.refresh_sender.send(()).await;
                 }
+                // Spectators are read-only.
+                SshSessionUpdate::Data(_) => {}
+                SshSessionUpdate::Close => break,
             }
         }
+        session.spectators.remove(id);
+        info!("Spectator detached from session {}", target.0);
     }
 
     async fn handle_session(
-        handle: Handle,
-        channel_id: ChannelId,
-        mut update_rx: Receiver<SshSessionUpdate>,
+        session_channel: SessionChannel,
         handle_id: SessionHandle,
-        key: Option<PublicKey>,
+        waiting_room: WaitingRoom,
+        settings: SessionSettings,
+        sessions: SessionRegistry,
+        detached: DetachedSessions,
+        console: bool,
     ) {
+        // The console is never parked for reattaching, so it can't be handed to an app user.
+        let identity = Identity::of(&session_channel).filter(|_| !console);
+        let SessionChannel {
+            output,
+            mut update_rx,
+            username,
+            key,
+            peer,
+            ..
+        } = session_channel;
         info!("Handling new session {}", handle_id.0);
-        let (mut ssh_side_output, bbs_side_input): (async_std::fs::File, File) = {
-            let (bbs_side, ssh_side, _name) =
-                openpty::openpty(None, None, None).expect("Creating pty failed");
-            (ssh_side.into(), bbs_side)
+        // Operators get in even when the server is full.
+        let slot = if console {
+            SessionSlot::exempt()
+        } else {
+            match waiting_room.admit(&output, &mut update_rx, handle_id).await {
+                Some(slot) => slot,
+                None => {
+                    info!("Session {} ended before it was admitted", handle_id.0);
+                    return;
+                }
+            }
+        };
+        // openpty's error isn't `Send`, so it has to be converted before anything is awaited.
+        let pty = openpty::openpty(None, None, None)
+            .map_err(|err| Error::Pty(io::Error::other(err.to_string())));
+        let (mut ssh_side_output, bbs_side_input): (async_std::fs::File, File) = match pty {
+            Ok((bbs_side, ssh_side, _name)) => (ssh_side.into(), bbs_side),
+            Err(err) => {
+                warn!("Session {} failed to start: {}", handle_id.0, err);
+                output.close().await;
+                return;
+            }
         };
         let (output_sender, mut output_receiver) = channel(100000);
         let (resize_sender, resize_receiver) = channel(100);
         let (exit_tx, exit_rx) = watch::channel(false);
         let (relayout_sender, relayout_receiver) = channel(100);
+        let (ended_tx, mut ended_rx) = watch::channel(false);
+        let detach_grace_period = settings.detach_grace_period;
+        let started = Instant::now();
+        let shared = SessionShared {
+            username: username.clone(),
+            peer,
+            started,
+            console,
+            control: SessionControl::default(),
+            pastes: Pastes::default(),
+            activity: Activity::new(),
+            recorder: SessionRecorder::new(settings.recording.clone(), handle_id),
+            spectators: Spectators::new(),
+            refresh_sender: relayout_sender.clone(),
+        };
+        sessions.lock().unwrap().insert(handle_id, shared.clone());
+        let SessionShared {
+            activity,
+            recorder,
+            spectators,
+            ..
+        } = shared.clone();
+        let session_spectators = spectators.clone();
+        let session_metrics = Arc::new(SessionMetrics::new());
+        audit::record(AuditEvent::SessionStarted {
+            session: handle_id,
+            username,
+            fingerprint: key.as_ref().map(PublicKey::fingerprint),
+        });
+        let output_metrics = session_metrics.clone();
+        if let Some(size) = slot.pending_resize {
+            recorder.resize(size);
+            spectators.resize_player(size);
+            let _ = resize_sender.send(size).await;
+        }
 
+        let relayout_sender_for_reattach = relayout_sender.clone();
         let plugin_manager = PluginManager::new(
             bbs_side_input,
             output_sender,
             resize_receiver,
             relayout_sender,
             relayout_receiver,
+            settings,
+            shared,
         );
 
-        let handle_id = handle_id.clone();
-        let join_handle = std::thread::spawn(move || {
-            debug!("Starting event loop thread for session: {}", handle_id.0);
-            plugin_manager.event_loop(key, handle_id, exit_rx).unwrap();
-            debug!(
-                "Falling out of event loop thread for session: {}",
-                handle_id.0
-            );
+        let span = spans::current();
+        // Joining the thread would block the runtime, so it says when it's done instead. It only
+        // drops the sender without sending if it panics.
+        let (event_loop_done, event_loop_ended) = oneshot::channel();
+        std::thread::spawn(move || {
+            spans::in_scope(&span, || {
+                debug!("Starting event loop thread for session: {}", handle_id.0);
+                // The event loop reports the app's own failures itself; only setting up its
+                // runtime and backend can fail here.
+                if let Err(err) = plugin_manager.event_loop(key, handle_id, exit_rx) {
+                    warn!("Session {} failed to start: {}", handle_id.0, err);
+                }
+                debug!(
+                    "Falling out of event loop thread for session: {}",
+                    handle_id.0
+                );
+            });
+            let _ = event_loop_done.send(());
         });
-        let forwarding_task_handle = spawn(async move {
+        let client = AttachedClient::new(output);
+        let output_client = client.clone();
+        let output_recorder = recorder.clone();
+        let output_spectators = spectators.clone();
+        let refresh_sender = relayout_sender_for_reattach;
+        let output_forwarding = async move {
             debug!(
                 "Entering output forwarding task for session: {}",
                 handle_id.0
             );
             loop {
-                match output_receiver.recv().await {
-                    Some(output) => match output {
-                        crate::ssh::backend::CursiveOutput::Data(data) => {
-                            handle
-                                .data(channel_id, CryptoVec::from_slice(&data))
-                                .await
-                                .unwrap();
+                // The event loop drops its sender without a close event if it fails to start.
+                let output = output_receiver.recv().await.unwrap_or(CursiveOutput::Close);
+                match output {
+                    CursiveOutput::Data(data) => {
+                        output_metrics.output(data.len());
+                        output_recorder.output(&data);
+                        output_spectators.broadcast(&data);
+                        // While detached the session keeps running headless.
+                        if let Some(client) = output_client.get() {
+                            let _ = client.data(&data).await;
                         }
-                        crate::ssh::backend::CursiveOutput::Close => {
-                            debug!(
-                                "Output forwarding task found close event on session: {}",
-                                handle_id.0
-                            );
-                            handle.close(channel_id).await.unwrap();
-                            break;
+                    }
+                    CursiveOutput::Close => {
+                        debug!(
+                            "Output forwarding task found close event on session: {}",
+                            handle_id.0
+                        );
+                        let _ = ended_tx.send(true);
+                        if let Some(client) = output_client.get() {
+                            client.close().await;
                         }
-                    },
-                    None => {
-                        sleep(Duration::from_millis(1)).await;
+                        break;
                     }
                 }
             }
@@ -154,41 +416,99 @@ impl SessionManager {
                 "Falling through output forwarding task for session: {}",
                 handle_id.0
             );
-        });
-        spawn(async move {
+        };
+        // The forwarding tasks belong to this one, so they're aborted if it is.
+        let mut output_forwarding_task = JoinSet::new();
+        output_forwarding_task.spawn(spans::instrument(output_forwarding, spans::current()));
+        let input_forwarding = async move {
             debug!(
                 "Entering input forwarding task for session: {}",
                 handle_id.0
             );
             loop {
-                let update = update_rx.recv().await;
-                if update.is_none() {
-                    sleep(Duration::from_millis(1)).await;
-                    continue;
-                }
-                match update.unwrap() {
+                // The handler drops our sender once the channel or the whole connection goes away.
+                let update = update_rx.recv().await.unwrap_or(SshSessionUpdate::Close);
+                metrics::input_backlog(update_rx.len());
+                match update {
                     SshSessionUpdate::Data(data) => {
-                        ssh_side_output.write_all(&data).await.unwrap();
-                        ssh_side_output.flush().await.unwrap();
+                        session_metrics.input(data.len());
+                        activity.touch();
+                        recorder.input(&data);
+                        let written = match ssh_side_output.write_all(&data).await {
+                            Ok(()) => ssh_side_output.flush().await,
+                            Err(err) => Err(err),
+                        };
+                        if let Err(err) = written {
+                            warn!("Session {} failed: {}", handle_id.0, Error::Pty(err));
+                            break;
+                        }
                     }
                     SshSessionUpdate::WindowResize(width, height) => {
-                        resize_sender.send(Vec2::new(width, height)).await.unwrap();
+                        recorder.resize(Vec2::new(width, height));
+                        spectators.resize_player(Vec2::new(width, height));
+                        // The event loop may already be gone, in which case the session is ending.
+                        let _ = resize_sender.send(Vec2::new(width, height)).await;
                     }
                     SshSessionUpdate::Close => {
                         debug!(
                             "Found close event on input forwarding task for session: {}",
                             handle_id.0
                         );
+                        let (grace_period, identity) = match (detach_grace_period, &identity) {
+                            (Some(grace_period), Some(identity)) if !*ended_rx.borrow() => {
+                                (grace_period, identity)
+                            }
+                            _ => break,
+                        };
+                        client.detach();
+                        info!(
+                            "Session {} detached, keeping it for {:?}",
+                            handle_id.0, grace_period
+                        );
+                        let reattach = detached.park(identity.clone(), handle_id);
+                        let channel = tokio::select! {
+                            channel = reattach => channel.ok(),
+                            _ = sleep(grace_period) => None,
+                            _ = ended_rx.changed() => None,
+                        };
+                        detached.unpark(identity, handle_id);
+                        let channel = match channel {
+                            Some(channel) => channel,
+                            None => break,
+                        };
+                        info!("Reattached client to session {}", handle_id.0);
+                        let modes = format!("{}{}", termion::cursor::Hide, ENABLE_BRACKETED_PASTE);
+                        let _ = channel.output.data(modes.as_bytes()).await;
+                        client.attach(channel.output);
+                        update_rx = channel.update_rx;
+                        // The new client's pty request brings its size, but the screen needs a full
+                        // redraw even if the size didn't change.
+                        let _ = refresh_sender.send(()).await;
                     }
                 }
             }
-        })
-        .await
-        .unwrap();
+        };
+        let mut input_forwarding_task = JoinSet::new();
+        input_forwarding_task.spawn(spans::instrument(input_forwarding, spans::current()));
+        if let Some(Err(err)) = input_forwarding_task.join_next().await {
+            warn!(
+                "Input forwarding task for session {} failed: {}",
+                handle_id.0, err
+            )
+        }
         debug!("Fell through input forwarding task, indicating disconnection on session {}. Aborting/joining other tasks/threads.", handle_id.0);
         let _ = exit_tx.send(true);
-        forwarding_task_handle.abort();
-        join_handle.join().expect("Failed to join thread");
+        output_forwarding_task.abort_all();
+        if event_loop_ended.await.is_err() {
+            warn!("Event loop thread for session {} panicked", handle_id.0);
+        }
+        sessions.lock().unwrap().remove(&handle_id);
+        session_spectators.close_all();
+        drop(slot);
+        audit::record(AuditEvent::SessionEnded {
+            session: handle_id,
+            duration: started.elapsed(),
+        });
         info!("Cleaned up from disconnected session: {}", handle_id.0);
     }
 }
@@ -0,0 +1,85 @@
+//! `tracing` spans for connections and sessions. The `log` lines emitted while a span is entered
+//! are attached to it once they're forwarded to `tracing`, for example by `tracing-log`. Without
+//! the `tracing` feature every span is a zero-sized placeholder and nothing here has any effect.
+
+use std::fmt::Display;
+use std::future::Future;
+
+use crate::SessionHandle;
+
+#[cfg(feature = "tracing")]
+pub(crate) type Span = tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone, Debug)]
+pub(crate) struct Span;
+
+/// The span of a client connection. `frontend` is `ssh`, `telnet`, `raw` or `websocket`. The
+/// username is filled in with [`record_username`] once it's known.
+#[cfg(feature = "tracing")]
+pub(crate) fn connection(frontend: &'static str, peer: &dyn Display) -> Span {
+    tracing::info_span!(
+        "connection",
+        frontend,
+        peer = %peer,
+        username = tracing::field::Empty
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn connection(_frontend: &'static str, _peer: &dyn Display) -> Span {
+    Span
+}
+
+/// The span of a session, as a child of the connection that started it.
+#[cfg(feature = "tracing")]
+pub(crate) fn session(connection: &Span, handle: SessionHandle) -> Span {
+    tracing::info_span!(parent: connection, "session", handle = handle.0)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn session(_connection: &Span, _handle: SessionHandle) -> Span {
+    Span
+}
+
+/// The span entered by the current task or thread, if any.
+#[cfg(feature = "tracing")]
+pub(crate) fn current() -> Span {
+    Span::current()
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn current() -> Span {
+    Span
+}
+
+/// Records the username on a connection's span.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_username(connection: &Span, username: &str) {
+    connection.record("username", username);
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_username(_connection: &Span, _username: &str) {}
+
+/// Enters `span` every time `future` is polled, so the tasks it's spawned on stay in the span.
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<F: Future>(future: F, span: Span) -> impl Future<Output = F::Output> {
+    tracing::Instrument::instrument(future, span)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument<F: Future>(future: F, _span: Span) -> impl Future<Output = F::Output> {
+    future
+}
+
+/// Runs `f` inside `span`, for code that runs on its own thread.
+#[cfg(feature = "tracing")]
+pub(crate) fn in_scope<R>(span: &Span, f: impl FnOnce() -> R) -> R {
+    span.in_scope(f)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn in_scope<R>(_span: &Span, f: impl FnOnce() -> R) -> R {
+    f()
+}
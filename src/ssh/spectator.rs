@@ -0,0 +1,321 @@
+use std::sync::{Arc, Mutex};
+
+use crate::cursive::backends::termion::termion;
+use crate::cursive::Vec2;
+
+use log::info;
+use tokio::spawn;
+use tokio::sync::mpsc::{channel, error::TrySendError, Sender};
+use tokio::task::JoinHandle;
+use unicode_width::UnicodeWidthChar;
+
+use super::client::ClientOutput;
+use super::spans;
+
+/// How many chunks of output may wait for a spectator before it's disconnected for falling behind.
+const SPECTATOR_BACKLOG: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParseState {
+    Ground,
+    Escape,
+    Csi,
+    Osc,
+    OscEscape,
+}
+
+/// Rewrites the backend's output so a screen drawn at the player's size fits a spectator's
+/// terminal. Smaller terminals see the top-left corner of the screen, larger ones see it centered.
+pub(crate) struct Viewport {
+    player: Vec2,
+    spectator: Vec2,
+    cursor: Vec2,
+    state: ParseState,
+    params: String,
+}
+
+impl Viewport {
+    pub fn new(player: Vec2, spectator: Vec2) -> Self {
+        Self {
+            player,
+            spectator,
+            cursor: Vec2::zero(),
+            state: ParseState::Ground,
+            params: String::new(),
+        }
+    }
+
+    fn offset(&self) -> Vec2 {
+        Vec2::new(
+            self.spectator.x.saturating_sub(self.player.x) / 2,
+            self.spectator.y.saturating_sub(self.player.y) / 2,
+        )
+    }
+
+    pub fn translate(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = String::with_capacity(data.len());
+        for c in String::from_utf8_lossy(data).chars() {
+            match self.state {
+                ParseState::Ground => match c {
+                    '\x1b' => self.state = ParseState::Escape,
+                    c if c.is_control() => out.push(c),
+                    c => self.print(c, &mut out),
+                },
+                ParseState::Escape => match c {
+                    '[' => {
+                        self.params.clear();
+                        self.state = ParseState::Csi;
+                    }
+                    ']' => {
+                        out.push_str("\x1b]");
+                        self.state = ParseState::Osc;
+                    }
+                    c => {
+                        out.push('\x1b');
+                        out.push(c);
+                        self.state = ParseState::Ground;
+                    }
+                },
+                ParseState::Csi => {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        self.csi(c, &mut out);
+                        self.state = ParseState::Ground;
+                    } else {
+                        self.params.push(c);
+                    }
+                }
+                ParseState::Osc => {
+                    out.push(c);
+                    match c {
+                        '\x07' => self.state = ParseState::Ground,
+                        '\x1b' => self.state = ParseState::OscEscape,
+                        _ => {}
+                    }
+                }
+                ParseState::OscEscape => {
+                    out.push(c);
+                    self.state = ParseState::Ground;
+                }
+            }
+        }
+        out.into_bytes()
+    }
+
+    fn print(&mut self, c: char, out: &mut String) {
+        let width = c.width().unwrap_or(0);
+        let offset = self.offset();
+        let x = self.cursor.x + offset.x;
+        let y = self.cursor.y + offset.y;
+        // Once a character falls off the right or bottom edge, everything after it does too until
+        // the backend moves the cursor again, so skipping is enough to crop.
+        if y < self.spectator.y && x < self.spectator.x {
+            if x + width <= self.spectator.x {
+                out.push(c);
+            } else {
+                // A wide character straddling the right edge would wrap, so blank the cell.
+                out.push(' ');
+            }
+        }
+        self.cursor.x += width;
+    }
+
+    fn csi(&mut self, final_byte: char, out: &mut String) {
+        if final_byte == 'H' || final_byte == 'f' {
+            let mut parts = self
+                .params
+                .split(';')
+                .map(|p| p.parse::<usize>().unwrap_or(1));
+            let row = parts.next().unwrap_or(1).max(1);
+            let col = parts.next().unwrap_or(1).max(1);
+            self.cursor = Vec2::new(col - 1, row - 1);
+            self.goto(out);
+        } else {
+            out.push_str("\x1b[");
+            out.push_str(&self.params);
+            out.push(final_byte);
+        }
+    }
+
+    fn goto(&self, out: &mut String) {
+        let offset = self.offset();
+        let x = (self.cursor.x + offset.x).min(self.spectator.x.saturating_sub(1));
+        let y = (self.cursor.y + offset.y).min(self.spectator.y.saturating_sub(1));
+        out.push_str(&format!(
+            "{}",
+            termion::cursor::Goto(1 + x as u16, 1 + y as u16)
+        ));
+    }
+}
+
+struct Spectator {
+    id: u64,
+    client: ClientOutput,
+    /// Feeds the task writing to the spectator, so a slow one never holds up the player.
+    queue: Sender<Vec<u8>>,
+    writer: JoinHandle<()>,
+    size: Vec2,
+    viewport: Viewport,
+    needs_clear: bool,
+}
+
+struct SpectatorsState {
+    player_size: Vec2,
+    next_id: u64,
+    spectators: Vec<Spectator>,
+}
+
+/// The read-only viewers attached to a session.
+#[derive(Clone)]
+pub(crate) struct Spectators(Arc<Mutex<SpectatorsState>>);
+
+impl Spectators {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(SpectatorsState {
+            player_size: Vec2::new(80, 24),
+            next_id: 0,
+            spectators: Vec::new(),
+        })))
+    }
+
+    pub fn add(&self, client: ClientOutput) -> u64 {
+        let mut state = self.0.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let size = Vec2::new(80, 24);
+        let viewport = Viewport::new(state.player_size, size);
+        let (queue, mut queued) = channel::<Vec<u8>>(SPECTATOR_BACKLOG);
+        let writer_client = client.clone();
+        let writer = async move {
+            while let Some(data) = queued.recv().await {
+                if writer_client.data(&data).await.is_err() {
+                    return;
+                }
+            }
+            // The spectator was let go of, once everything queued for it was written.
+            writer_client.close().await;
+        };
+        let writer = spawn(spans::instrument(writer, spans::current()));
+        state.spectators.push(Spectator {
+            id,
+            client,
+            queue,
+            writer,
+            size,
+            viewport,
+            needs_clear: true,
+        });
+        id
+    }
+
+    /// Detaches a spectator that went away by itself.
+    pub fn remove(&self, id: u64) {
+        let mut state = self.0.lock().unwrap();
+        for spectator in state.spectators.iter().filter(|s| s.id == id) {
+            spectator.writer.abort();
+        }
+        state.spectators.retain(|s| s.id != id);
+    }
+
+    /// Detaches every spectator. Their channels are closed once what's queued for them is written.
+    pub fn close_all(&self) {
+        self.0.lock().unwrap().spectators.clear();
+    }
+
+    pub fn resize_spectator(&self, id: u64, size: Vec2) {
+        let mut state = self.0.lock().unwrap();
+        let player_size = state.player_size;
+        if let Some(spectator) = state.spectators.iter_mut().find(|s| s.id == id) {
+            spectator.size = size;
+            spectator.viewport = Viewport::new(player_size, size);
+            spectator.needs_clear = true;
+        }
+    }
+
+    /// The size of the player's terminal, which spectators see a viewport of.
+    pub fn player_size(&self) -> Vec2 {
+        self.0.lock().unwrap().player_size
+    }
+
+    pub fn resize_player(&self, size: Vec2) {
+        let mut state = self.0.lock().unwrap();
+        state.player_size = size;
+        for spectator in state.spectators.iter_mut() {
+            spectator.viewport = Viewport::new(size, spectator.size);
+            spectator.needs_clear = true;
+        }
+    }
+
+    /// Translates a chunk of the player's output and queues it for every spectator, without
+    /// waiting on any of them. Spectators that have fallen [`SPECTATOR_BACKLOG`] chunks behind are
+    /// disconnected, and so are those that went away.
+    pub fn broadcast(&self, data: &[u8]) {
+        let mut state = self.0.lock().unwrap();
+        state.spectators.retain_mut(|spectator| {
+            let mut out = Vec::new();
+            if spectator.needs_clear {
+                spectator.needs_clear = false;
+                out.extend(format!("{}{}", termion::cursor::Hide, termion::clear::All).as_bytes());
+            }
+            out.extend(spectator.viewport.translate(data));
+            match spectator.queue.try_send(out) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    info!(
+                        "Disconnecting spectator {}, it fell too far behind",
+                        spectator.id
+                    );
+                    spectator.writer.abort();
+                    let client = spectator.client.clone();
+                    spawn(async move { client.close().await });
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::super::client::FrontendOutput;
+    use super::*;
+
+    #[tokio::test]
+    async fn stalled_spectators_are_dropped_without_blocking() {
+        let spectators = Spectators::new();
+        let (stalled, mut stalled_output) = channel(1);
+        let (reading, mut reading_output) = channel(SPECTATOR_BACKLOG * 2);
+        spectators.add(ClientOutput::Frontend(stalled));
+        spectators.add(ClientOutput::Frontend(reading));
+        for _ in 0..SPECTATOR_BACKLOG * 2 {
+            spectators.broadcast(b"x");
+            // Lets the writers run, as they would next to the session on a busy runtime.
+            tokio::task::yield_now().await;
+        }
+        let remaining: Vec<u64> = spectators
+            .0
+            .lock()
+            .unwrap()
+            .spectators
+            .iter()
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(remaining, [1]);
+
+        // The stalled spectator is closed once it reads again.
+        assert!(matches!(
+            stalled_output.recv().await,
+            Some(FrontendOutput::Data(_))
+        ));
+        assert!(matches!(
+            stalled_output.recv().await,
+            Some(FrontendOutput::Close)
+        ));
+        assert!(matches!(
+            reading_output.recv().await,
+            Some(FrontendOutput::Data(_))
+        ));
+    }
+}
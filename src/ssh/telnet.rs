@@ -0,0 +1,529 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use log::{debug, info, trace};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinSet;
+
+use super::audit::{self, AuditEvent};
+use super::client::{ClientOutput, FrontendOutput};
+use super::limits::ConnectionLimiter;
+use super::listener::back_off_after;
+use super::metrics;
+use super::session_manager::{SessionChannel, SessionRepoUpdate, SshSessionUpdate};
+use super::spans;
+use crate::Error;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const BINARY: u8 = 0;
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const NAWS: u8 = 31;
+
+/// The longest subnegotiation kept, which is plenty for a window size. Longer
+/// ones are dropped, so a client can't grow the buffer by never ending one.
+const MAX_SUBNEGOTIATION: usize = 64;
+
+/// Settings for serving the app over telnet next to ssh.
+///
+/// Telnet has no encryption and no authentication, so every telnet client gets the same username
+/// and no public key, and can't reattach to a detached session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TelnetConfig {
+    /// Address the telnet listener binds.
+    pub listen: SocketAddr,
+    /// Unless this is set, the listener refuses to bind anything but a loopback address, so it can
+    /// only be reached through a TLS-terminating proxy on the same machine.
+    pub allow_remote: bool,
+    /// Username given to sessions started over telnet.
+    pub username: String,
+}
+
+impl TelnetConfig {
+    /// Listens on `port` of the IPv4 loopback address, for use behind a TLS-terminating proxy.
+    pub fn loopback(port: u16) -> Self {
+        Self {
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            allow_remote: false,
+            username: "telnet".to_string(),
+        }
+    }
+
+    /// Listens on `listen`, which may be reachable from other machines over plaintext.
+    pub fn remote(listen: SocketAddr) -> Self {
+        Self {
+            listen,
+            allow_remote: true,
+            username: "telnet".to_string(),
+        }
+    }
+
+    pub(crate) async fn bind(&self) -> Result<TcpListener, Error> {
+        let bound = if !self.allow_remote && !self.listen.ip().is_loopback() {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "refusing to serve plaintext telnet on a non-loopback address",
+            ))
+        } else {
+            TcpListener::bind(self.listen).await
+        };
+        bound.map_err(|source| Error::Bind {
+            addr: self.listen.to_string(),
+            source,
+        })
+    }
+}
+
+/// Accepts telnet connections and hands each one to the session manager as a new session.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    config: TelnetConfig,
+    limiter: ConnectionLimiter,
+    session_sender: Sender<SessionRepoUpdate>,
+) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Listening for telnet on {}", addr);
+    }
+    // Connections belong to the frontend's task, so they're dropped along with it.
+    let mut connections = JoinSet::new();
+    loop {
+        let (socket, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!("Failed to accept telnet connection: {}", err);
+                back_off_after(&err).await;
+                continue;
+            }
+        };
+        // Forget the connections that have ended.
+        while connections.try_join_next().is_some() {}
+        let permit = match limiter.try_admit(peer_addr.ip()) {
+            Ok(permit) => permit,
+            Err(refusal) => {
+                info!(
+                    "Refusing telnet connection from {}: {:?}",
+                    peer_addr, refusal
+                );
+                audit::record(AuditEvent::ConnectionRefused {
+                    frontend: "telnet",
+                    peer: Some(peer_addr),
+                    reason: refusal.reason(),
+                });
+                continue;
+            }
+        };
+        metrics::connection_accepted("telnet");
+        audit::record(AuditEvent::ConnectionOpened {
+            frontend: "telnet",
+            peer: Some(peer_addr),
+        });
+        trace!("New telnet client for peer {:?}", peer_addr);
+        let username = config.username.clone();
+        let session_sender = session_sender.clone();
+        let span = spans::connection("telnet", &peer_addr);
+        spans::record_username(&span, &username);
+        let connection = async move {
+            handle_connection(socket, username, peer_addr, session_sender).await;
+            debug!("Telnet connection from {} closed", peer_addr);
+            drop(permit);
+        };
+        connections.spawn(spans::instrument(connection, span));
+    }
+}
+
+async fn handle_connection(
+    socket: TcpStream,
+    username: String,
+    peer_addr: SocketAddr,
+    session_sender: Sender<SessionRepoUpdate>,
+) {
+    let (mut reader, writer) = socket.into_split();
+    let (output_sender, output_receiver) = channel(100);
+    let (reply_sender, reply_receiver) = channel(10);
+    let (update_sender, update_rx) = channel(100);
+    // Owned by the connection's task, so the writer and its half of the socket go with it.
+    let mut writer_task = JoinSet::new();
+    writer_task.spawn(write_output(writer, output_receiver, reply_receiver));
+
+    // Clients that never report their window size still need something to lay out against.
+    let _ = update_sender
+        .send(SshSessionUpdate::WindowResize(80, 24))
+        .await;
+    let channel = SessionChannel {
+        output: ClientOutput::Frontend(output_sender),
+        update_rx,
+        username,
+        key: None,
+        peer: Some(peer_addr),
+        console: false,
+        span: spans::current(),
+    };
+    if session_sender
+        .send(SessionRepoUpdate::NewSession(channel))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let mut parser = Parser::default();
+    let mut buf = [0u8; 4096];
+    loop {
+        let read = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        for event in parser.feed(&buf[..read]) {
+            let update = match event {
+                TelnetEvent::Data(data) => SshSessionUpdate::Data(data),
+                TelnetEvent::WindowSize(width, height) => {
+                    SshSessionUpdate::WindowResize(width, height)
+                }
+                TelnetEvent::Reply(reply) => {
+                    let _ = reply_sender.send(reply).await;
+                    continue;
+                }
+            };
+            if update_sender.send(update).await.is_err() {
+                debug!("Session for telnet connection has already ended");
+            }
+        }
+    }
+    let _ = update_sender.send(SshSessionUpdate::Close).await;
+    // Lets the writer finish sending what the session had left to say.
+    let _ = writer_task.join_next().await;
+}
+
+/// Writes the session's output, escaped for telnet, along with the parser's negotiation replies.
+async fn write_output(
+    mut writer: OwnedWriteHalf,
+    mut output_receiver: Receiver<FrontendOutput>,
+    mut reply_receiver: Receiver<Vec<u8>>,
+) {
+    // Echo and go-ahead suppression put the client in character mode, and binary mode keeps
+    // UTF-8 intact in both directions. The terminal type isn't asked for, since the backend writes
+    // the same xterm sequences to every client, as it does for ssh whatever its pty request says.
+    let negotiation = [
+        [IAC, WILL, ECHO],
+        [IAC, WILL, SUPPRESS_GO_AHEAD],
+        [IAC, WILL, BINARY],
+        [IAC, DO, BINARY],
+        [IAC, DO, NAWS],
+    ]
+    .concat();
+    if writer.write_all(&negotiation).await.is_err() {
+        return;
+    }
+    loop {
+        let bytes = tokio::select! {
+            output = output_receiver.recv() => match output {
+                Some(FrontendOutput::Data(data)) => escape(&data),
+                Some(FrontendOutput::Close) | None => break,
+            },
+            Some(reply) = reply_receiver.recv() => reply,
+        };
+        if writer.write_all(&bytes).await.is_err() {
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Doubles every IAC byte so the client reads it as data.
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        escaped.push(byte);
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+    }
+    escaped
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TelnetEvent {
+    Data(Vec<u8>),
+    WindowSize(usize, usize),
+    /// Bytes to send back to the client.
+    Reply(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum ParseState {
+    #[default]
+    Data,
+    /// After a carriage return, which clients follow with a NUL or line feed to be dropped.
+    Return,
+    Iac,
+    Negotiate(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Splits the client's byte stream into data and telnet commands.
+#[derive(Default)]
+struct Parser {
+    state: ParseState,
+    subnegotiation: Vec<u8>,
+    /// Whether the current subnegotiation outgrew [`MAX_SUBNEGOTIATION`].
+    overlong: bool,
+}
+
+impl Parser {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<TelnetEvent> {
+        let mut events = Vec::new();
+        let mut data = Vec::new();
+        for &byte in bytes {
+            match self.state {
+                ParseState::Data | ParseState::Return => {
+                    let after_return = self.state == ParseState::Return;
+                    self.state = ParseState::Data;
+                    match byte {
+                        IAC => self.state = ParseState::Iac,
+                        0 | b'\n' if after_return => {}
+                        b'\r' => {
+                            data.push(byte);
+                            self.state = ParseState::Return;
+                        }
+                        _ => data.push(byte),
+                    }
+                }
+                ParseState::Iac => {
+                    self.state = ParseState::Data;
+                    match byte {
+                        IAC => data.push(IAC),
+                        WILL | WONT | DO | DONT => self.state = ParseState::Negotiate(byte),
+                        SB => {
+                            self.subnegotiation.clear();
+                            self.overlong = false;
+                            self.state = ParseState::Subnegotiation;
+                        }
+                        // Go-aheads, interrupts and the like don't mean anything to a session.
+                        _ => {}
+                    }
+                }
+                ParseState::Negotiate(command) => {
+                    self.state = ParseState::Data;
+                    if let Some(reply) = negotiate(command, byte) {
+                        events.push(TelnetEvent::Reply(reply));
+                    }
+                }
+                ParseState::Subnegotiation => match byte {
+                    IAC => self.state = ParseState::SubnegotiationIac,
+                    _ => self.push_subnegotiation(byte),
+                },
+                ParseState::SubnegotiationIac => match byte {
+                    IAC => {
+                        self.push_subnegotiation(IAC);
+                        self.state = ParseState::Subnegotiation;
+                    }
+                    SE => {
+                        self.state = ParseState::Data;
+                        if !data.is_empty() {
+                            events.push(TelnetEvent::Data(std::mem::take(&mut data)));
+                        }
+                        events.extend(self.subnegotiation());
+                    }
+                    _ => self.state = ParseState::Subnegotiation,
+                },
+            }
+        }
+        if !data.is_empty() {
+            events.push(TelnetEvent::Data(data));
+        }
+        events
+    }
+
+    fn push_subnegotiation(&mut self, byte: u8) {
+        if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+            self.subnegotiation.push(byte);
+        } else {
+            self.overlong = true;
+        }
+    }
+
+    fn subnegotiation(&self) -> Option<TelnetEvent> {
+        if self.overlong {
+            debug!("Ignoring an overlong telnet subnegotiation");
+            return None;
+        }
+        match self.subnegotiation.as_slice() {
+            [NAWS, w1, w2, h1, h2] => {
+                let width = u16::from_be_bytes([*w1, *w2]) as usize;
+                let height = u16::from_be_bytes([*h1, *h2]) as usize;
+                // Some clients report zero before they know their size.
+                (width > 0 && height > 0).then_some(TelnetEvent::WindowSize(width, height))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Answers the client's side of an option negotiation. Options we asked for up front are taken as
+/// agreed, and everything else is refused.
+fn negotiate(command: u8, option: u8) -> Option<Vec<u8>> {
+    match (command, option) {
+        (WILL, NAWS | BINARY) | (DO, ECHO | SUPPRESS_GO_AHEAD | BINARY) => None,
+        (WILL, _) => Some(vec![IAC, DONT, option]),
+        (DO, _) => Some(vec![IAC, WONT, option]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::spawn;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::ConnectionLimits;
+
+    #[test]
+    fn reads_the_window_size() {
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.feed(&[IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE]),
+            [TelnetEvent::WindowSize(80, 24)]
+        );
+        // A dimension of 255 arrives escaped.
+        assert_eq!(
+            parser.feed(&[IAC, SB, NAWS, 1, IAC, IAC, 0, 50, IAC, SE]),
+            [TelnetEvent::WindowSize(511, 50)]
+        );
+        assert_eq!(parser.feed(&[IAC, SB, NAWS, 0, 0, 0, 0, IAC, SE]), []);
+    }
+
+    #[test]
+    fn subnegotiations_can_arrive_in_pieces() {
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.feed(&[b'a', IAC, SB, NAWS, 0]),
+            [TelnetEvent::Data(b"a".to_vec())]
+        );
+        assert_eq!(parser.feed(&[100, 0, 30, IAC]), []);
+        assert_eq!(
+            parser.feed(&[SE, b'b']),
+            [
+                TelnetEvent::WindowSize(100, 30),
+                TelnetEvent::Data(b"b".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_iac_is_data() {
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.feed(&[b'a', IAC, IAC, b'b']),
+            [TelnetEvent::Data(vec![b'a', IAC, b'b'])]
+        );
+    }
+
+    #[test]
+    fn line_endings_lose_their_padding() {
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.feed(b"a\r\0b\r\nc"),
+            [TelnetEvent::Data(b"a\rb\rc".to_vec())]
+        );
+    }
+
+    #[test]
+    fn overlong_subnegotiations_are_dropped() {
+        let mut parser = Parser::default();
+        let mut bytes = vec![IAC, SB, NAWS];
+        bytes.extend([b'x'; MAX_SUBNEGOTIATION * 2]);
+        bytes.extend([IAC, SE, b'a']);
+        assert_eq!(parser.feed(&bytes), [TelnetEvent::Data(b"a".to_vec())]);
+        assert_eq!(
+            parser.feed(&[IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE]),
+            [TelnetEvent::WindowSize(80, 24)]
+        );
+    }
+
+    #[test]
+    fn unterminated_subnegotiations_stay_bounded() {
+        let mut parser = Parser::default();
+        assert_eq!(parser.feed(&[IAC, SB, NAWS]), []);
+        for _ in 0..1000 {
+            assert_eq!(parser.feed(&[0; 1024]), []);
+        }
+        assert_eq!(parser.subnegotiation.len(), MAX_SUBNEGOTIATION);
+    }
+
+    #[test]
+    fn refuses_options_it_didnt_ask_for() {
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.feed(&[IAC, WILL, 24, IAC, WILL, NAWS, IAC, DO, 99]),
+            [
+                TelnetEvent::Reply(vec![IAC, DONT, 24]),
+                TelnetEvent::Reply(vec![IAC, WONT, 99])
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_apply_to_loopback_peers() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limiter = ConnectionLimiter::new(ConnectionLimits {
+            max_connections_per_ip: Some(1),
+            ..ConnectionLimits::default()
+        });
+        let (session_sender, mut session_receiver) = channel(10);
+        let server = spawn(serve(
+            listener,
+            TelnetConfig::loopback(0),
+            limiter,
+            session_sender,
+        ));
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let _session = session_receiver.recv().await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(second.read(&mut buf).await.unwrap(), 0);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn connections_close_when_the_server_is_dropped() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limiter = ConnectionLimiter::new(ConnectionLimits::default());
+        let (session_sender, mut session_receiver) = channel(10);
+        let server = spawn(serve(
+            listener,
+            TelnetConfig::loopback(0),
+            limiter,
+            session_sender,
+        ));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        // Holding on to the session leaves the server going away as the only reason to close.
+        let _session = session_receiver.recv().await.unwrap();
+        server.abort();
+        let mut buf = [0; 64];
+        // Skips the option negotiation sent on connect.
+        while timeout(Duration::from_secs(10), client.read(&mut buf))
+            .await
+            .expect("the connection outlived the server")
+            .unwrap()
+            > 0
+        {}
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limits on how long a session may sit idle or run in total.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionTimeouts {
+    /// End the session after this long without any input from the client.
+    pub idle_timeout: Option<Duration>,
+    /// Show an on-screen countdown for this long before the idle timeout ends the session.
+    pub idle_warning: Option<Duration>,
+    /// End the session after this long regardless of activity.
+    pub max_duration: Option<Duration>,
+}
+
+/// Why a session was ended by the server rather than by the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Expiry {
+    /// No input arrived for the configured idle timeout.
+    Idle,
+    /// The session reached its maximum duration.
+    MaxDuration,
+}
+
+/// Shared record of when the client last sent input.
+#[derive(Clone)]
+pub(crate) struct Activity(Arc<Mutex<Instant>>);
+
+impl Activity {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn touch(&self) {
+        self.touch_at(Instant::now());
+    }
+
+    fn touch_at(&self, now: Instant) {
+        *self.0.lock().unwrap() = now;
+    }
+
+    fn idle_at(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(*self.0.lock().unwrap())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ClockState {
+    Running,
+    /// The session will expire for being idle after the contained duration.
+    Warning(Duration),
+    Expired(Expiry),
+}
+
+/// Decides when a session has run out of time.
+pub(crate) struct SessionClock {
+    timeouts: SessionTimeouts,
+    started: Instant,
+    activity: Activity,
+}
+
+impl SessionClock {
+    pub fn new(timeouts: SessionTimeouts, activity: Activity) -> Self {
+        Self {
+            timeouts,
+            started: Instant::now(),
+            activity,
+        }
+    }
+
+    pub fn state(&self) -> ClockState {
+        self.state_at(Instant::now())
+    }
+
+    fn state_at(&self, now: Instant) -> ClockState {
+        if let Some(max) = self.timeouts.max_duration {
+            if now.saturating_duration_since(self.started) >= max {
+                return ClockState::Expired(Expiry::MaxDuration);
+            }
+        }
+        if let Some(timeout) = self.timeouts.idle_timeout {
+            let idle = self.activity.idle_at(now);
+            if idle >= timeout {
+                return ClockState::Expired(Expiry::Idle);
+            }
+            let remaining = timeout - idle;
+            if let Some(warning) = self.timeouts.idle_warning {
+                if remaining <= warning {
+                    return ClockState::Warning(remaining);
+                }
+            }
+        }
+        ClockState::Running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    /// A clock for `timeouts` along with the instant it started, which is also when the client
+    /// last sent input.
+    fn clock(timeouts: SessionTimeouts) -> (SessionClock, Activity, Instant) {
+        let activity = Activity::new();
+        let clock = SessionClock::new(timeouts, activity.clone());
+        activity.touch_at(clock.started);
+        let started = clock.started;
+        (clock, activity, started)
+    }
+
+    #[test]
+    fn sessions_without_timeouts_run_forever() {
+        let (clock, _, started) = clock(SessionTimeouts::default());
+        assert_eq!(clock.state_at(started + 1000 * MINUTE), ClockState::Running);
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let (clock, _, started) = clock(SessionTimeouts {
+            idle_timeout: Some(10 * MINUTE),
+            ..Default::default()
+        });
+        let almost = started + 10 * MINUTE - Duration::from_secs(1);
+        assert_eq!(clock.state_at(almost), ClockState::Running);
+        assert_eq!(
+            clock.state_at(started + 10 * MINUTE),
+            ClockState::Expired(Expiry::Idle)
+        );
+    }
+
+    #[test]
+    fn idle_sessions_are_warned_before_they_expire() {
+        let (clock, _, started) = clock(SessionTimeouts {
+            idle_timeout: Some(10 * MINUTE),
+            idle_warning: Some(MINUTE),
+            ..Default::default()
+        });
+        assert_eq!(clock.state_at(started + 8 * MINUTE), ClockState::Running);
+        assert_eq!(
+            clock.state_at(started + 9 * MINUTE + Duration::from_secs(30)),
+            ClockState::Warning(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn input_restarts_the_idle_timer() {
+        let (clock, activity, started) = clock(SessionTimeouts {
+            idle_timeout: Some(10 * MINUTE),
+            idle_warning: Some(MINUTE),
+            ..Default::default()
+        });
+        let warned = started + 9 * MINUTE + Duration::from_secs(30);
+        assert_ne!(clock.state_at(warned), ClockState::Running);
+        activity.touch_at(warned);
+        assert_eq!(clock.state_at(warned), ClockState::Running);
+        assert_eq!(clock.state_at(started + 15 * MINUTE), ClockState::Running);
+        assert_eq!(
+            clock.state_at(warned + 10 * MINUTE),
+            ClockState::Expired(Expiry::Idle)
+        );
+    }
+
+    #[test]
+    fn sessions_expire_at_their_max_duration_however_active() {
+        let (clock, activity, started) = clock(SessionTimeouts {
+            idle_timeout: Some(10 * MINUTE),
+            max_duration: Some(60 * MINUTE),
+            ..Default::default()
+        });
+        activity.touch_at(started + 59 * MINUTE);
+        assert_eq!(clock.state_at(started + 59 * MINUTE), ClockState::Running);
+        assert_eq!(
+            clock.state_at(started + 60 * MINUTE),
+            ClockState::Expired(Expiry::MaxDuration)
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cursive::backends::termion::termion;
+use crate::cursive::Vec2;
+use crate::SessionHandle;
+
+use log::{debug, info};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::interval;
+
+use super::client::ClientOutput;
+use super::session_manager::SshSessionUpdate;
+
+/// What happens to a connection that arrives while every session slot is taken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionOverflow {
+    /// Print the message and close the channel.
+    Reject(String),
+    /// Park the connection on a built-in waiting screen until a slot frees up. Connections are
+    /// admitted in the order they arrived.
+    Queue,
+}
+
+/// Caps the number of sessions that may run at once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionLimits {
+    /// Maximum number of concurrently running sessions, or `None` for no limit.
+    pub max_sessions: Option<usize>,
+    /// Policy for connections over the limit.
+    pub overflow: SessionOverflow,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_sessions: None,
+            overflow: SessionOverflow::Queue,
+        }
+    }
+}
+
+/// A claimed session slot. The slot is released when this is dropped.
+pub(crate) struct SessionSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+    /// The most recent window size the client reported while it was waiting, if any.
+    pub pending_resize: Option<Vec2>,
+}
+
+impl SessionSlot {
+    /// A slot outside the limits, for sessions that mustn't wait.
+    pub fn exempt() -> Self {
+        Self {
+            _permit: None,
+            pending_resize: None,
+        }
+    }
+}
+
+/// Hands out session slots and keeps track of connections waiting for one.
+#[derive(Clone)]
+pub(crate) struct WaitingRoom {
+    overflow: SessionOverflow,
+    slots: Option<Arc<Semaphore>>,
+    queue: Arc<Mutex<VecDeque<SessionHandle>>>,
+}
+
+impl WaitingRoom {
+    pub fn new(limits: SessionLimits) -> Self {
+        Self {
+            overflow: limits.overflow,
+            slots: limits.max_sessions.map(|max| Arc::new(Semaphore::new(max))),
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Waits for a free session slot on behalf of the client. Returns `None` if the client was
+    /// turned away or hung up before a slot became available.
+    pub async fn admit(
+        &self,
+        client: &ClientOutput,
+        update_rx: &mut Receiver<SshSessionUpdate>,
+        handle_id: SessionHandle,
+    ) -> Option<SessionSlot> {
+        let slots = match &self.slots {
+            Some(slots) => slots.clone(),
+            None => {
+                return Some(SessionSlot {
+                    _permit: None,
+                    pending_resize: None,
+                })
+            }
+        };
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Some(SessionSlot {
+                _permit: Some(permit),
+                pending_resize: None,
+            });
+        }
+
+        match &self.overflow {
+            SessionOverflow::Reject(message) => {
+                info!("Rejecting session {}, server is full", handle_id.0);
+                let text = format!("{}\r\n", message.replace('\n', "\r\n"));
+                let _ = client.data(text.as_bytes()).await;
+                client.close().await;
+                None
+            }
+            SessionOverflow::Queue => {
+                info!("Session {} is waiting for a free slot", handle_id.0);
+                self.queue.lock().unwrap().push_back(handle_id);
+                let slot = self.wait_in_line(slots, client, update_rx, handle_id).await;
+                self.queue.lock().unwrap().retain(|h| *h != handle_id);
+                if slot.is_none() {
+                    client.close().await;
+                }
+                slot
+            }
+        }
+    }
+
+    async fn wait_in_line(
+        &self,
+        slots: Arc<Semaphore>,
+        client: &ClientOutput,
+        update_rx: &mut Receiver<SshSessionUpdate>,
+        handle_id: SessionHandle,
+    ) -> Option<SessionSlot> {
+        let mut size = None;
+        let mut redraw = interval(Duration::from_secs(1));
+        let acquire = slots.acquire_owned();
+        tokio::pin!(acquire);
+        loop {
+            tokio::select! {
+                permit = &mut acquire => {
+                    debug!("Session {} left the waiting room", handle_id.0);
+                    let _ = client.data(reset_screen().as_bytes()).await;
+                    return permit.ok().map(|permit| SessionSlot {
+                        _permit: Some(permit),
+                        pending_resize: size,
+                    });
+                }
+                update = update_rx.recv() => match update {
+                    Some(SshSessionUpdate::WindowResize(width, height)) => {
+                        size = Some(Vec2::new(width, height));
+                        self.draw(client, handle_id, size).await;
+                    }
+                    Some(SshSessionUpdate::Data(data)) => {
+                        // `q`, Ctrl-C and Ctrl-D let the user give up their spot.
+                        if data.iter().any(|b| matches!(b, b'q' | b'Q' | 3 | 4)) {
+                            let _ = client.data(reset_screen().as_bytes()).await;
+                            return None;
+                        }
+                    }
+                    Some(SshSessionUpdate::Close) | None => return None,
+                },
+                _ = redraw.tick() => {
+                    self.draw(client, handle_id, size).await;
+                }
+            }
+        }
+    }
+
+    fn position(&self, handle_id: SessionHandle) -> usize {
+        let queue = self.queue.lock().unwrap();
+        queue
+            .iter()
+            .position(|h| *h == handle_id)
+            .map_or(1, |p| p + 1)
+    }
+
+    async fn draw(&self, client: &ClientOutput, handle_id: SessionHandle, size: Option<Vec2>) {
+        let size = size.unwrap_or_else(|| Vec2::new(80, 24));
+        let lines = [
+            "The server is full.".to_string(),
+            format!("You are number {} in line.", self.position(handle_id)),
+            "Press q to leave.".to_string(),
+        ];
+        let top = (size.y.saturating_sub(lines.len()) / 2) as u16;
+        let mut screen = format!("{}{}", termion::cursor::Hide, termion::clear::All);
+        for (i, line) in lines.iter().enumerate() {
+            let left = (size.x.saturating_sub(line.len()) / 2) as u16;
+            screen.push_str(&format!(
+                "{}{}",
+                termion::cursor::Goto(1 + left, 1 + top + i as u16),
+                line
+            ));
+        }
+        let _ = client.data(screen.as_bytes()).await;
+    }
+}
+
+fn reset_screen() -> String {
+    format!(
+        "{}{}{}",
+        termion::clear::All,
+        termion::cursor::Goto(1, 1),
+        termion::cursor::Show
+    )
+}
@@ -0,0 +1,464 @@
+use std::fmt::Debug;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, trace};
+use serde_json::Value;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::audit::{self, AuditEvent};
+use super::client::{ClientOutput, FrontendOutput};
+use super::limits::ConnectionLimiter;
+use super::listener::back_off_after;
+use super::metrics;
+use super::session_manager::{SessionChannel, SessionRepoUpdate, SshSessionUpdate};
+use super::spans;
+use crate::Error;
+
+/// How long a client gets to complete the upgrade request before the connection is dropped, so
+/// idle sockets can't hold on to a connection slot.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Authenticator = dyn Fn(&WebSocketRequest) -> Option<String> + Send + Sync;
+
+/// Settings for serving the app to browser terminals over WebSocket.
+///
+/// Terminal input and output travel in binary frames. Text frames carry JSON control messages, of
+/// which the only one so far is `{"type": "resize", "cols": 80, "rows": 24}`.
+///
+/// Browsers let any page open a WebSocket to any address, so upgrade requests from a page on
+/// another origin are refused with `403 Forbidden`. Without
+/// [`WebSocketConfig::with_allowed_origins`] only pages served from the same host as the WebSocket
+/// may connect. Requests without an `Origin` header don't come from a browser and are let through.
+#[derive(Clone)]
+pub struct WebSocketConfig {
+    /// Address the WebSocket listener binds.
+    pub listen: SocketAddr,
+    /// Unless this is set, the listener refuses to bind anything but a loopback address, so it can
+    /// only be reached through a reverse proxy on the same machine.
+    pub allow_remote: bool,
+    /// Username given to sessions when there's no authenticator to pick one.
+    pub username: String,
+    allowed_origins: Vec<String>,
+    authenticator: Option<Arc<Authenticator>>,
+}
+
+impl WebSocketConfig {
+    /// Listens on `port` of the IPv4 loopback address, for use behind a reverse proxy.
+    pub fn loopback(port: u16) -> Self {
+        Self {
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            allow_remote: false,
+            username: "websocket".to_string(),
+            allowed_origins: Vec::new(),
+            authenticator: None,
+        }
+    }
+
+    /// Listens on `listen`, which may be reachable from other machines. Without an authenticator,
+    /// anyone who can reach it gets a session.
+    pub fn remote(listen: SocketAddr) -> Self {
+        Self {
+            listen,
+            allow_remote: true,
+            ..Self::loopback(0)
+        }
+    }
+
+    /// Only accepts upgrade requests from pages on one of `origins`, each written the way browsers
+    /// send it in the `Origin` header, such as `https://example.com` or `http://localhost:8080`.
+    pub fn with_allowed_origins<S: Into<String>>(
+        mut self,
+        origins: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.allowed_origins = origins.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Calls `authenticator` with every upgrade request, before the WebSocket is accepted. It
+    /// returns the username for the new session, or `None` to refuse the request with
+    /// `401 Unauthorized`.
+    pub fn with_authenticator(
+        mut self,
+        authenticator: impl Fn(&WebSocketRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    pub(crate) async fn bind(&self) -> Result<TcpListener, Error> {
+        let bound = if !self.allow_remote && !self.listen.ip().is_loopback() {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "refusing to serve WebSocket on a non-loopback address without allow_remote",
+            ))
+        } else {
+            TcpListener::bind(self.listen).await
+        };
+        bound.map_err(|source| Error::Bind {
+            addr: self.listen.to_string(),
+            source,
+        })
+    }
+
+    fn allows_origin(&self, request: &WebSocketRequest) -> bool {
+        let origin = match request.header("origin") {
+            Some(origin) => origin.trim_end_matches('/'),
+            None => return true,
+        };
+        if self.allowed_origins.is_empty() {
+            let host = origin.split_once("://").map(|(_, host)| host);
+            host.is_some() && host == request.header("host")
+        } else {
+            self.allowed_origins
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+        }
+    }
+
+    fn authenticate(&self, request: &WebSocketRequest) -> Option<String> {
+        match &self.authenticator {
+            Some(authenticator) => authenticator(request),
+            None => Some(self.username.clone()),
+        }
+    }
+}
+
+impl Debug for WebSocketConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketConfig")
+            .field("listen", &self.listen)
+            .field("allow_remote", &self.allow_remote)
+            .field("username", &self.username)
+            .field("allowed_origins", &self.allowed_origins)
+            .field("authenticator", &self.authenticator.is_some())
+            .finish()
+    }
+}
+
+/// The HTTP upgrade request that opened a WebSocket, as seen by an authenticator.
+#[derive(Clone, Debug)]
+pub struct WebSocketRequest {
+    peer_addr: SocketAddr,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl WebSocketRequest {
+    fn new(peer_addr: SocketAddr, request: &Request) -> Self {
+        Self {
+            peer_addr,
+            path: request.uri().to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.as_str().to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+        }
+    }
+
+    /// Address of the peer, which is the proxy's if there is one in front of the listener.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Requested path, including the query string.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Token from an `Authorization: Bearer` header.
+    pub fn bearer_token(&self) -> Option<&str> {
+        let (scheme, token) = self.header("authorization")?.split_once(' ')?;
+        scheme
+            .eq_ignore_ascii_case("bearer")
+            .then_some(token.trim())
+    }
+
+    /// Value of the cookie called `name`.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(cookie, _)| *cookie == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Accepts WebSocket connections and hands each one to the session manager as a new session.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    config: WebSocketConfig,
+    limiter: ConnectionLimiter,
+    session_sender: Sender<SessionRepoUpdate>,
+) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Listening for WebSocket connections on {}", addr);
+    }
+    // Connections belong to the frontend's task, so they're dropped along with it.
+    let mut connections = JoinSet::new();
+    loop {
+        let (socket, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!("Failed to accept WebSocket connection: {}", err);
+                back_off_after(&err).await;
+                continue;
+            }
+        };
+        // Forget the connections that have ended.
+        while connections.try_join_next().is_some() {}
+        let permit = match limiter.try_admit(peer_addr.ip()) {
+            Ok(permit) => permit,
+            Err(refusal) => {
+                info!(
+                    "Refusing WebSocket connection from {}: {:?}",
+                    peer_addr, refusal
+                );
+                audit::record(AuditEvent::ConnectionRefused {
+                    frontend: "websocket",
+                    peer: Some(peer_addr),
+                    reason: refusal.reason(),
+                });
+                continue;
+            }
+        };
+        metrics::connection_accepted("websocket");
+        audit::record(AuditEvent::ConnectionOpened {
+            frontend: "websocket",
+            peer: Some(peer_addr),
+        });
+        trace!("New WebSocket client for peer {:?}", peer_addr);
+        let config = config.clone();
+        let session_sender = session_sender.clone();
+        let connection = async move {
+            handle_connection(socket, peer_addr, config, session_sender).await;
+            debug!("WebSocket connection from {} closed", peer_addr);
+            drop(permit);
+        };
+        connections.spawn(spans::instrument(
+            connection,
+            spans::connection("websocket", &peer_addr),
+        ));
+    }
+}
+
+fn audit_auth(peer_addr: SocketAddr, username: &str, accepted: bool) {
+    audit::record(AuditEvent::Auth {
+        peer: Some(peer_addr),
+        method: "websocket",
+        username: username.to_string(),
+        fingerprint: None,
+        accepted,
+    });
+}
+
+async fn handle_connection(
+    socket: TcpStream,
+    peer_addr: SocketAddr,
+    config: WebSocketConfig,
+    session_sender: Sender<SessionRepoUpdate>,
+) {
+    let mut username = None;
+    // Tungstenite dictates the callback's error type.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        let request = WebSocketRequest::new(peer_addr, request);
+        let refuse = |status| {
+            let mut refusal = ErrorResponse::new(None);
+            *refusal.status_mut() = status;
+            Err(refusal)
+        };
+        if !config.allows_origin(&request) {
+            info!(
+                "Refusing WebSocket connection from {} for origin {:?}",
+                peer_addr,
+                request.header("origin")
+            );
+            audit::record(AuditEvent::ConnectionRefused {
+                frontend: "websocket",
+                peer: Some(peer_addr),
+                reason: "forbidden_origin",
+            });
+            return refuse(StatusCode::FORBIDDEN);
+        }
+        match config.authenticate(&request) {
+            Some(name) => {
+                audit_auth(peer_addr, &name, true);
+                username = Some(name);
+                Ok(response)
+            }
+            None => {
+                audit_auth(peer_addr, "", false);
+                refuse(StatusCode::UNAUTHORIZED)
+            }
+        }
+    };
+    let handshake = tokio_tungstenite::accept_hdr_async(socket, callback);
+    let stream = match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            debug!("WebSocket handshake with {} failed: {}", peer_addr, err);
+            return;
+        }
+        Err(_) => {
+            info!("WebSocket handshake with {} timed out", peer_addr);
+            audit::record(AuditEvent::ConnectionRefused {
+                frontend: "websocket",
+                peer: Some(peer_addr),
+                reason: "handshake_timeout",
+            });
+            return;
+        }
+    };
+    let username = match username {
+        Some(username) => username,
+        None => return,
+    };
+    let span = spans::current();
+    spans::record_username(&span, &username);
+    let (mut writer, mut reader) = stream.split();
+    let (output_sender, mut output_receiver) = channel(100);
+    let (update_sender, update_rx) = channel(100);
+    // Owned by the connection's task, so the writer and its half of the socket go with it.
+    let mut writer_task = JoinSet::new();
+    writer_task.spawn(async move {
+        while let Some(output) = output_receiver.recv().await {
+            let message = match output {
+                FrontendOutput::Data(data) => Message::Binary(data),
+                FrontendOutput::Close => break,
+            };
+            if writer.send(message).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.close().await;
+    });
+
+    // The browser sends its size once the socket opens, but the session needs one to start with.
+    let _ = update_sender
+        .send(SshSessionUpdate::WindowResize(80, 24))
+        .await;
+    let channel = SessionChannel {
+        output: ClientOutput::Frontend(output_sender),
+        update_rx,
+        username,
+        key: None,
+        peer: Some(peer_addr),
+        console: false,
+        span,
+    };
+    if session_sender
+        .send(SessionRepoUpdate::NewSession(channel))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    while let Some(Ok(message)) = reader.next().await {
+        let update = match message {
+            Message::Binary(data) => SshSessionUpdate::Data(data),
+            Message::Text(text) => match control_message(&text) {
+                Some(update) => update,
+                None => {
+                    debug!("Ignoring WebSocket control message: {}", text);
+                    continue;
+                }
+            },
+            Message::Close(_) => break,
+            // Tungstenite answers pings itself.
+            _ => continue,
+        };
+        if update_sender.send(update).await.is_err() {
+            debug!("Session for WebSocket connection has already ended");
+        }
+    }
+    let _ = update_sender.send(SshSessionUpdate::Close).await;
+    // Lets the writer finish sending what the session had left to say.
+    let _ = writer_task.join_next().await;
+}
+
+/// Parses a JSON control message from the browser.
+fn control_message(text: &str) -> Option<SshSessionUpdate> {
+    let message: Value = serde_json::from_str(text).ok()?;
+    match message.get("type")?.as_str()? {
+        "resize" => {
+            let cols = message.get("cols")?.as_u64()? as usize;
+            let rows = message.get("rows")?.as_u64()? as usize;
+            (cols > 0 && rows > 0).then_some(SshSessionUpdate::WindowResize(cols, rows))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> WebSocketRequest {
+        WebSocketRequest {
+            peer_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 40000)),
+            path: "/".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn only_same_origin_pages_connect_by_default() {
+        let config = WebSocketConfig::loopback(8022);
+        let host = ("Host", "localhost:8022");
+        assert!(config.allows_origin(&request(&[host, ("Origin", "http://localhost:8022")])));
+        assert!(config.allows_origin(&request(&[host])));
+        assert!(!config.allows_origin(&request(&[host, ("Origin", "https://evil.example")])));
+        assert!(!config.allows_origin(&request(&[host, ("Origin", "null")])));
+        assert!(!config.allows_origin(&request(&[("Origin", "http://localhost:8022")])));
+    }
+
+    #[test]
+    fn allowed_origins_replace_the_same_origin_check() {
+        let config = WebSocketConfig::loopback(8022)
+            .with_allowed_origins(["https://example.com", "http://localhost:8080/"]);
+        let host = ("Host", "localhost:8022");
+        assert!(config.allows_origin(&request(&[host, ("Origin", "https://example.com")])));
+        assert!(config.allows_origin(&request(&[host, ("Origin", "HTTP://LOCALHOST:8080")])));
+        assert!(!config.allows_origin(&request(&[host, ("Origin", "http://localhost:8022")])));
+        assert!(!config.allows_origin(&request(&[host, ("Origin", "https://example.com.evil")])));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_bind_remote_addresses_unless_allowed() {
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let config = WebSocketConfig {
+            listen: unspecified,
+            ..WebSocketConfig::loopback(0)
+        };
+        assert!(matches!(config.bind().await, Err(Error::Bind { .. })));
+        assert!(WebSocketConfig::remote(unspecified).bind().await.is_ok());
+        assert!(WebSocketConfig::loopback(0).bind().await.is_ok());
+    }
+}
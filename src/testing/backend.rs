@@ -0,0 +1,193 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::cursive::backend;
+use crate::cursive::event::Event;
+use crate::cursive::reexports::enumset::EnumSet;
+use crate::cursive::theme::{Color, ColorPair, Effect};
+use crate::cursive::Vec2;
+
+use super::{Cell, CellStyle, Screen};
+
+/// State shared between the backend, which lives inside the cursive runner, and the harness.
+pub(super) struct BackendState {
+    pub screen: Screen,
+    pub events: VecDeque<Event>,
+    pub title: Option<String>,
+    colors: ColorPair,
+    effects: EnumSet<Effect>,
+}
+
+impl BackendState {
+    pub fn new(size: Vec2) -> Self {
+        Self {
+            screen: Screen::new(size),
+            events: VecDeque::new(),
+            title: None,
+            colors: ColorPair {
+                front: Color::TerminalDefault,
+                back: Color::TerminalDefault,
+            },
+            effects: EnumSet::new(),
+        }
+    }
+
+    fn style(&self) -> CellStyle {
+        CellStyle {
+            colors: self.colors,
+            effects: self.effects,
+        }
+    }
+}
+
+/// A cursive backend that draws into an in-memory grid of cells instead of a terminal.
+pub(super) struct MemoryBackend {
+    state: Rc<RefCell<BackendState>>,
+}
+
+impl MemoryBackend {
+    pub fn new(state: Rc<RefCell<BackendState>>) -> Self {
+        Self { state }
+    }
+}
+
+impl backend::Backend for MemoryBackend {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn poll_event(&mut self) -> Option<Event> {
+        self.state.borrow_mut().events.pop_front()
+    }
+
+    fn set_title(&mut self, title: String) {
+        self.state.borrow_mut().title = Some(title);
+    }
+
+    fn refresh(&mut self) {}
+
+    fn has_colors(&self) -> bool {
+        true
+    }
+
+    fn screen_size(&self) -> Vec2 {
+        self.state.borrow().screen.size()
+    }
+
+    fn print_at(&self, pos: Vec2, text: &str) {
+        let mut state = self.state.borrow_mut();
+        let style = state.style();
+        let size = state.screen.size();
+        if pos.y >= size.y {
+            return;
+        }
+        let mut x = pos.x;
+        for c in text.chars() {
+            if x >= size.x {
+                break;
+            }
+            x += state.screen.put(Vec2::new(x, pos.y), c, style);
+        }
+    }
+
+    fn clear(&self, color: Color) {
+        let mut state = self.state.borrow_mut();
+        let style = CellStyle {
+            colors: ColorPair {
+                front: color,
+                back: color,
+            },
+            effects: EnumSet::new(),
+        };
+        state.screen.fill(Cell {
+            text: " ".to_string(),
+            style,
+        });
+    }
+
+    fn set_color(&self, colors: ColorPair) -> ColorPair {
+        std::mem::replace(&mut self.state.borrow_mut().colors, colors)
+    }
+
+    fn set_effect(&self, effect: Effect) {
+        self.state.borrow_mut().effects.insert(effect);
+    }
+
+    fn unset_effect(&self, effect: Effect) {
+        self.state.borrow_mut().effects.remove(effect);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cursive::backend::Backend;
+    use crate::cursive::theme::BaseColor;
+
+    use super::*;
+
+    fn backend(size: Vec2) -> (MemoryBackend, Rc<RefCell<BackendState>>) {
+        let state = Rc::new(RefCell::new(BackendState::new(size)));
+        (MemoryBackend::new(state.clone()), state)
+    }
+
+    #[test]
+    fn prints_with_the_current_colors_and_effects() {
+        let (backend, state) = backend(Vec2::new(10, 2));
+        let red = ColorPair {
+            front: Color::Dark(BaseColor::Red),
+            back: Color::Dark(BaseColor::Black),
+        };
+        let previous = backend.set_color(red);
+        assert_eq!(previous.front, Color::TerminalDefault);
+        backend.set_effect(Effect::Bold);
+        backend.print_at(Vec2::new(1, 1), "hi");
+        backend.unset_effect(Effect::Bold);
+        backend.print_at(Vec2::new(3, 1), "!");
+
+        let screen = state.borrow().screen.clone();
+        assert_eq!(screen.lines(), ["", " hi!"]);
+        let h = screen.cell(Vec2::new(1, 1)).unwrap();
+        assert_eq!(h.style.colors, red);
+        assert!(h.style.effects.contains(Effect::Bold));
+        let bang = screen.cell(Vec2::new(3, 1)).unwrap();
+        assert_eq!(bang.style.colors, red);
+        assert!(bang.style.effects.is_empty());
+        assert_eq!(
+            screen.cell(Vec2::new(0, 1)).unwrap().style,
+            CellStyle::default()
+        );
+    }
+
+    #[test]
+    fn clips_text_at_the_edges() {
+        let (backend, state) = backend(Vec2::new(4, 1));
+        backend.print_at(Vec2::new(2, 0), "abcdef");
+        backend.print_at(Vec2::new(0, 3), "off screen");
+        assert_eq!(state.borrow().screen.lines(), ["  ab"]);
+    }
+
+    #[test]
+    fn clear_fills_the_screen_with_the_color() {
+        let (backend, state) = backend(Vec2::new(3, 2));
+        backend.print_at(Vec2::zero(), "abc");
+        backend.clear(Color::Dark(BaseColor::Blue));
+        let screen = state.borrow().screen.clone();
+        assert_eq!(screen.lines(), ["", ""]);
+        let cell = screen.cell(Vec2::new(2, 1)).unwrap();
+        assert_eq!(cell.style.colors.back, Color::Dark(BaseColor::Blue));
+    }
+
+    #[test]
+    fn hands_out_queued_events_in_order() {
+        let (mut backend, state) = backend(Vec2::new(3, 2));
+        state.borrow_mut().events.push_back(Event::Char('a'));
+        state.borrow_mut().events.push_back(Event::Char('b'));
+        assert_eq!(backend.poll_event(), Some(Event::Char('a')));
+        assert_eq!(backend.poll_event(), Some(Event::Char('b')));
+        assert_eq!(backend.poll_event(), None);
+        backend.set_title("title".to_string());
+        assert_eq!(state.borrow().title.as_deref(), Some("title"));
+        assert_eq!(backend.screen_size(), Vec2::new(3, 2));
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cursive::event::Key;
+use crate::cursive::Vec2;
+use crate::russh_keys::encoding::{Encoding, Reader};
+use crate::russh_keys::key::{KeyPair, PublicKey};
+use crate::russh_keys::PublicKeyBase64;
+use crate::{App, AppServer};
+
+use russh::client;
+use russh::{AgentAuthError, Channel, ChannelId, CryptoVec, Signer};
+use tokio::net::TcpListener;
+use tokio::sync::{watch, Mutex as AsyncMutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+
+use super::{Screen, Terminal};
+
+/// An [`AppServer`] listening on an ephemeral localhost port, for end-to-end tests over a real
+/// ssh connection.
+///
+/// The server's [`App`] is registered process-wide, the same as with [`AppServer::run`], so tests
+/// that run servers for different apps at the same time shouldn't share a test binary.
+pub struct LoopbackServer {
+    addr: SocketAddr,
+    host_key: PublicKey,
+    task: JoinHandle<()>,
+}
+
+impl LoopbackServer {
+    /// Starts `server` for `app` on 127.0.0.1 with a freshly generated host key. The server's
+    /// configured port is ignored. Must be called from within a tokio runtime. Dropping the
+    /// returned value stops the server, closing its listener and the connections it accepted.
+    pub async fn start(mut server: AppServer, app: Arc<dyn App>) -> Result<Self, Box<dyn Error>> {
+        let key_pair = KeyPair::generate_ed25519().ok_or("failed to generate a host key")?;
+        let host_key = key_pair.clone_public_key()?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            server.run_on(listener, &[key_pair], app).await;
+        });
+        Ok(Self {
+            addr,
+            host_key,
+            task,
+        })
+    }
+
+    /// The address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The server's host key.
+    pub fn host_key(&self) -> &PublicKey {
+        &self.host_key
+    }
+
+    /// Connects as `username` with a freshly generated key and opens a session on a terminal of
+    /// the given size.
+    pub async fn connect(
+        &self,
+        username: &str,
+        size: Vec2,
+    ) -> Result<LoopbackClient, Box<dyn Error>> {
+        let key = KeyPair::generate_ed25519().ok_or("failed to generate a client key")?;
+        self.connect_with_key(username, key, size).await
+    }
+
+    /// Connects as `username` with the given key, so a test can reconnect under the same
+    /// identity.
+    pub async fn connect_with_key(
+        &self,
+        username: &str,
+        key: KeyPair,
+        size: Vec2,
+    ) -> Result<LoopbackClient, Box<dyn Error>> {
+        let auth = Credentials::Key(username.to_string(), Arc::new(key));
+        LoopbackClient::connect(self, size, auth).await
+    }
+
+    /// Connects as `username` the way a client trying to pass itself off as the holder of
+    /// `queried` would: it asks whether `queried` would be accepted, then signs the request with
+    /// `key` instead.
+    pub async fn connect_with_swapped_key(
+        &self,
+        username: &str,
+        queried: PublicKey,
+        key: KeyPair,
+        size: Vec2,
+    ) -> Result<LoopbackClient, Box<dyn Error>> {
+        let auth = Credentials::SwappedKey(username.to_string(), queried, Arc::new(key));
+        LoopbackClient::connect(self, size, auth).await
+    }
+
+    /// Connects as the `anonymous` user without a key.
+    pub async fn connect_anonymous(&self, size: Vec2) -> Result<LoopbackClient, Box<dyn Error>> {
+        LoopbackClient::connect(self, size, Credentials::None("anonymous".to_string())).await
+    }
+}
+
+impl Drop for LoopbackServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// State shared between the client's connection handler and the [`LoopbackClient`] for one
+/// channel.
+struct ClientState {
+    terminal: Mutex<Terminal>,
+    closed: Mutex<bool>,
+    // Bumped whenever the terminal or `closed` changes, to wake up waiters.
+    updated: watch::Sender<()>,
+}
+
+impl ClientState {
+    fn close(&self) {
+        *self.closed.lock().unwrap() = true;
+        self.updated.send_replace(());
+    }
+}
+
+/// State shared between the client's connection handler and every [`LoopbackClient`] on the
+/// connection.
+#[derive(Default)]
+struct ConnectionState {
+    /// The banner the server sent before authentication, if any.
+    banner: Option<String>,
+    /// The state for the channel being opened, which the server may start drawing to before the
+    /// client has learnt its id.
+    opening: Option<Arc<ClientState>>,
+    open: HashMap<ChannelId, Arc<ClientState>>,
+}
+
+impl ConnectionState {
+    fn channel(&mut self, channel: ChannelId) -> Option<Arc<ClientState>> {
+        if !self.open.contains_key(&channel) {
+            let state = self.opening.take()?;
+            self.open.insert(channel, state);
+        }
+        self.open.get(&channel).cloned()
+    }
+}
+
+struct ClientHandler {
+    host_key: String,
+    connection: Arc<Mutex<ConnectionState>>,
+}
+
+#[async_trait::async_trait]
+impl client::Handler for ClientHandler {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(
+        self,
+        server_public_key: &PublicKey,
+    ) -> Result<(Self, bool), Self::Error> {
+        let trusted = server_public_key.fingerprint() == self.host_key;
+        Ok((self, trusted))
+    }
+
+    async fn auth_banner(
+        self,
+        banner: &str,
+        session: client::Session,
+    ) -> Result<(Self, client::Session), Self::Error> {
+        self.connection.lock().unwrap().banner = Some(banner.to_string());
+        Ok((self, session))
+    }
+
+    async fn data(
+        self,
+        channel: ChannelId,
+        data: &[u8],
+        session: client::Session,
+    ) -> Result<(Self, client::Session), Self::Error> {
+        if let Some(state) = self.connection.lock().unwrap().channel(channel) {
+            state.terminal.lock().unwrap().feed(data);
+            state.updated.send_replace(());
+        }
+        Ok((self, session))
+    }
+
+    async fn channel_close(
+        self,
+        channel: ChannelId,
+        session: client::Session,
+    ) -> Result<(Self, client::Session), Self::Error> {
+        if let Some(state) = self.connection.lock().unwrap().channel(channel) {
+            state.close();
+        }
+        Ok((self, session))
+    }
+}
+
+impl Drop for ClientHandler {
+    fn drop(&mut self) {
+        // The handler goes away with the connection, however it ended.
+        let connection = self.connection.lock().unwrap();
+        for state in connection.open.values().chain(&connection.opening) {
+            state.close();
+        }
+    }
+}
+
+/// How a [`LoopbackClient`] authenticates, along with the username it authenticates as.
+enum Credentials {
+    Key(String, Arc<KeyPair>),
+    /// Queries the public key, then signs with the key pair.
+    SwappedKey(String, PublicKey, Arc<KeyPair>),
+    None(String),
+}
+
+/// Signs an authentication request with its own key, whichever key the request was made for.
+struct SwappingSigner(Arc<KeyPair>);
+
+impl Signer for SwappingSigner {
+    type Error = AgentAuthError;
+    type Future = std::future::Ready<(Self, Result<CryptoVec, Self::Error>)>;
+
+    fn auth_publickey_sign(self, _queried: &PublicKey, to_sign: CryptoVec) -> Self::Future {
+        let signed = swap_key(&to_sign, &self.0);
+        std::future::ready((self, signed))
+    }
+}
+
+/// Rewrites `to_sign`, the session id followed by a `publickey` authentication request, to carry
+/// `key` in place of the key it was made for, and signs it with `key`.
+fn swap_key(to_sign: &[u8], key: &KeyPair) -> Result<CryptoVec, AgentAuthError> {
+    // Skips the session id, the message number, the username, service and method names, and the
+    // flag saying a signature follows, which leaves the key.
+    let mut reader = to_sign.reader(0);
+    reader.read_string()?;
+    reader.read_byte()?;
+    for _ in 0..3 {
+        reader.read_string()?;
+    }
+    reader.read_byte()?;
+    let mut signed = CryptoVec::from_slice(&to_sign[..reader.position]);
+    signed.extend_ssh_string(key.name().as_bytes());
+    signed.extend_ssh_string(&key.public_key_bytes());
+    key.add_self_signature(&mut signed)?;
+    Ok(signed)
+}
+
+/// An ssh client connected to a [`LoopbackServer`], with a terminal emulator keeping track of
+/// what the session has drawn.
+pub struct LoopbackClient {
+    handle: Arc<AsyncMutex<client::Handle<ClientHandler>>>,
+    connection: Arc<Mutex<ConnectionState>>,
+    channel: Channel<client::Msg>,
+    state: Arc<ClientState>,
+    updates: watch::Receiver<()>,
+}
+
+impl LoopbackClient {
+    async fn connect(
+        server: &LoopbackServer,
+        size: Vec2,
+        credentials: Credentials,
+    ) -> Result<Self, Box<dyn Error>> {
+        let connection = Arc::new(Mutex::new(ConnectionState::default()));
+        let handler = ClientHandler {
+            host_key: server.host_key.fingerprint(),
+            connection: connection.clone(),
+        };
+        let config = Arc::new(client::Config::default());
+        let mut handle = client::connect(config, server.addr, handler).await?;
+        let accepted = match credentials {
+            Credentials::Key(username, key) => handle.authenticate_publickey(username, key).await?,
+            Credentials::SwappedKey(username, queried, key) => {
+                let signer = SwappingSigner(key);
+                handle
+                    .authenticate_future(username, queried, signer)
+                    .await
+                    .1?
+            }
+            Credentials::None(username) => handle.authenticate_none(username).await?,
+        };
+        if !accepted {
+            return Err("authentication was rejected".into());
+        }
+        Self::open(Arc::new(AsyncMutex::new(handle)), connection, size).await
+    }
+
+    /// Opens another session on the same connection, on a terminal of the given size.
+    pub async fn open_session(&self, size: Vec2) -> Result<Self, Box<dyn Error>> {
+        Self::open(self.handle.clone(), self.connection.clone(), size).await
+    }
+
+    async fn open(
+        handle: Arc<AsyncMutex<client::Handle<ClientHandler>>>,
+        connection: Arc<Mutex<ConnectionState>>,
+        size: Vec2,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (updated, updates) = watch::channel(());
+        let state = Arc::new(ClientState {
+            terminal: Mutex::new(Terminal::new(size)),
+            closed: Mutex::new(false),
+            updated,
+        });
+        // Holding the handle keeps any other channel from being opened until this one has its id.
+        let mut channel = {
+            let mut handle = handle.lock().await;
+            connection.lock().unwrap().opening = Some(state.clone());
+            let channel = handle.channel_open_session().await?;
+            // Binds the state to the channel's id, unless something sent on it already has.
+            connection.lock().unwrap().channel(channel.id());
+            channel
+        };
+        channel
+            .request_pty(
+                false,
+                "xterm-256color",
+                size.x as u32,
+                size.y as u32,
+                0,
+                0,
+                &[],
+            )
+            .await?;
+        channel.request_shell(false).await?;
+        Ok(Self {
+            handle,
+            connection,
+            channel,
+            state,
+            updates,
+        })
+    }
+
+    /// The banner the server sent before the client authenticated, if any.
+    pub fn banner(&self) -> Option<String> {
+        self.connection.lock().unwrap().banner.clone()
+    }
+
+    /// A copy of the screen as the client currently sees it.
+    pub fn screen(&self) -> Screen {
+        self.state.terminal.lock().unwrap().screen().clone()
+    }
+
+    /// The last title the session set, if any.
+    pub fn title(&self) -> Option<String> {
+        let terminal = self.state.terminal.lock().unwrap();
+        terminal.title().map(str::to_string)
+    }
+
+    /// Returns true once the server has closed the session or the connection has dropped.
+    pub fn is_closed(&self) -> bool {
+        *self.state.closed.lock().unwrap()
+    }
+
+    /// Sends raw bytes, as if they had been typed into the client's terminal.
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.channel.data(data).await?;
+        Ok(())
+    }
+
+    /// Types each character of `text`. Newlines are sent as carriage returns, like a terminal's
+    /// Enter key.
+    pub async fn type_text(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        self.send(text.replace('\n', "\r").as_bytes()).await
+    }
+
+    /// Pastes `text` wrapped in bracketed paste markers, with newlines sent as carriage returns
+    /// like a terminal does.
+    pub async fn paste(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        let text = text.replace('\n', "\r");
+        self.send(format!("\x1b[200~{}\x1b[201~", text).as_bytes())
+            .await
+    }
+
+    /// Presses a single key, encoded the way xterm sends it.
+    pub async fn press(&mut self, key: Key) -> Result<(), Box<dyn Error>> {
+        let bytes = key_sequence(key).ok_or_else(|| format!("{:?} can't be sent", key))?;
+        self.send(bytes).await
+    }
+
+    /// Resizes the client's terminal and tells the server about it.
+    pub async fn resize(&mut self, size: Vec2) -> Result<(), Box<dyn Error>> {
+        self.state.terminal.lock().unwrap().resize(size);
+        self.channel
+            .window_change(size.x as u32, size.y as u32, 0, 0)
+            .await?;
+        Ok(())
+    }
+
+    /// Waits until `needle` appears on the screen, returning the screen it appeared on.
+    pub async fn wait_for(
+        &mut self,
+        needle: &str,
+        timeout: Duration,
+    ) -> Result<Screen, Box<dyn Error>> {
+        self.wait_until(timeout, |screen| screen.contains(needle))
+            .await
+            .map_err(|_| format!("timed out waiting for {:?} to appear", needle).into())
+    }
+
+    /// Waits until `predicate` holds for the screen, returning the screen it held for.
+    pub async fn wait_until(
+        &mut self,
+        timeout: Duration,
+        predicate: impl Fn(&Screen) -> bool,
+    ) -> Result<Screen, Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.updates.borrow_and_update();
+            let screen = self.screen();
+            if predicate(&screen) {
+                return Ok(screen);
+            }
+            if self.is_closed() {
+                return Err("the session closed before the screen matched".into());
+            }
+            self.wait_for_update(deadline).await?;
+        }
+    }
+
+    /// Waits until the server closes the session.
+    pub async fn wait_for_close(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.updates.borrow_and_update();
+            if self.is_closed() {
+                return Ok(());
+            }
+            self.wait_for_update(deadline).await?;
+        }
+    }
+
+    async fn wait_for_update(&mut self, deadline: Instant) -> Result<(), Box<dyn Error>> {
+        match timeout_at(deadline, self.updates.changed()).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err("timed out waiting for the session".into()),
+        }
+    }
+
+    /// Closes the session and the connection, along with any other session opened on it.
+    pub async fn disconnect(mut self) -> Result<(), Box<dyn Error>> {
+        self.channel.eof().await?;
+        self.handle
+            .lock()
+            .await
+            .disconnect(russh::Disconnect::ByApplication, "", "en")
+            .await?;
+        Ok(())
+    }
+}
+
+/// The bytes xterm sends for `key`, if it sends anything at all.
+fn key_sequence(key: Key) -> Option<&'static [u8]> {
+    let bytes: &'static [u8] = match key {
+        Key::Enter => b"\r",
+        Key::Tab => b"\t",
+        Key::Backspace => b"\x7f",
+        Key::Esc => b"\x1b",
+        Key::Up => b"\x1b[A",
+        Key::Down => b"\x1b[B",
+        Key::Right => b"\x1b[C",
+        Key::Left => b"\x1b[D",
+        Key::Home => b"\x1b[H",
+        Key::End => b"\x1b[F",
+        Key::Ins => b"\x1b[2~",
+        Key::Del => b"\x1b[3~",
+        Key::PageUp => b"\x1b[5~",
+        Key::PageDown => b"\x1b[6~",
+        Key::F1 => b"\x1bOP",
+        Key::F2 => b"\x1bOQ",
+        Key::F3 => b"\x1bOR",
+        Key::F4 => b"\x1bOS",
+        Key::F5 => b"\x1b[15~",
+        Key::F6 => b"\x1b[17~",
+        Key::F7 => b"\x1b[18~",
+        Key::F8 => b"\x1b[19~",
+        Key::F9 => b"\x1b[20~",
+        Key::F10 => b"\x1b[21~",
+        Key::F11 => b"\x1b[23~",
+        Key::F12 => b"\x1b[24~",
+        _ => return None,
+    };
+    Some(bytes)
+}
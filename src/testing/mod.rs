@@ -0,0 +1,415 @@
+//! Helpers for driving an [`AppSession`] without an ssh server, for use in tests.
+//!
+//! A [`TestSession`] runs the session against an in-memory backend. Events are delivered and
+//! processed synchronously, and ticks only happen when asked for, so tests are deterministic.
+//! The rendered [`Screen`] can be inspected cell by cell or compared against snapshot files with
+//! [`assert_snapshot`].
+//!
+//! For end-to-end tests, a [`LoopbackServer`] runs a real [`AppServer`](crate::AppServer) on a
+//! localhost port and a [`LoopbackClient`] connects to it over ssh, reading back what the session
+//! draws through a small [`Terminal`] emulator.
+
+mod backend;
+mod loopback;
+mod snapshot;
+mod terminal;
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+
+use crate::cursive::event::{Event, Key, MouseButton, MouseEvent};
+use crate::cursive::reexports::enumset::EnumSet;
+use crate::cursive::theme::{Color, ColorPair, Effect};
+use crate::cursive::{Cursive, CursiveRunner, Vec2};
+use crate::{App, AppSession, SessionHandle};
+
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::mpsc::{channel, Receiver};
+use unicode_width::UnicodeWidthChar;
+
+use backend::{BackendState, MemoryBackend};
+
+pub use loopback::{LoopbackClient, LoopbackServer};
+pub use snapshot::{assert_snapshot, diff, UPDATE_SNAPSHOTS_ENV};
+pub use terminal::Terminal;
+
+/// The colors and effects a cell was drawn with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CellStyle {
+    pub colors: ColorPair,
+    pub effects: EnumSet<Effect>,
+}
+
+impl Default for CellStyle {
+    fn default() -> Self {
+        Self {
+            colors: ColorPair {
+                front: Color::TerminalDefault,
+                back: Color::TerminalDefault,
+            },
+            effects: EnumSet::new(),
+        }
+    }
+}
+
+/// A single cell of the rendered screen.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Cell {
+    /// The text drawn in the cell. Usually a single character, but empty for the right half of a
+    /// wide character and longer when combining characters are involved.
+    pub text: String,
+    pub style: CellStyle,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            text: " ".to_string(),
+            style: CellStyle::default(),
+        }
+    }
+}
+
+/// The rendered contents of a session's screen.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Screen {
+    size: Vec2,
+    cells: Vec<Cell>,
+}
+
+impl Screen {
+    pub(crate) fn new(size: Vec2) -> Self {
+        Self {
+            size,
+            cells: vec![Cell::default(); size.x * size.y],
+        }
+    }
+
+    /// Width and height of the screen in cells.
+    pub fn size(&self) -> Vec2 {
+        self.size
+    }
+
+    /// The cell at `pos`, or `None` if it's off screen.
+    pub fn cell(&self, pos: Vec2) -> Option<&Cell> {
+        if pos.x < self.size.x && pos.y < self.size.y {
+            self.cells.get(pos.y * self.size.x + pos.x)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn cell_mut(&mut self, pos: Vec2) -> Option<&mut Cell> {
+        if pos.x < self.size.x && pos.y < self.size.y {
+            self.cells.get_mut(pos.y * self.size.x + pos.x)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn fill(&mut self, cell: Cell) {
+        self.cells.iter_mut().for_each(|c| *c = cell.clone());
+    }
+
+    /// Draws `c` at `pos` and returns how many columns it takes up. Characters that don't fit on
+    /// the row are dropped, and combining characters are attached to the cell before `pos`.
+    pub(crate) fn put(&mut self, pos: Vec2, c: char, style: CellStyle) -> usize {
+        let width = c.width().unwrap_or(0);
+        if width == 0 {
+            if pos.x > 0 {
+                if let Some(cell) = self.cell_mut(Vec2::new(pos.x - 1, pos.y)) {
+                    cell.text.push(c);
+                }
+            }
+            return 0;
+        }
+        if pos.x + width > self.size.x {
+            return width;
+        }
+        if let Some(cell) = self.cell_mut(pos) {
+            *cell = Cell {
+                text: c.to_string(),
+                style,
+            };
+        }
+        // The right half of a wide character is covered by it and holds no text of its own.
+        for covered in 1..width {
+            if let Some(cell) = self.cell_mut(Vec2::new(pos.x + covered, pos.y)) {
+                *cell = Cell {
+                    text: String::new(),
+                    style,
+                };
+            }
+        }
+        width
+    }
+
+    /// The text of row `y`, with trailing spaces removed.
+    pub fn row(&self, y: usize) -> String {
+        if y >= self.size.y {
+            return String::new();
+        }
+        let row = &self.cells[y * self.size.x..(y + 1) * self.size.x];
+        let text: String = row.iter().map(|cell| cell.text.as_str()).collect();
+        text.trim_end().to_string()
+    }
+
+    /// The text of every row, with trailing spaces removed.
+    pub fn lines(&self) -> Vec<String> {
+        (0..self.size.y).map(|y| self.row(y)).collect()
+    }
+
+    /// Returns true if `needle` appears on any single row of the screen.
+    pub fn contains(&self, needle: &str) -> bool {
+        self.find(needle).is_some()
+    }
+
+    /// The position of the first occurrence of `needle` on a single row of the screen.
+    pub fn find(&self, needle: &str) -> Option<Vec2> {
+        (0..self.size.y).find_map(|y| {
+            let row = &self.cells[y * self.size.x..(y + 1) * self.size.x];
+            (0..self.size.x).find_map(|x| {
+                let mut text = String::new();
+                for cell in &row[x..] {
+                    if text.len() >= needle.len() {
+                        break;
+                    }
+                    text.push_str(&cell.text);
+                }
+                text.starts_with(needle).then(|| Vec2::new(x, y))
+            })
+        })
+    }
+}
+
+/// An [`AppSession`] running against an in-memory screen.
+pub struct TestSession {
+    // Declared first so the runner, and with it the session, is dropped inside the runtime's
+    // lifetime.
+    runner: CursiveRunner<Cursive>,
+    session: Rc<RefCell<Box<dyn AppSession>>>,
+    state: Rc<RefCell<BackendState>>,
+    refresh_receiver: Receiver<()>,
+    handle: SessionHandle,
+    runtime: Runtime,
+}
+
+impl TestSession {
+    /// Starts a new session of `app` on a screen of the given size.
+    pub fn new(app: &dyn App, size: Vec2) -> Result<Self, Box<dyn Error>> {
+        Self::from_session(app.new_session(), size)
+    }
+
+    /// Starts the given session on a screen of the given size.
+    pub fn from_session(
+        mut session: Box<dyn AppSession>,
+        size: Vec2,
+    ) -> Result<Self, Box<dyn Error>> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let _enter = runtime.enter();
+
+        let handle = SessionHandle(0);
+        let (refresh_sender, refresh_receiver) = channel(10);
+        let mut siv = Cursive::new();
+        let view = session.on_start(&mut siv, handle, None, refresh_sender)?;
+        siv.add_layer(view);
+
+        let state = Rc::new(RefCell::new(BackendState::new(size)));
+        let mut runner = siv.into_runner(Box::new(MemoryBackend::new(state.clone())));
+        let session = Rc::new(RefCell::new(session));
+        let tick_session = session.clone();
+        runner.add_global_callback(Event::Refresh, move |siv| {
+            let _ = tick_session.borrow_mut().on_tick(siv);
+        });
+        runner.refresh();
+        drop(_enter);
+
+        let mut test_session = Self {
+            runner,
+            session,
+            state,
+            refresh_receiver,
+            handle,
+            runtime,
+        };
+        test_session.tick();
+        Ok(test_session)
+    }
+
+    /// The handle the session was started with.
+    pub fn handle(&self) -> SessionHandle {
+        self.handle
+    }
+
+    /// Direct access to the session's cursive instance.
+    pub fn cursive(&mut self) -> &mut Cursive {
+        &mut self.runner
+    }
+
+    /// Returns false once the session has quit.
+    pub fn is_running(&self) -> bool {
+        self.runner.is_running()
+    }
+
+    /// The last title the session set, if any.
+    pub fn title(&self) -> Option<String> {
+        self.state.borrow().title.clone()
+    }
+
+    /// A copy of the screen as it was last drawn.
+    pub fn screen(&self) -> Screen {
+        self.state.borrow().screen.clone()
+    }
+
+    /// Delivers an event and redraws the screen.
+    pub fn send_event(&mut self, event: Event) {
+        self.state.borrow_mut().events.push_back(event);
+        self.step();
+    }
+
+    /// Presses a single key.
+    pub fn press(&mut self, key: Key) {
+        self.send_event(Event::Key(key));
+    }
+
+    /// Types each character of `text`, turning newlines into Enter and tabs into Tab.
+    pub fn type_text(&mut self, text: &str) {
+        for c in text.chars() {
+            let event = match c {
+                '\n' => Event::Key(Key::Enter),
+                '\t' => Event::Key(Key::Tab),
+                c => Event::Char(c),
+            };
+            self.state.borrow_mut().events.push_back(event);
+        }
+        self.step();
+    }
+
+    /// Pastes `text` in one go, as a terminal with bracketed paste does.
+    pub fn paste(&mut self, text: &str) {
+        let _enter = self.runtime.enter();
+        self.session.borrow_mut().on_paste(&mut self.runner, text);
+        drop(_enter);
+        self.step();
+    }
+
+    /// Sends a mouse event at `position`.
+    pub fn mouse(&mut self, event: MouseEvent, position: Vec2) {
+        self.send_event(Event::Mouse {
+            event,
+            position,
+            offset: Vec2::zero(),
+        });
+    }
+
+    /// Presses and releases the left mouse button at `position`.
+    pub fn click(&mut self, position: Vec2) {
+        self.mouse(MouseEvent::Press(MouseButton::Left), position);
+        self.mouse(MouseEvent::Release(MouseButton::Left), position);
+    }
+
+    /// Resizes the screen, as if the client's terminal window changed size.
+    pub fn resize(&mut self, size: Vec2) {
+        self.state.borrow_mut().screen = Screen::new(size);
+        self.send_event(Event::WindowResize);
+    }
+
+    /// Runs one tick of the session, calling [`AppSession::on_tick`], and redraws the screen.
+    pub fn tick(&mut self) {
+        {
+            let _enter = self.runtime.enter();
+            self.runner.on_event(Event::Refresh);
+        }
+        self.step();
+    }
+
+    /// Runs `ticks` ticks of the session.
+    pub fn advance(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// Processes pending events and callbacks, then redraws the screen.
+    pub fn step(&mut self) {
+        let _enter = self.runtime.enter();
+        self.runner.process_events();
+        while self.refresh_receiver.try_recv().is_ok() {}
+        self.runner.refresh();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen_with(rows: &[&str]) -> Screen {
+        let width = rows
+            .iter()
+            .map(|row| row.chars().count())
+            .max()
+            .unwrap_or(0);
+        let mut screen = Screen::new(Vec2::new(width + 4, rows.len()));
+        for (y, row) in rows.iter().enumerate() {
+            let mut x = 0;
+            for c in row.chars() {
+                x += screen.put(Vec2::new(x, y), c, CellStyle::default());
+            }
+        }
+        screen
+    }
+
+    #[test]
+    fn find_reports_the_first_occurrence() {
+        let screen = screen_with(&["abc abc", "", "  abc"]);
+        assert_eq!(screen.find("abc"), Some(Vec2::new(0, 0)));
+        assert_eq!(screen.find("c a"), Some(Vec2::new(2, 0)));
+        assert_eq!(screen.find("  abc"), Some(Vec2::new(0, 2)));
+        assert_eq!(screen.find("abd"), None);
+        assert!(screen.contains(""));
+    }
+
+    #[test]
+    fn find_doesnt_span_rows() {
+        let screen = screen_with(&["ab", "cd"]);
+        assert_eq!(screen.find("b c"), None);
+        assert_eq!(screen.find("bc"), None);
+    }
+
+    #[test]
+    fn wide_characters_cover_the_next_cell() {
+        let screen = screen_with(&["a\u{4e16}b"]);
+        assert_eq!(screen.row(0), "a\u{4e16}b");
+        assert_eq!(screen.cell(Vec2::new(2, 0)).unwrap().text, "");
+        assert_eq!(screen.cell(Vec2::new(3, 0)).unwrap().text, "b");
+        assert_eq!(screen.find("\u{4e16}b"), Some(Vec2::new(1, 0)));
+    }
+
+    #[test]
+    fn wide_characters_that_dont_fit_are_dropped() {
+        let mut screen = Screen::new(Vec2::new(2, 1));
+        assert_eq!(
+            screen.put(Vec2::new(1, 0), '\u{4e16}', CellStyle::default()),
+            2
+        );
+        assert_eq!(screen.row(0), "");
+    }
+
+    #[test]
+    fn combining_characters_join_the_previous_cell() {
+        let screen = screen_with(&["e\u{301}x"]);
+        assert_eq!(screen.cell(Vec2::new(0, 0)).unwrap().text, "e\u{301}");
+        assert_eq!(screen.cell(Vec2::new(1, 0)).unwrap().text, "x");
+        assert!(screen.contains("e\u{301}x"));
+    }
+
+    #[test]
+    fn cells_off_screen_are_none() {
+        let screen = Screen::new(Vec2::new(3, 2));
+        assert!(screen.cell(Vec2::new(2, 1)).is_some());
+        assert!(screen.cell(Vec2::new(3, 0)).is_none());
+        assert!(screen.cell(Vec2::new(0, 2)).is_none());
+        assert_eq!(screen.row(5), "");
+    }
+}
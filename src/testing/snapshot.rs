@@ -0,0 +1,240 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use crate::cursive::theme::{BaseColor, Color, Effect};
+
+use super::{CellStyle, Screen, TestSession};
+
+/// Set this environment variable to any value to overwrite snapshots instead of comparing them.
+pub const UPDATE_SNAPSHOTS_ENV: &str = "SSH_UI_UPDATE_SNAPSHOTS";
+
+impl Screen {
+    /// The screen as plain text, one line per row with trailing spaces removed.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for line in self.lines() {
+            text.push_str(&line);
+            text.push('\n');
+        }
+        text
+    }
+
+    /// The screen as text, with every row followed by the runs of colors and effects it was drawn
+    /// with. Columns in the runs are zero-based and inclusive.
+    pub fn to_annotated(&self) -> String {
+        let size = self.size();
+        let number_width = size.y.saturating_sub(1).to_string().len();
+        let mut text = String::new();
+        for y in 0..size.y {
+            let _ = writeln!(text, "{:>w$}|{}", y, self.row(y), w = number_width);
+            let mut start = 0;
+            while start < size.x {
+                let style = self.style_at(start, y);
+                let mut end = start;
+                while end + 1 < size.x && self.style_at(end + 1, y) == style {
+                    end += 1;
+                }
+                let _ = writeln!(
+                    text,
+                    "{:>w$}| {}-{} {}",
+                    "",
+                    start,
+                    end,
+                    describe_style(&style),
+                    w = number_width
+                );
+                start = end + 1;
+            }
+        }
+        text
+    }
+
+    fn style_at(&self, x: usize, y: usize) -> CellStyle {
+        self.cell((x, y).into())
+            .map(|cell| cell.style)
+            .unwrap_or_default()
+    }
+}
+
+impl TestSession {
+    /// Compares the screen's text against the snapshot stored at `path`. See [`assert_snapshot`].
+    pub fn assert_snapshot(&self, path: impl AsRef<Path>) {
+        assert_snapshot(path, &self.screen().to_text());
+    }
+
+    /// Compares the screen's text, colors and effects against the snapshot stored at `path`. See
+    /// [`assert_snapshot`].
+    pub fn assert_annotated_snapshot(&self, path: impl AsRef<Path>) {
+        assert_snapshot(path, &self.screen().to_annotated());
+    }
+}
+
+/// Compares `actual` against the snapshot stored at `path`, panicking with a line diff if they
+/// differ.
+///
+/// Missing snapshots are written and the assertion passes, so the first run of a new test records
+/// its snapshot. Existing snapshots are overwritten instead of compared when the
+/// [`UPDATE_SNAPSHOTS_ENV`] environment variable is set. Relative paths are resolved against the
+/// current directory, which cargo sets to the package root when running tests.
+pub fn assert_snapshot(path: impl AsRef<Path>, actual: &str) {
+    let path = path.as_ref();
+    let update = std::env::var_os(UPDATE_SNAPSHOTS_ENV).is_some();
+    match fs::read_to_string(path) {
+        Ok(expected) if !update => {
+            if expected != actual {
+                panic!(
+                    "snapshot {} doesn't match (- expected, + actual):\n{}\nSet {}=1 to update it.",
+                    path.display(),
+                    diff(&expected, actual),
+                    UPDATE_SNAPSHOTS_ENV
+                );
+            }
+        }
+        _ => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .unwrap_or_else(|err| panic!("failed to create {}: {}", parent.display(), err));
+            }
+            fs::write(path, actual)
+                .unwrap_or_else(|err| panic!("failed to write {}: {}", path.display(), err));
+        }
+    }
+}
+
+/// A line diff of `expected` against `actual`, marking removed lines with `-` and added lines
+/// with `+`.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            let _ = writeln!(out, " {}", old[i]);
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            let _ = writeln!(out, "-{}", old[i]);
+            i += 1;
+        } else {
+            let _ = writeln!(out, "+{}", new[j]);
+            j += 1;
+        }
+    }
+    out
+}
+
+fn describe_style(style: &CellStyle) -> String {
+    let mut text = format!(
+        "{} on {}",
+        describe_color(style.colors.front),
+        describe_color(style.colors.back)
+    );
+    for effect in style.effects.iter() {
+        let name = match effect {
+            Effect::Simple => continue,
+            Effect::Reverse => "reverse",
+            Effect::Dim => "dim",
+            Effect::Bold => "bold",
+            Effect::Italic => "italic",
+            Effect::Strikethrough => "strikethrough",
+            Effect::Underline => "underline",
+            Effect::Blink => "blink",
+        };
+        text.push('+');
+        text.push_str(name);
+    }
+    text
+}
+
+fn describe_color(color: Color) -> String {
+    fn base(color: BaseColor) -> &'static str {
+        match color {
+            BaseColor::Black => "black",
+            BaseColor::Red => "red",
+            BaseColor::Green => "green",
+            BaseColor::Yellow => "yellow",
+            BaseColor::Blue => "blue",
+            BaseColor::Magenta => "magenta",
+            BaseColor::Cyan => "cyan",
+            BaseColor::White => "white",
+        }
+    }
+    match color {
+        Color::TerminalDefault => "default".to_string(),
+        Color::Dark(color) => base(color).to_string(),
+        Color::Light(color) => format!("light-{}", base(color)),
+        Color::Rgb(r, g, b) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+        Color::RgbLowRes(r, g, b) => format!("rgb{}{}{}", r, g, b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cursive::theme::{BaseColor, ColorPair};
+    use crate::cursive::Vec2;
+
+    use super::*;
+
+    #[test]
+    fn diff_marks_changed_lines() {
+        assert_eq!(diff("a\nb\nc\n", "a\nb\nc\n"), " a\n b\n c\n");
+        assert_eq!(diff("a\nb\nc\n", "a\nx\nc\n"), " a\n-b\n+x\n c\n");
+        assert_eq!(diff("a\nc\n", "a\nb\nc\n"), " a\n+b\n c\n");
+        assert_eq!(diff("a\nb\n", "b\n"), "-a\n b\n");
+        assert_eq!(diff("", "a\n"), "+a\n");
+    }
+
+    #[test]
+    fn annotated_screens_list_style_runs() {
+        let mut screen = Screen::new(Vec2::new(4, 2));
+        let bold_red = CellStyle {
+            colors: ColorPair {
+                front: Color::Light(BaseColor::Red),
+                back: Color::Rgb(0, 0, 0x80),
+            },
+            effects: Effect::Bold.into(),
+        };
+        screen.put(Vec2::new(1, 0), 'h', bold_red);
+        screen.put(Vec2::new(2, 0), 'i', bold_red);
+        assert_eq!(
+            screen.to_annotated(),
+            "0| hi\n \
+             | 0-0 default on default\n \
+             | 1-2 light-red on #000080+bold\n \
+             | 3-3 default on default\n\
+             1|\n \
+             | 0-3 default on default\n"
+        );
+        assert_eq!(screen.to_text(), " hi\n\n");
+    }
+
+    #[test]
+    fn missing_snapshots_are_recorded_then_compared() {
+        let path = std::env::temp_dir()
+            .join(format!("ssh_ui-snapshot-test-{}", std::process::id()))
+            .join("screen.txt");
+        let _ = fs::remove_file(&path);
+        assert_snapshot(&path, "first\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\n");
+        assert_snapshot(&path, "first\n");
+        let mismatch = std::panic::catch_unwind(|| assert_snapshot(&path, "second\n"));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        let message = *mismatch.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.contains("-first\n+second"), "{}", message);
+    }
+}
@@ -0,0 +1,310 @@
+use crate::cursive::reexports::enumset::EnumSet;
+use crate::cursive::theme::{Color, Effect};
+use crate::cursive::Vec2;
+
+use super::{Cell, CellStyle, Screen};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParseState {
+    Ground,
+    Escape,
+    Csi,
+    Osc,
+    OscEscape,
+}
+
+/// A small terminal emulator that turns the escape sequences written by a session's backend back
+/// into a [`Screen`].
+///
+/// It understands cursor movement, erasing, colors and effects, and window titles, which is
+/// everything the backend uses. It doesn't scroll or wrap: text past the right edge of the screen
+/// is dropped, and so are line feeds on the last row.
+pub struct Terminal {
+    screen: Screen,
+    cursor: Vec2,
+    style: CellStyle,
+    state: ParseState,
+    params: String,
+    osc: String,
+    title: Option<String>,
+    // The start of a UTF-8 sequence that was split across two writes.
+    pending: Vec<u8>,
+}
+
+impl Terminal {
+    /// Creates a blank terminal of the given size.
+    pub fn new(size: Vec2) -> Self {
+        Self {
+            screen: Screen::new(size),
+            cursor: Vec2::zero(),
+            style: CellStyle::default(),
+            state: ParseState::Ground,
+            params: String::new(),
+            osc: String::new(),
+            title: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The current contents of the screen.
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    /// The last title set with an OSC 0 or OSC 2 sequence, if any.
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Resizes the screen, keeping whatever fits of its contents.
+    pub fn resize(&mut self, size: Vec2) {
+        let mut screen = Screen::new(size);
+        for y in 0..size.y.min(self.screen.size().y) {
+            for x in 0..size.x.min(self.screen.size().x) {
+                let pos = Vec2::new(x, y);
+                if let (Some(cell), Some(old)) = (screen.cell_mut(pos), self.screen.cell(pos)) {
+                    *cell = old.clone();
+                }
+            }
+        }
+        self.screen = screen;
+        self.cursor = self.cursor.zip_map(size.saturating_sub((1, 1)), usize::min);
+    }
+
+    /// Feeds output written to the terminal through the emulator.
+    pub fn feed(&mut self, data: &[u8]) {
+        let mut bytes = std::mem::take(&mut self.pending);
+        bytes.extend_from_slice(data);
+        let mut rest = &bytes[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => {
+                    self.feed_str(text);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, after) = rest.split_at(err.valid_up_to());
+                    // Only the prefix up to `valid_up_to` is known to be valid UTF-8.
+                    self.feed_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match err.error_len() {
+                        Some(len) => {
+                            self.feed_str("\u{fffd}");
+                            rest = &after[len..];
+                        }
+                        None => {
+                            self.pending = after.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn feed_str(&mut self, text: &str) {
+        for c in text.chars() {
+            match self.state {
+                ParseState::Ground => match c {
+                    '\x1b' => self.state = ParseState::Escape,
+                    '\r' => self.cursor.x = 0,
+                    '\n' => self.cursor.y = (self.cursor.y + 1).min(self.last_row()),
+                    '\x08' => self.cursor.x = self.cursor.x.saturating_sub(1),
+                    '\t' => self.cursor.x = (self.cursor.x / 8 + 1) * 8,
+                    c if c.is_control() => {}
+                    c => {
+                        let width = self.screen.put(self.cursor, c, self.style);
+                        self.cursor.x += width;
+                    }
+                },
+                ParseState::Escape => match c {
+                    '[' => {
+                        self.params.clear();
+                        self.state = ParseState::Csi;
+                    }
+                    ']' => {
+                        self.osc.clear();
+                        self.state = ParseState::Osc;
+                    }
+                    _ => self.state = ParseState::Ground,
+                },
+                ParseState::Csi => {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        self.csi(c);
+                        self.state = ParseState::Ground;
+                    } else {
+                        self.params.push(c);
+                    }
+                }
+                ParseState::Osc => match c {
+                    '\x07' => {
+                        self.osc();
+                        self.state = ParseState::Ground;
+                    }
+                    '\x1b' => self.state = ParseState::OscEscape,
+                    c => self.osc.push(c),
+                },
+                ParseState::OscEscape => {
+                    // Anything after the escape ends the string, though only `\` should.
+                    self.osc();
+                    self.state = ParseState::Ground;
+                }
+            }
+        }
+    }
+
+    fn last_row(&self) -> usize {
+        self.screen.size().y.saturating_sub(1)
+    }
+
+    fn last_column(&self) -> usize {
+        self.screen.size().x.saturating_sub(1)
+    }
+
+    fn osc(&mut self) {
+        if let Some((kind, text)) = self.osc.split_once(';') {
+            if kind == "0" || kind == "2" {
+                self.title = Some(text.to_string());
+            }
+        }
+    }
+
+    fn csi(&mut self, final_byte: char) {
+        // Private modes such as `?25l` only change how the terminal behaves, not what's on it.
+        if self.params.starts_with('?') {
+            return;
+        }
+        let params: Vec<usize> = self
+            .params
+            .split(';')
+            .map(|p| p.parse().unwrap_or(0))
+            .collect();
+        let arg = |i: usize| params.get(i).copied().unwrap_or(0);
+        // Movement counts and positions treat a missing or zero parameter as one.
+        let count = |i: usize| arg(i).max(1);
+        match final_byte {
+            'H' | 'f' => {
+                self.cursor = Vec2::new(
+                    (count(1) - 1).min(self.last_column()),
+                    (count(0) - 1).min(self.last_row()),
+                );
+            }
+            'A' => self.cursor.y = self.cursor.y.saturating_sub(count(0)),
+            'B' => self.cursor.y = (self.cursor.y + count(0)).min(self.last_row()),
+            'C' => self.cursor.x = (self.cursor.x + count(0)).min(self.last_column()),
+            'D' => self.cursor.x = self.cursor.x.saturating_sub(count(0)),
+            'G' => self.cursor.x = (count(0) - 1).min(self.last_column()),
+            'd' => self.cursor.y = (count(0) - 1).min(self.last_row()),
+            'J' => self.erase_display(arg(0)),
+            'K' => self.erase_line(arg(0)),
+            'm' => self.sgr(&params),
+            _ => {}
+        }
+    }
+
+    fn erase(&mut self, y: usize, columns: std::ops::Range<usize>) {
+        // Erased cells take the current colors, like xterm's background color erase.
+        let cell = Cell {
+            text: " ".to_string(),
+            style: CellStyle {
+                colors: self.style.colors,
+                effects: EnumSet::new(),
+            },
+        };
+        for x in columns {
+            if let Some(target) = self.screen.cell_mut(Vec2::new(x, y)) {
+                *target = cell.clone();
+            }
+        }
+    }
+
+    fn erase_display(&mut self, mode: usize) {
+        let size = self.screen.size();
+        let rows = match mode {
+            0 => {
+                self.erase_line(0);
+                self.cursor.y + 1..size.y
+            }
+            1 => {
+                self.erase_line(1);
+                0..self.cursor.y
+            }
+            _ => 0..size.y,
+        };
+        for y in rows {
+            self.erase(y, 0..size.x);
+        }
+    }
+
+    fn erase_line(&mut self, mode: usize) {
+        let width = self.screen.size().x;
+        let columns = match mode {
+            0 => self.cursor.x..width,
+            1 => 0..self.cursor.x + 1,
+            _ => 0..width,
+        };
+        self.erase(self.cursor.y, columns);
+    }
+
+    fn sgr(&mut self, params: &[usize]) {
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => self.style = CellStyle::default(),
+                1 => self.set_effect(Effect::Bold, true),
+                2 => self.set_effect(Effect::Dim, true),
+                3 => self.set_effect(Effect::Italic, true),
+                4 => self.set_effect(Effect::Underline, true),
+                5 => self.set_effect(Effect::Blink, true),
+                7 => self.set_effect(Effect::Reverse, true),
+                9 => self.set_effect(Effect::Strikethrough, true),
+                21 | 22 => {
+                    self.set_effect(Effect::Bold, false);
+                    self.set_effect(Effect::Dim, false);
+                }
+                23 => self.set_effect(Effect::Italic, false),
+                24 => self.set_effect(Effect::Underline, false),
+                25 => self.set_effect(Effect::Blink, false),
+                27 => self.set_effect(Effect::Reverse, false),
+                29 => self.set_effect(Effect::Strikethrough, false),
+                30..=37 => self.style.colors.front = Color::from_256colors((param - 30) as u8),
+                40..=47 => self.style.colors.back = Color::from_256colors((param - 40) as u8),
+                90..=97 => self.style.colors.front = Color::from_256colors((param - 82) as u8),
+                100..=107 => self.style.colors.back = Color::from_256colors((param - 92) as u8),
+                38 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.style.colors.front = color;
+                    }
+                }
+                48 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.style.colors.back = color;
+                    }
+                }
+                39 => self.style.colors.front = Color::TerminalDefault,
+                49 => self.style.colors.back = Color::TerminalDefault,
+                _ => {}
+            }
+        }
+    }
+
+    fn set_effect(&mut self, effect: Effect, enabled: bool) {
+        if enabled {
+            self.style.effects.insert(effect);
+        } else {
+            self.style.effects.remove(effect);
+        }
+    }
+}
+
+/// Parses the rest of a `38` or `48` SGR parameter, either `5;n` or `2;r;g;b`.
+fn extended_color(params: &mut impl Iterator<Item = usize>) -> Option<Color> {
+    match params.next()? {
+        5 => Some(Color::from_256colors(params.next()? as u8)),
+        2 => Some(Color::Rgb(
+            params.next()? as u8,
+            params.next()? as u8,
+            params.next()? as u8,
+        )),
+        _ => None,
+    }
+}
@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ssh_ui::cursive::views::TextView;
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::{KeyPair, PublicKey};
+use ssh_ui::testing::LoopbackServer;
+use ssh_ui::{App, AppServer, AppSession, AuditEvent, AuditSink, SessionHandle};
+use tokio::sync::mpsc::Sender;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SIZE: Vec2 = Vec2 { x: 40, y: 10 };
+
+struct HelloApp;
+
+struct HelloSession;
+
+impl App for HelloApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        Box::new(HelloSession)
+    }
+}
+
+impl AppSession for HelloSession {
+    fn on_start(
+        &mut self,
+        _siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        Ok(Box::new(TextView::new("hello")))
+    }
+}
+
+#[derive(Default)]
+struct CapturingSink(Mutex<Vec<AuditEvent>>);
+
+impl CapturingSink {
+    /// The fingerprints of the keys offered and of those accepted, in order, emptying the log.
+    fn take_keys(&self) -> (Vec<String>, Vec<String>) {
+        let mut offered = Vec::new();
+        let mut accepted = Vec::new();
+        for event in self.0.lock().unwrap().drain(..) {
+            match event {
+                AuditEvent::KeyOffered { fingerprint, .. } => offered.push(fingerprint),
+                AuditEvent::Auth {
+                    fingerprint: Some(fingerprint),
+                    accepted: true,
+                    ..
+                } => accepted.push(fingerprint),
+                _ => {}
+            }
+        }
+        (offered, accepted)
+    }
+}
+
+impl AuditSink for CapturingSink {
+    fn record(&self, event: &AuditEvent) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+}
+
+// A single test, since the audit sink is shared by every server in the process.
+#[tokio::test]
+async fn only_signed_keys_are_audited_as_accepted() {
+    let sink = Arc::new(CapturingSink::default());
+    let server = AppServer::new_with_port(0).with_audit_sink(sink.clone());
+    let server = LoopbackServer::start(server, Arc::new(HelloApp))
+        .await
+        .unwrap();
+
+    let key = KeyPair::generate_ed25519().unwrap();
+    let fingerprint = key.clone_public_key().unwrap().fingerprint();
+    let mut client = server.connect_with_key("alice", key, SIZE).await.unwrap();
+    client.wait_for("hello", TIMEOUT).await.unwrap();
+    let (offered, accepted) = sink.take_keys();
+    assert!(offered.iter().all(|offered| *offered == fingerprint));
+    assert_eq!(accepted, [fingerprint]);
+
+    let queried = KeyPair::generate_ed25519().unwrap();
+    let queried = queried.clone_public_key().unwrap();
+    let signing = KeyPair::generate_ed25519().unwrap();
+    let signed = signing.clone_public_key().unwrap().fingerprint();
+    let mut client = server
+        .connect_with_swapped_key("mallory", queried.clone(), signing, SIZE)
+        .await
+        .unwrap();
+    client.wait_for("hello", TIMEOUT).await.unwrap();
+    let (offered, accepted) = sink.take_keys();
+    assert_eq!(offered, [queried.fingerprint(), signed.clone()]);
+    assert_eq!(accepted, [signed]);
+}
@@ -0,0 +1,79 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ssh_ui::cursive::views::TextView;
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::{KeyPair, PublicKey};
+use ssh_ui::testing::LoopbackServer;
+use ssh_ui::{AdminConsole, App, AppServer, AppSession, SessionHandle};
+use tokio::sync::mpsc::Sender;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shows the fingerprint of the key the session was started with.
+struct WhoAmIApp;
+
+struct WhoAmISession;
+
+impl App for WhoAmIApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        Box::new(WhoAmISession)
+    }
+}
+
+impl AppSession for WhoAmISession {
+    fn on_start(
+        &mut self,
+        _siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        let fingerprint = pub_key.map_or_else(|| "none".to_string(), |key| key.fingerprint());
+        Ok(Box::new(TextView::new(format!("key {}", fingerprint))))
+    }
+}
+
+fn size() -> Vec2 {
+    Vec2::new(100, 24)
+}
+
+fn key_pair() -> KeyPair {
+    KeyPair::generate_ed25519().unwrap()
+}
+
+async fn start(server: AppServer) -> LoopbackServer {
+    LoopbackServer::start(server, Arc::new(WhoAmIApp))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn console_is_opened_for_an_authorized_key() {
+    let admin = key_pair();
+    let console = AdminConsole::on_username("admin", vec![admin.clone_public_key().unwrap()]);
+    let server = start(AppServer::new_with_port(0).with_admin_console(console)).await;
+    let mut client = server
+        .connect_with_key("admin", admin, size())
+        .await
+        .unwrap();
+    client.wait_for("Admin console", TIMEOUT).await.unwrap();
+}
+
+#[tokio::test]
+async fn console_is_refused_to_a_client_that_only_queried_an_authorized_key() {
+    let admin = key_pair().clone_public_key().unwrap();
+    let console = AdminConsole::on_username("admin", vec![admin.clone()]);
+    let server = start(AppServer::new_with_port(0).with_admin_console(console)).await;
+    let mut client = server
+        .connect_with_swapped_key("admin", admin, key_pair(), size())
+        .await
+        .unwrap();
+    client.wait_for("Not authorized.", TIMEOUT).await.unwrap();
+    client.wait_for_close(TIMEOUT).await.unwrap();
+}
@@ -0,0 +1,83 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ssh_ui::cursive::views::TextView;
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::PublicKey;
+use ssh_ui::testing::LoopbackServer;
+use ssh_ui::{App, AppServer, AppSession, Banner, SessionHandle};
+use tokio::sync::mpsc::Sender;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SIZE: Vec2 = Vec2 { x: 60, y: 20 };
+
+struct PlainApp;
+
+struct PlainSession;
+
+impl App for PlainApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        Box::new(PlainSession)
+    }
+}
+
+impl AppSession for PlainSession {
+    fn on_start(
+        &mut self,
+        _siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        Ok(Box::new(TextView::new("the app")))
+    }
+}
+
+#[tokio::test]
+async fn banner_comes_before_authentication_and_the_motd_after() {
+    let banner = Banner::PerConnection(Arc::new(|peer| {
+        Some(format!("Authorized use only, {}.\r\n", peer.ip()))
+    }));
+    let server = AppServer::new_with_port(0)
+        .with_banner(banner)
+        .with_motd("Maintenance at noon");
+    let server = LoopbackServer::start(server, Arc::new(PlainApp))
+        .await
+        .unwrap();
+
+    // The client only listens for a banner until it has authenticated.
+    let mut client = server.connect("alice", SIZE).await.unwrap();
+    assert_eq!(
+        client.banner().as_deref(),
+        Some("Authorized use only, 127.0.0.1.\r\n")
+    );
+
+    let screen = client
+        .wait_for("Maintenance at noon", TIMEOUT)
+        .await
+        .unwrap();
+    assert!(screen.contains("Message of the day"));
+    client.type_text(" ").await.unwrap();
+    client
+        .wait_until(TIMEOUT, |screen| {
+            screen.contains("the app") && !screen.contains("Maintenance at noon")
+        })
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn no_banner_unless_one_is_configured() {
+    let server = LoopbackServer::start(AppServer::new_with_port(0), Arc::new(PlainApp))
+        .await
+        .unwrap();
+
+    let mut client = server.connect("alice", SIZE).await.unwrap();
+    client.wait_for("the app", TIMEOUT).await.unwrap();
+    assert_eq!(client.banner(), None);
+}
@@ -0,0 +1,88 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ssh_ui::cursive::traits::Nameable;
+use ssh_ui::cursive::views::TextView;
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::PublicKey;
+use ssh_ui::testing::LoopbackServer;
+use ssh_ui::{App, AppServer, AppSession, Incident, SessionHandle};
+use tokio::sync::mpsc::Sender;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SIZE: Vec2 = Vec2 { x: 100, y: 30 };
+
+/// Counts presses of `c`, and panics on `p`.
+struct FragileApp {
+    incidents: Arc<Mutex<Vec<String>>>,
+}
+
+struct FragileSession;
+
+impl App for FragileApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        Box::new(FragileSession)
+    }
+
+    fn on_error(&self, incident: &Incident) {
+        self.incidents.lock().unwrap().push(incident.id.clone());
+    }
+}
+
+impl AppSession for FragileSession {
+    fn on_start(
+        &mut self,
+        siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        siv.add_global_callback('c', |siv| {
+            siv.call_on_name("count", |text: &mut TextView| {
+                let count: usize = text.get_content().source()[6..].parse().unwrap();
+                text.set_content(format!("count {}", count + 1));
+            });
+        });
+        siv.add_global_callback('p', |_| panic!("the app fell over"));
+        Ok(Box::new(TextView::new("count 0").with_name("count")))
+    }
+}
+
+#[tokio::test]
+async fn a_panicking_session_shows_its_incident_without_affecting_others() {
+    let incidents = Arc::new(Mutex::new(Vec::new()));
+    let app = FragileApp {
+        incidents: incidents.clone(),
+    };
+    let server = LoopbackServer::start(AppServer::new_with_port(0), Arc::new(app))
+        .await
+        .unwrap();
+    let mut failing = server.connect("alice", SIZE).await.unwrap();
+    let mut bystander = server.connect("bob", SIZE).await.unwrap();
+    failing.wait_for("count 0", TIMEOUT).await.unwrap();
+    bystander.wait_for("count 0", TIMEOUT).await.unwrap();
+
+    failing.type_text("p").await.unwrap();
+    let screen = failing
+        .wait_for("Something went wrong", TIMEOUT)
+        .await
+        .unwrap();
+    // The hook runs before the error screen is shown.
+    let incident = incidents.lock().unwrap().clone();
+    assert_eq!(incident.len(), 1);
+    assert!(screen.contains(&format!("incident {}", incident[0])));
+
+    bystander.type_text("c").await.unwrap();
+    bystander.wait_for("count 1", TIMEOUT).await.unwrap();
+
+    // The error screen's only button disconnects.
+    failing.type_text("\n").await.unwrap();
+    failing.wait_for_close(TIMEOUT).await.unwrap();
+    bystander.type_text("c").await.unwrap();
+    bystander.wait_for("count 2", TIMEOUT).await.unwrap();
+}
@@ -0,0 +1,153 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ssh_ui::cursive::event::{Event, EventResult, EventTrigger, Key};
+use ssh_ui::cursive::traits::Resizable;
+use ssh_ui::cursive::views::{Canvas, OnEventView};
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::PublicKey;
+use ssh_ui::testing::{assert_snapshot, LoopbackServer};
+use ssh_ui::{App, AppServer, AppSession, SessionHandle};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shows its own size and the last key it got, and quits on `q`.
+struct EchoApp;
+
+struct EchoSession;
+
+impl App for EchoApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        Box::new(EchoSession)
+    }
+}
+
+impl AppSession for EchoSession {
+    fn on_start(
+        &mut self,
+        siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        siv.add_global_callback('q', |siv| siv.quit());
+        let canvas = Canvas::new("none".to_string()).with_draw(|last_key, printer| {
+            printer.print(
+                (0, 0),
+                &format!("size {}x{}", printer.size.x, printer.size.y),
+            );
+            printer.print((0, 1), &format!("key {}", last_key));
+        });
+        let keys = EventTrigger::from_fn(|event| {
+            matches!(
+                event,
+                Event::Key(_) | Event::Ctrl(_) | Event::Shift(_) | Event::Char('a'..='p')
+            )
+        });
+        let view = OnEventView::new(canvas).on_pre_event_inner(keys, |canvas, event| {
+            *canvas.state_mut() = format!("{:?}", event);
+            Some(EventResult::Consumed(None))
+        });
+        Ok(Box::new(view.full_screen()))
+    }
+}
+
+#[tokio::test]
+async fn connects_resizes_and_sends_keys() {
+    let server = LoopbackServer::start(AppServer::new_with_port(0), Arc::new(EchoApp))
+        .await
+        .unwrap();
+    let mut client = server.connect("alice", Vec2::new(80, 24)).await.unwrap();
+    // Cursive pads every layer and draws its shadow, which takes two cells each way.
+    client.wait_for("size 78x22", TIMEOUT).await.unwrap();
+
+    client.resize(Vec2::new(60, 15)).await.unwrap();
+    let screen = client.wait_for("size 58x13", TIMEOUT).await.unwrap();
+    assert_eq!(screen.size(), Vec2::new(60, 15));
+
+    client.press(Key::PageDown).await.unwrap();
+    client.wait_for("key Key(PageDown)", TIMEOUT).await.unwrap();
+    client.send(b"\x1b[1;5A").await.unwrap();
+    client.wait_for("key Ctrl(Up)", TIMEOUT).await.unwrap();
+    client.type_text("b").await.unwrap();
+    client.wait_for("key Char('b')", TIMEOUT).await.unwrap();
+
+    client.type_text("q").await.unwrap();
+    client.wait_for_close(TIMEOUT).await.unwrap();
+}
+
+#[tokio::test]
+async fn resizing_redraws_the_whole_screen() {
+    let server = LoopbackServer::start(AppServer::new_with_port(0), Arc::new(EchoApp))
+        .await
+        .unwrap();
+    let mut client = server.connect("alice", Vec2::new(30, 6)).await.unwrap();
+    client.wait_for("size 28x4", TIMEOUT).await.unwrap();
+
+    // The whole screen is drawn for the new size, text, colors and shadow alike.
+    client.resize(Vec2::new(40, 8)).await.unwrap();
+    let screen = client.wait_for("size 38x6", TIMEOUT).await.unwrap();
+    assert_snapshot("tests/snapshots/resized.txt", &screen.to_annotated());
+}
+
+#[tokio::test]
+async fn sessions_on_one_connection_are_independent() {
+    let server = LoopbackServer::start(AppServer::new_with_port(0), Arc::new(EchoApp))
+        .await
+        .unwrap();
+    let mut first = server.connect("alice", Vec2::new(80, 24)).await.unwrap();
+    first.wait_for("size 78x22", TIMEOUT).await.unwrap();
+    let mut second = first.open_session(Vec2::new(60, 15)).await.unwrap();
+    second.wait_for("size 58x13", TIMEOUT).await.unwrap();
+
+    first.type_text("b").await.unwrap();
+    first.wait_for("key Char('b')", TIMEOUT).await.unwrap();
+    second.type_text("c").await.unwrap();
+    let screen = second.wait_for("key Char('c')", TIMEOUT).await.unwrap();
+    assert!(!screen.contains("size 78x22"));
+    assert!(first.screen().contains("key Char('b')"));
+
+    first.type_text("q").await.unwrap();
+    first.wait_for_close(TIMEOUT).await.unwrap();
+    assert!(!second.is_closed());
+    second.type_text("d").await.unwrap();
+    second.wait_for("key Char('d')", TIMEOUT).await.unwrap();
+}
+
+#[tokio::test]
+async fn dropping_the_server_closes_its_listener() {
+    let server = LoopbackServer::start(AppServer::new_with_port(0), Arc::new(EchoApp))
+        .await
+        .unwrap();
+    let addr = server.addr();
+    TcpStream::connect(addr).await.unwrap();
+    drop(server);
+
+    let deadline = Instant::now() + TIMEOUT;
+    while TcpStream::connect(addr).await.is_ok() {
+        assert!(
+            Instant::now() < deadline,
+            "the server still accepts connections"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn dropping_the_server_closes_its_connections() {
+    let server = LoopbackServer::start(AppServer::new_with_port(0), Arc::new(EchoApp))
+        .await
+        .unwrap();
+    let mut client = server.connect("alice", Vec2::new(80, 24)).await.unwrap();
+    client.wait_for("size 78x22", TIMEOUT).await.unwrap();
+
+    drop(server);
+    client.wait_for_close(TIMEOUT).await.unwrap();
+}
@@ -0,0 +1,49 @@
+use std::error::Error;
+
+use ssh_ui::cursive::traits::Nameable;
+use ssh_ui::cursive::views::TextArea;
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::PublicKey;
+use ssh_ui::testing::TestSession;
+use ssh_ui::{AppSession, SessionHandle, MAX_REPLAYED_PASTE_CHARS};
+use tokio::sync::mpsc::Sender;
+
+/// A text area that keeps the default paste handling.
+struct EditorSession;
+
+impl AppSession for EditorSession {
+    fn on_start(
+        &mut self,
+        _siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        Ok(Box::new(TextArea::new().with_name("editor")))
+    }
+}
+
+fn content(session: &mut TestSession) -> String {
+    session
+        .cursive()
+        .call_on_name("editor", |editor: &mut TextArea| {
+            editor.get_content().to_string()
+        })
+        .unwrap()
+}
+
+#[test]
+fn pastes_are_replayed_as_typing() {
+    let mut session =
+        TestSession::from_session(Box::new(EditorSession), Vec2::new(40, 10)).unwrap();
+    session.paste("one\ntwo three");
+    assert_eq!(content(&mut session), "one\ntwo three");
+}
+
+#[test]
+fn only_the_start_of_a_long_paste_is_replayed() {
+    let mut session =
+        TestSession::from_session(Box::new(EditorSession), Vec2::new(40, 10)).unwrap();
+    session.paste(&"x".repeat(MAX_REPLAYED_PASTE_CHARS * 2));
+    assert_eq!(content(&mut session).len(), MAX_REPLAYED_PASTE_CHARS);
+}
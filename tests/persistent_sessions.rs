@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ssh_ui::cursive::views::TextView;
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::{KeyPair, PublicKey};
+use ssh_ui::testing::LoopbackServer;
+use ssh_ui::{App, AppServer, AppSession, SessionHandle};
+use tokio::sync::mpsc::Sender;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SIZE: Vec2 = Vec2 { x: 40, y: 10 };
+
+/// Numbers its sessions, so a test can tell whether it got a new one.
+struct CountingApp {
+    sessions: Arc<AtomicUsize>,
+}
+
+struct CountingSession {
+    number: usize,
+}
+
+impl App for CountingApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        let number = self.sessions.fetch_add(1, Ordering::SeqCst) + 1;
+        Box::new(CountingSession { number })
+    }
+}
+
+impl AppSession for CountingSession {
+    fn on_start(
+        &mut self,
+        _siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        Ok(Box::new(TextView::new(format!("session {}", self.number))))
+    }
+}
+
+async fn start() -> LoopbackServer {
+    let server = AppServer::new_with_port(0).with_persistent_sessions(Duration::from_secs(60));
+    let app = CountingApp {
+        sessions: Arc::new(AtomicUsize::new(0)),
+    };
+    LoopbackServer::start(server, Arc::new(app)).await.unwrap()
+}
+
+#[tokio::test]
+async fn anonymous_clients_never_share_a_session() {
+    let server = start().await;
+
+    let mut first = server.connect_anonymous(SIZE).await.unwrap();
+    first.wait_for("session 1", TIMEOUT).await.unwrap();
+    first.disconnect().await.unwrap();
+
+    let mut second = server.connect_anonymous(SIZE).await.unwrap();
+    let screen = second.wait_for("session 2", TIMEOUT).await.unwrap();
+    assert!(!screen.contains("session 1"));
+}
+
+#[tokio::test]
+async fn clients_with_the_same_key_pick_their_session_back_up() {
+    let server = start().await;
+    let key = KeyPair::generate_ed25519().unwrap();
+
+    let mut first = server
+        .connect_with_key("alice", key.clone(), SIZE)
+        .await
+        .unwrap();
+    first.wait_for("session 1", TIMEOUT).await.unwrap();
+    first.disconnect().await.unwrap();
+
+    // Someone else with the same username doesn't get it.
+    let mut other = server.connect("alice", SIZE).await.unwrap();
+    other.wait_for("session 2", TIMEOUT).await.unwrap();
+
+    let mut again = server.connect_with_key("alice", key, SIZE).await.unwrap();
+    again.wait_for("session 1", TIMEOUT).await.unwrap();
+}
+
+#[tokio::test]
+async fn querying_a_key_is_not_enough_to_pick_up_its_session() {
+    let server = start().await;
+    let key = KeyPair::generate_ed25519().unwrap();
+    let public_key = key.clone_public_key().unwrap();
+
+    let mut first = server.connect_with_key("alice", key, SIZE).await.unwrap();
+    first.wait_for("session 1", TIMEOUT).await.unwrap();
+    first.disconnect().await.unwrap();
+
+    // The server accepts the query for alice's key, but the request is signed with another one.
+    let mut impostor = server
+        .connect_with_swapped_key(
+            "alice",
+            public_key,
+            KeyPair::generate_ed25519().unwrap(),
+            SIZE,
+        )
+        .await
+        .unwrap();
+    let screen = impostor.wait_for("session 2", TIMEOUT).await.unwrap();
+    assert!(!screen.contains("session 1"));
+}
@@ -0,0 +1,141 @@
+#![cfg(feature = "tracing")]
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ssh_ui::cursive::views::TextView;
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::PublicKey;
+use ssh_ui::testing::LoopbackServer;
+use ssh_ui::{App, AppServer, AppSession, SessionHandle};
+use tokio::sync::mpsc::Sender;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SIZE: Vec2 = Vec2 { x: 40, y: 10 };
+
+struct HelloApp;
+
+struct HelloSession;
+
+impl App for HelloApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        Box::new(HelloSession)
+    }
+}
+
+impl AppSession for HelloSession {
+    fn on_start(
+        &mut self,
+        _siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        Ok(Box::new(TextView::new("hello")))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CapturedSpan {
+    name: &'static str,
+    parent: Option<u64>,
+    fields: HashMap<&'static str, String>,
+}
+
+impl Visit for CapturedSpan {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields.insert(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.fields.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+/// Keeps every span created in the process, along with the fields recorded on it.
+#[derive(Clone, Default)]
+struct CapturingSubscriber {
+    next_id: Arc<AtomicU64>,
+    spans: Arc<Mutex<HashMap<u64, CapturedSpan>>>,
+}
+
+impl CapturingSubscriber {
+    fn spans_named(&self, name: &str) -> Vec<(u64, CapturedSpan)> {
+        let spans = self.spans.lock().unwrap();
+        let spans = spans.iter().filter(|(_, span)| span.name == name);
+        spans.map(|(id, span)| (*id, span.clone())).collect()
+    }
+}
+
+impl Subscriber for CapturingSubscriber {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut span = CapturedSpan {
+            name: attributes.metadata().name(),
+            parent: attributes.parent().map(Id::into_u64),
+            fields: HashMap::new(),
+        };
+        attributes.record(&mut span);
+        self.spans.lock().unwrap().insert(id, span);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(span);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+// A single test, since the subscriber is shared by every thread in the process.
+#[tokio::test]
+async fn sessions_are_spanned_under_their_connection() {
+    let subscriber = CapturingSubscriber::default();
+    tracing::subscriber::set_global_default(subscriber.clone()).unwrap();
+    let server = LoopbackServer::start(AppServer::new_with_port(0), Arc::new(HelloApp))
+        .await
+        .unwrap();
+
+    let mut client = server.connect("alice", SIZE).await.unwrap();
+    client.wait_for("hello", TIMEOUT).await.unwrap();
+
+    let connections = subscriber.spans_named("connection");
+    let [(connection_id, connection)] = connections.as_slice() else {
+        panic!("expected one connection span, got {:?}", connections);
+    };
+    assert_eq!(connection.fields["frontend"], "ssh");
+    assert_eq!(connection.fields["username"], "alice");
+    let peer = &connection.fields["peer"];
+    assert!(peer.starts_with("127.0.0.1:"), "peer was {}", peer);
+    assert_ne!(*peer, server.addr().to_string());
+
+    let sessions = subscriber.spans_named("session");
+    let [(_, session)] = sessions.as_slice() else {
+        panic!("expected one session span, got {:?}", sessions);
+    };
+    assert_eq!(session.parent, Some(*connection_id));
+    assert!(session.fields.contains_key("handle"));
+}
@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ssh_ui::cursive::views::TextView;
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::PublicKey;
+use ssh_ui::testing::LoopbackServer;
+use ssh_ui::{App, AppServer, AppSession, SessionHandle, SessionTimeouts};
+use tokio::sync::mpsc::Sender;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SIZE: Vec2 = Vec2 { x: 80, y: 24 };
+const WARNING: &str = "Are you still there?";
+
+struct IdleApp;
+
+struct IdleSession;
+
+impl App for IdleApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        Box::new(IdleSession)
+    }
+}
+
+impl AppSession for IdleSession {
+    fn on_start(
+        &mut self,
+        _siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        Ok(Box::new(TextView::new("waiting around")))
+    }
+}
+
+#[tokio::test]
+async fn idle_sessions_are_warned_then_disconnected_unless_the_user_types() {
+    let server = AppServer::new_with_port(0).with_session_timeouts(SessionTimeouts {
+        idle_timeout: Some(Duration::from_secs(3)),
+        idle_warning: Some(Duration::from_secs(2)),
+        ..Default::default()
+    });
+    let server = LoopbackServer::start(server, Arc::new(IdleApp))
+        .await
+        .unwrap();
+    let mut client = server.connect("alice", SIZE).await.unwrap();
+    client.wait_for("waiting around", TIMEOUT).await.unwrap();
+
+    let screen = client.wait_for(WARNING, TIMEOUT).await.unwrap();
+    assert!(screen.contains("You will be disconnected in"));
+    client.type_text("a").await.unwrap();
+    client
+        .wait_until(TIMEOUT, |screen| !screen.contains(WARNING))
+        .await
+        .unwrap();
+
+    client.wait_for(WARNING, TIMEOUT).await.unwrap();
+    client.wait_for_close(TIMEOUT).await.unwrap();
+}
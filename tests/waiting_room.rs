@@ -0,0 +1,110 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ssh_ui::cursive::views::TextView;
+use ssh_ui::cursive::{Cursive, Vec2, View};
+use ssh_ui::russh_keys::key::PublicKey;
+use ssh_ui::testing::{LoopbackClient, LoopbackServer};
+use ssh_ui::{App, AppServer, AppSession, SessionHandle, SessionLimits, SessionOverflow};
+use tokio::sync::mpsc::Sender;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const SIZE: Vec2 = Vec2 { x: 60, y: 12 };
+
+/// Numbers its sessions in the order they start, and quits on `x`.
+struct CountingApp {
+    sessions: Arc<AtomicUsize>,
+}
+
+struct CountingSession {
+    number: usize,
+}
+
+impl App for CountingApp {
+    fn on_load(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn new_session(&self) -> Box<dyn AppSession> {
+        let number = self.sessions.fetch_add(1, Ordering::SeqCst) + 1;
+        Box::new(CountingSession { number })
+    }
+}
+
+impl AppSession for CountingSession {
+    fn on_start(
+        &mut self,
+        siv: &mut Cursive,
+        _session_handle: SessionHandle,
+        _pub_key: Option<PublicKey>,
+        _force_refresh_sender: Sender<()>,
+    ) -> Result<Box<dyn View>, Box<dyn Error>> {
+        siv.add_global_callback('x', |siv| siv.quit());
+        Ok(Box::new(TextView::new(format!("session {}", self.number))))
+    }
+}
+
+/// A server with a single session slot and a queue for everyone else.
+async fn start() -> LoopbackServer {
+    let server = AppServer::new_with_port(0).with_session_limits(SessionLimits {
+        max_sessions: Some(1),
+        overflow: SessionOverflow::Queue,
+    });
+    let app = CountingApp {
+        sessions: Arc::new(AtomicUsize::new(0)),
+    };
+    LoopbackServer::start(server, Arc::new(app)).await.unwrap()
+}
+
+async fn wait_in_line(client: &mut LoopbackClient, position: usize) {
+    let line = format!("You are number {} in line.", position);
+    client.wait_for(&line, TIMEOUT).await.unwrap();
+}
+
+async fn quit(mut client: LoopbackClient) {
+    client.type_text("x").await.unwrap();
+    client.wait_for_close(TIMEOUT).await.unwrap();
+}
+
+#[tokio::test]
+async fn waiting_clients_are_admitted_in_order_as_their_position_updates() {
+    let server = start().await;
+    let mut first = server.connect("first", SIZE).await.unwrap();
+    first.wait_for("session 1", TIMEOUT).await.unwrap();
+    let mut second = server.connect("second", SIZE).await.unwrap();
+    wait_in_line(&mut second, 1).await;
+    let mut third = server.connect("third", SIZE).await.unwrap();
+    wait_in_line(&mut third, 2).await;
+
+    quit(first).await;
+    second.wait_for("session 2", TIMEOUT).await.unwrap();
+    wait_in_line(&mut third, 1).await;
+
+    quit(second).await;
+    third.wait_for("session 3", TIMEOUT).await.unwrap();
+}
+
+#[tokio::test]
+async fn leaving_the_line_frees_the_place() {
+    let server = start().await;
+    let mut first = server.connect("first", SIZE).await.unwrap();
+    first.wait_for("session 1", TIMEOUT).await.unwrap();
+    let mut hanging_up = server.connect("hanging-up", SIZE).await.unwrap();
+    wait_in_line(&mut hanging_up, 1).await;
+    let mut giving_up = server.connect("giving-up", SIZE).await.unwrap();
+    wait_in_line(&mut giving_up, 2).await;
+    let mut patient = server.connect("patient", SIZE).await.unwrap();
+    wait_in_line(&mut patient, 3).await;
+
+    hanging_up.disconnect().await.unwrap();
+    wait_in_line(&mut patient, 2).await;
+    giving_up.type_text("q").await.unwrap();
+    giving_up.wait_for_close(TIMEOUT).await.unwrap();
+    wait_in_line(&mut patient, 1).await;
+
+    // Neither of them holds on to the slot once it frees up.
+    quit(first).await;
+    patient.wait_for("session 2", TIMEOUT).await.unwrap();
+}